@@ -0,0 +1,137 @@
+use crate::*;
+use std::{cell::RefCell, rc::Rc};
+
+// All sources are read through a backend. This is the only place that decides
+// whether a source is really read from disk, the network or git.
+pub trait SourceBackend {
+    fn fetch_bytes(&self, source: &FileSource, fetch: &FetchContext) -> Result<Vec<u8>>;
+    fn list_dir(&self, source: &DirSource, fetch: &FetchContext) -> Result<Vec<PathBuf>>;
+}
+
+// Reads the sources for real. This is always used outside of tests.
+pub struct RealBackend;
+
+impl SourceBackend for RealBackend {
+    fn fetch_bytes(&self, source: &FileSource, fetch: &FetchContext) -> Result<Vec<u8>> {
+        source.fetch_directly(fetch)
+    }
+    fn list_dir(&self, source: &DirSource, fetch: &FetchContext) -> Result<Vec<PathBuf>> {
+        source.list_directly(fetch)
+    }
+}
+
+// Sources are read on the thread that resolves the config, so a replaced backend
+// only affects the code that runs inside of with_backend.
+thread_local! {
+    static BACKEND: RefCell<Option<Rc<dyn SourceBackend>>> = const { RefCell::new(None) };
+}
+
+pub fn source_backend() -> Rc<dyn SourceBackend> {
+    BACKEND
+        .with(|b| b.borrow().clone())
+        .unwrap_or_else(|| Rc::new(RealBackend))
+}
+
+// Runs f with all sources read through the given backend. Only tests replace the backend.
+#[cfg(test)]
+pub fn with_backend<T>(backend: impl SourceBackend + 'static, f: impl FnOnce() -> T) -> T {
+    let previous = BACKEND.with(|b| b.replace(Some(Rc::new(backend))));
+    let result = f();
+    BACKEND.with(|b| *b.borrow_mut() = previous);
+    result
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    // Serves files and directories from memory, keyed by the local path of the source.
+    struct FakeBackend {
+        files: HashMap<PathBuf, Vec<u8>>,
+        dirs: HashMap<PathBuf, Vec<PathBuf>>,
+    }
+
+    impl SourceBackend for FakeBackend {
+        fn fetch_bytes(&self, source: &FileSource, _: &FetchContext) -> Result<Vec<u8>> {
+            let path = source
+                .local_path()
+                .context("Only local sources are faked")?;
+            self.files
+                .get(&path)
+                .cloned()
+                .context(format!("No fake file {}", path.display()))
+        }
+        fn list_dir(&self, source: &DirSource, _: &FetchContext) -> Result<Vec<PathBuf>> {
+            let path = source
+                .local_path()
+                .context("Only local sources are faked")?;
+            self.dirs
+                .get(&path)
+                .cloned()
+                .context(format!("No fake directory {}", path.display()))
+        }
+    }
+
+    #[test]
+    fn test_fake_backend() {
+        let conf: Config = toml::from_str(
+            r#"
+            [[file]]
+            path = "single.txt"
+            sources = ["/fake/missing.txt", "/fake/single.txt"]
+            [[directory]]
+            path = "dir"
+            sources = ["/fake/dir"]
+            "#,
+        )
+        .unwrap();
+        let conf = conf
+            .set_variables(
+                &FileSource::Local {
+                    path: PathBuf::from("Cargo.toml"),
+                },
+                None,
+                &FetchContext::default(),
+            )
+            .unwrap();
+        let backend = FakeBackend {
+            files: HashMap::from([
+                (PathBuf::from("/fake/single.txt"), b"single".to_vec()),
+                (
+                    PathBuf::from("/fake/dir/sub/nested.txt"),
+                    b"nested".to_vec(),
+                ),
+            ]),
+            dirs: HashMap::from([(
+                PathBuf::from("/fake/dir"),
+                vec![PathBuf::from("sub/nested.txt")],
+            )]),
+        };
+        let built = with_backend(backend, || {
+            conf.get_active(&vec![], &FetchContext::default())
+                .unwrap()
+                .iter()
+                .map(|f| {
+                    (
+                        f.get_path(),
+                        f.build(&vec![], &vec![], false, &FetchContext::default())
+                            .unwrap(),
+                    )
+                })
+                .collect::<BTreeMap<_, _>>()
+        });
+        assert_eq!(
+            built,
+            BTreeMap::from([
+                (PathBuf::from("dir/sub/nested.txt"), b"nested".to_vec()),
+                (PathBuf::from("single.txt"), b"single".to_vec()),
+            ])
+        );
+        // Outside of with_backend, the real files are read again.
+        assert!(FileSource::Local {
+            path: PathBuf::from("/fake/single.txt")
+        }
+        .fetch(&FetchContext::default())
+        .is_err());
+    }
+}
@@ -6,16 +6,69 @@ use crate::*;
 pub struct Cli {
     #[command(subcommand)]
     pub command: Commands,
+    #[arg(
+        long,
+        global = true,
+        default_value = "false",
+        help = "Do not delete the cache directory at the end and print its path",
+        long_help = "The cloned repos and the other cached data are left in the temporary cache directory, also when the operation is canceled. The path is printed. This is meant for debugging and it can leave large directories behind!"
+    )]
+    pub keep_cache: bool,
+    #[arg(
+        long,
+        global = true,
+        default_value = "false",
+        help = "Allow secret variables to run programs like vault or op",
+        long_help = "Variables like { secret = \"vault:secret/app#token\" } are read by running the command line tool of the secret manager. Without this flag, only secrets from the environment can be used."
+    )]
+    pub allow_exec: bool,
+    #[arg(
+        long,
+        global = true,
+        value_name = "AGENT",
+        help = "The user agent of http requests",
+        long_help = "Replaces the default user agent, which is lorevault and its version. A single http source can still set its own user_agent."
+    )]
+    pub user_agent: Option<String>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "PEM",
+        help = "Trust the certificates in this file for https",
+        long_help = "The certificates in the PEM file are trusted in addition to the root certificates of the system, for example for a company CA."
+    )]
+    pub ca_bundle: Option<PathBuf>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "KEY=VALUE",
+        value_parser = parse_config_var,
+        help = "Set a variable of the config that is loaded",
+        long_help = "Replaces the value of a variable (or sets a parameter) of the config given on the command line, before the variables refer to each other. It wins over the config itself and its tag sections. Included configs are not affected, they only see what is passed with with_vars. The config must define the variable."
+    )]
+    pub config_var: Vec<(String, String)>,
+    #[arg(
+        long,
+        global = true,
+        value_name = "NAME",
+        help = "Use the tags and variables of a profile of the config",
+        long_help = "A [profiles.NAME] table of the config given on the command line lists tags and vars. The tags are active like default tags and the vars replace variables of the config. --config-var wins over the profile. Included configs are not affected."
+    )]
+    pub profile: Option<String>,
 }
 
 #[derive(Subcommand, Debug)]
 pub enum Commands {
     #[command(about = "Sync to a specified directory")]
     Sync {
-        #[arg(help = "Config file", long_help = "Supports repo#id:path")]
+        #[arg(
+            value_name = "FILE",
+            help = "Config file (if the output is omitted, this is the output)",
+            long_help = "Supports repo#id:path. If only one path is given, it is the destination directory and the config is discovered."
+        )]
         file: String,
         #[arg(help = "Destination directory")]
-        output: PathBuf,
+        output: Option<PathBuf>,
         #[arg(
             short,
             long,
@@ -41,16 +94,236 @@ pub enum Commands {
             short,
             long,
             use_value_delimiter(true),
-            long_help = "List of age (v1) key-files that might be used for decryption."
+            long_help = "List of age (v1) key-files, private ssh keys or directories of key-files that might be used for decryption. The default key file of age is used as well."
         )]
         identity_files: Vec<PathBuf>,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Write the files that could be built and report the others"
+        )]
+        keep_going: bool,
+        #[arg(
+            long,
+            help = "Expected SHA3-256 hash of the config file",
+            long_help = "The config file is only used if its SHA3-256 hash matches. This can be used to pin a remote config."
+        )]
+        config_hash: Option<String>,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Clone all remote repos of the config concurrently before building"
+        )]
+        parallel_clone: bool,
+        #[arg(
+            short,
+            long,
+            default_value = "4",
+            help = "Maximum number of concurrent jobs"
+        )]
+        jobs: usize,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Keep a list of the managed files in the output directory",
+            long_help = "Writes the list of all managed paths to .lorevault-managed in the output directory. With -S, files from the previous list that are no longer in the config are removed."
+        )]
+        managed_list: bool,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Do not verify any hashes (for debugging only)",
+            long_help = "Accepts files and configs even if their hashes do not match. This is meant for working on a config whose sources are still changing. Integrity is not verified!"
+        )]
+        no_hash_check: bool,
+        #[arg(
+            long,
+            value_name = "MB",
+            help = "Maximum size of the built files kept in memory (in MiB)",
+            long_help = "When the built files take up more memory than this, they are moved to a hidden directory next to the output. They are moved into the output once everything is built."
+        )]
+        mem_budget: Option<usize>,
+        #[arg(
+            long,
+            value_enum,
+            requires = "skip_first_level",
+            help = "Keep tracked paths and handle files in them that are not in the config",
+            long_help = "Only with -S. Instead of replacing tracked paths as a whole, the files are written one by one. Files inside of tracked paths that are not part of the config are then listed (report), removed (prune) or stop the sync before anything is written (fail)."
+        )]
+        untracked: Option<UntrackedMode>,
+        #[arg(
+            long,
+            default_value = "false",
+            requires = "skip_first_level",
+            conflicts_with = "untracked",
+            help = "Shorthand for --untracked prune"
+        )]
+        prune: bool,
+        #[arg(
+            long,
+            value_name = "BUNDLE",
+            help = "Write the files from a bundle instead of a config",
+            long_help = "Writes the files stored in a bundle (see the bundle command). No sources are fetched. Only the output directory is given."
+        )]
+        from_bundle: Option<PathBuf>,
+        #[arg(
+            short,
+            long,
+            default_value = "false",
+            help = "Summarize which source was used for each file",
+            long_help = "At the end, lists for each file which of its sources was used and why the ones before it failed."
+        )]
+        verbose: bool,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Print the slowest fetches, listings and edits",
+            long_help = "Measures the time spent fetching each source, listing each directory source and applying the edits of each file. The slowest ones are printed at the end."
+        )]
+        timing: bool,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Replace a file or symlink at the output path",
+            long_help = "If the output path is a file or a symlink instead of a directory, it is removed and the directory is created. Without this, it is an error."
+        )]
+        replace_nondir: bool,
+        #[arg(
+            long,
+            value_name = "MODE",
+            value_parser = parse_mode,
+            help = "Mode of the written files in octal, like 600",
+            long_help = "All written files get this mode instead of the one from the umask. This is useful for files with credentials. It is ignored on systems other than unix."
+        )]
+        default_mode: Option<u32>,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Only allow full commit hashes as git ids",
+            long_help = "Fails if any git id (of files, directories, included configs or the config itself) is a branch, a tag or a short hash. The id must be the full 40 character commit hash."
+        )]
+        require_pinned_refs: bool,
+        #[arg(
+            long,
+            default_value = "false",
+            requires = "skip_first_level",
+            conflicts_with_all = ["no_confirm", "managed_list"],
+            help = "Ask for each tracked path that exists before overwriting it",
+            long_help = "Only with -S. Asks for every tracked path (the first level of the directory) that already exists. Declined paths are left untouched."
+        )]
+        confirm_each: bool,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Hard link files with the same content",
+            long_help = "Files with identical content are written once and the others are hard links to it. If linking fails, the file is written normally. Changing one of the linked files changes all of them!"
+        )]
+        dedup_links: bool,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Print every step of resolving the config",
+            long_help = "Prints (to stderr) which configs are loaded, how the variables and tags resolve, which inclusions and directories add which files and which files are shadowed or inactive. Nested inclusions are indented. Variable values are printed too!"
+        )]
+        trace: bool,
+        #[arg(
+            long,
+            value_name = "LOCKFILE",
+            conflicts_with = "from_bundle",
+            help = "Only use configs whose hashes match the lockfile",
+            long_help = "Every loaded config, including the ones that are included by other configs, must be listed in the lockfile (see lock --configs) with a matching hash."
+        )]
+        config_lock: Option<PathBuf>,
+        #[arg(
+            long,
+            value_name = "GLOB",
+            value_parser = parse_exclude,
+            conflicts_with = "from_bundle",
+            help = "Leave out the files whose path matches (can be repeated)",
+            long_help = "Removes the files whose path in the output directory matches the glob after the config is resolved. Like for the include patterns of directories, * also matches /. A pattern that matches no file gives a warning."
+        )]
+        exclude: Vec<glob::Pattern>,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Use the credentials in ~/.netrc for https sources and clones",
+            long_help = "Looks up the host of https downloads and git remotes in the .netrc file ($NETRC or ~/.netrc) and uses that login. Credentials in the url always win. For git, the usual credential helpers are asked if the .netrc login fails."
+        )]
+        netrc: bool,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Fail if no file is active with the given tags",
+            long_help = "Without this, a config that has no active files with the given tags only gives a warning and the output directory is emptied. This is usually caused by wrong tags."
+        )]
+        require_nonempty: bool,
+        #[arg(
+            long,
+            value_name = "CODE",
+            help = "Exit with this code if the output is already up to date",
+            long_help = "If the files on disk already match the config, nothing is written either way. With this, lorevault also exits with the given code instead of 0, so scripts can tell whether anything changed."
+        )]
+        unchanged_exit_code: Option<i32>,
+        #[arg(
+            long,
+            value_name = "DEPTH",
+            default_value_t = DEFAULT_MAX_INCLUDE_DEPTH,
+            help = "Fail if configs are included deeper than this",
+            long_help = "Included configs can include further configs. If the chain gets longer than this, the command fails and prints the chain. This catches configs that include each other."
+        )]
+        max_include_depth: usize,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Print the tree of included configs"
+        )]
+        print_includes: bool,
+        #[arg(
+            long,
+            value_name = "STYLE",
+            help = "Write all text files with these line endings",
+            long_help = "The line endings of text files are converted after the edits. Files with a null byte in the first 8000 bytes are treated as binary and are not changed."
+        )]
+        normalize_newlines: Option<NewlineStyle>,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Warn about local sources that no longer match the hash of their file",
+            long_help = "Normally only the source that is used is checked. With this, every local source of a file with a hash is compared with it, also if an earlier source was used or the file was taken from the output directory."
+        )]
+        warn_local_drift: bool,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Flush every written file to disk before it replaces the old one",
+            long_help = "Each file is written to a temporary file next to it, flushed with fsync and then renamed into place, and the directory is flushed too. After a crash, a file is either the old or the new one, never empty or partial. This makes the sync slower."
+        )]
+        durable: bool,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Only warn if a directory has a different number of files than its count",
+            long_help = "The files that were found are used anyway. This is meant for working on a config whose directories still change. Like count_is_warning on a single directory."
+        )]
+        lenient_count: bool,
+        #[arg(
+            long,
+            value_name = "MINUTES",
+            help = "Do not ask again for a while after an overwrite was confirmed",
+            long_help = "After a sync, the files in the output are recorded for this config and output path. A later sync with this option within the given minutes does not ask for confirmation, as long as exactly these files would be overwritten. Any other file in the way, another output or another config asks again. The records are kept in lorevault/confirmations in the cache directory of the user (~/.cache on Linux)."
+        )]
+        remember_confirmation: Option<u64>,
     },
     #[command(about = "Remove files controlled by corresponding sync operation")]
     Clean {
-        #[arg(help = "Config file", long_help = "Supports repo#id:path")]
+        #[arg(
+            value_name = "FILE",
+            help = "Config file (if the output is omitted, this is the output)",
+            long_help = "Supports repo#id:path. If only one path is given, it is the destination directory and the config is discovered."
+        )]
         file: String,
         #[arg(help = "Destination directory")]
-        output: PathBuf,
+        output: Option<PathBuf>,
         #[arg(
             short,
             long,
@@ -72,11 +345,28 @@ pub enum Commands {
             help = "Overwrite target directory without confirmation"
         )]
         no_confirm: bool,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Only list the paths that would be deleted"
+        )]
+        dry_run: bool,
+        #[arg(
+            long,
+            default_value = "false",
+            conflicts_with_all = ["tags", "skip_first_level"],
+            help = "Remove the files listed by sync --managed-list instead of resolving the config",
+            long_help = "Only the files recorded in the list of managed files in the destination are removed, together with the list and directories that end up empty. The config is not read at all, so this works offline and even if the config has changed since the sync. If only one path is given, it is the destination directory."
+        )]
+        from_managed_list: bool,
     },
     #[command(about = "Shortcut for syncing to ~/.config with -S")]
     Config {
-        #[arg(help = "Config file", long_help = "Supports repo#id:path")]
-        file: String,
+        #[arg(
+            help = "Config file",
+            long_help = "Supports repo#id:path. If omitted, the config is discovered."
+        )]
+        file: Option<String>,
         #[arg(
             short,
             long,
@@ -93,14 +383,127 @@ pub enum Commands {
         no_confirm: bool,
     },
     #[command(about = "Writes out an example configuration file", alias = "init")]
-    Example {},
-    #[command(about = "Prints the SHA3-256 hash of a file")]
-    Hash { file: String },
+    Example {
+        #[arg(
+            help = "Where to write the example",
+            default_value = "lorevault_example.toml"
+        )]
+        path: PathBuf,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Overwrite the file if it exists"
+        )]
+        force: bool,
+        #[arg(
+            long,
+            value_name = "DIR",
+            help = "Describe this existing directory instead of writing the example",
+            long_help = "Every file in the directory becomes a file with a local source, pinned by its hash. The result can be synced right away and edited from there."
+        )]
+        from: Option<PathBuf>,
+        #[arg(
+            long,
+            default_value = "false",
+            requires = "from",
+            help = "Use a single directory entry instead of one pinned file per file"
+        )]
+        as_directory: bool,
+    },
+    #[command(about = "Prints the hash of a file (SHA3-256 by default)")]
+    Hash {
+        file: String,
+        #[arg(
+            long,
+            value_enum,
+            default_value = "sha3-256",
+            help = "The algorithm of the hash",
+            long_help = "Hashes of other algorithms are printed with a prefix like sha256:, which is how they are written in a config."
+        )]
+        algo: HashAlgo,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Only print the first 12 characters",
+            long_help = "The short hash is meant for reading. A pinned hash in a config must always be the full one."
+        )]
+        short: bool,
+    },
     #[command(about = "Lists all the tags defined in the file")]
-    Tags { file: String },
+    Tags {
+        file: Option<String>,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Show how many files each tag adds, removes or changes"
+        )]
+        effective: bool,
+    },
+    #[command(
+        about = "Lists the variables of the file after resolution",
+        alias = "vars"
+    )]
+    Variables { file: Option<String> },
+    #[command(
+        about = "Prints the config in a normalized form for diffing",
+        long_about = "The config is parsed and written out again with sorted keys and all default values. Variables, tags and inclusions are not resolved. Two configs that only differ in formatting or the order of keys give the same output."
+    )]
+    Canonicalize { file: Option<String> },
+    #[command(about = "Lists the commit that every git id of the active files resolves to")]
+    Refs {
+        file: Option<String>,
+        #[arg(
+            short,
+            long,
+            use_value_delimiter(true),
+            long_help = "Tags must be defined in the configuration file"
+        )]
+        tags: Vec<String>,
+    },
     #[command(about = "Lists all the files that would be in the directory")]
     List {
+        file: Option<String>,
+        #[arg(
+            short,
+            long,
+            use_value_delimiter(true),
+            long_help = "Tags must be defined in the configuration file"
+        )]
+        tags: Vec<String>,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Print every step of resolving the config"
+        )]
+        trace: bool,
+        #[arg(
+            long,
+            value_name = "GLOB",
+            value_parser = parse_exclude,
+            help = "Leave out the files whose path matches (can be repeated)"
+        )]
+        exclude: Vec<glob::Pattern>,
+        #[arg(
+            long,
+            value_name = "DEPTH",
+            default_value_t = DEFAULT_MAX_INCLUDE_DEPTH,
+            help = "Fail if configs are included deeper than this",
+            long_help = "Included configs can include further configs. If the chain gets longer than this, the command fails and prints the chain. This catches configs that include each other."
+        )]
+        max_include_depth: usize,
+        #[arg(
+            long,
+            default_value = "false",
+            help = "Print the tree of included configs"
+        )]
+        print_includes: bool,
+    },
+    #[command(about = "Explains why a path is or is not part of the directory")]
+    Explain {
+        #[arg(help = "Config file", long_help = "Supports repo#id:path")]
         file: String,
+        #[arg(help = "The path inside of the directory")]
+        path: PathBuf,
         #[arg(
             short,
             long,
@@ -109,8 +512,125 @@ pub enum Commands {
         )]
         tags: Vec<String>,
     },
+    #[command(about = "Stores all files of the directory in a single bundle file")]
+    Bundle {
+        #[arg(help = "Config file", long_help = "Supports repo#id:path")]
+        file: String,
+        #[arg(help = "The bundle file to write")]
+        output: PathBuf,
+        #[arg(
+            short,
+            long,
+            use_value_delimiter(true),
+            long_help = "Tags must be defined in the configuration file"
+        )]
+        tags: Vec<String>,
+        #[arg(
+            short,
+            long,
+            use_value_delimiter(true),
+            long_help = "List of age (v1) key-files, private ssh keys or directories of key-files that might be used for decryption. The default key file of age is used as well."
+        )]
+        identity_files: Vec<PathBuf>,
+    },
+    #[command(
+        about = "Prints one hash for all files of the directory",
+        long_about = "Builds the directory in memory and hashes it as a whole. Every file gives the line \"<HASH> <path>\", the lines are sorted by path and joined with newlines (including a final one), and the fingerprint is the SHA3-256 hash of that text."
+    )]
+    Fingerprint {
+        #[arg(help = "Config file", long_help = "Supports repo#id:path")]
+        file: Option<String>,
+        #[arg(
+            short,
+            long,
+            use_value_delimiter(true),
+            long_help = "Tags must be defined in the configuration file"
+        )]
+        tags: Vec<String>,
+        #[arg(
+            short,
+            long,
+            use_value_delimiter(true),
+            long_help = "List of age (v1) key-files, private ssh keys or directories of key-files that might be used for decryption. The default key file of age is used as well."
+        )]
+        identity_files: Vec<PathBuf>,
+    },
+    #[command(
+        about = "Checks that a directory still matches the config",
+        long_about = "Builds the directory in memory like sync and compares it with the one on disk. Missing, extra and modified files are listed and the command fails if there are any. Nothing is written or removed."
+    )]
+    Verify {
+        #[arg(help = "Config file", long_help = "Supports repo#id:path")]
+        file: String,
+        #[arg(help = "The directory to check")]
+        output: PathBuf,
+        #[arg(
+            short,
+            long,
+            use_value_delimiter(true),
+            long_help = "Tags must be defined in the configuration file"
+        )]
+        tags: Vec<String>,
+        #[arg(
+            long,
+            short = 'S',
+            default_value = "false",
+            help = "Only check the paths that are tracked at the first level, like sync -S"
+        )]
+        skip_first_level: bool,
+        #[arg(
+            short,
+            long,
+            use_value_delimiter(true),
+            long_help = "List of age (v1) key-files, private ssh keys or directories of key-files that might be used for decryption. The default key file of age is used as well."
+        )]
+        identity_files: Vec<PathBuf>,
+    },
+    #[command(about = "Records the hashes of the config and all included configs")]
+    Lock {
+        #[arg(help = "Config file", long_help = "Supports repo#id:path")]
+        file: String,
+        #[arg(help = "The lockfile to write")]
+        output: PathBuf,
+        #[arg(
+            long,
+            default_value = "true",
+            help = "Lock the configs of the resolution tree (the default)",
+            long_help = "Records the SHA3-256 hash of every config that is loaded when the config is resolved, including transitively included ones. sync --config-lock verifies them. This is the default, as only the configs can be locked. The files are pinned by their hashes in the config."
+        )]
+        configs: bool,
+        #[arg(
+            short,
+            long,
+            use_value_delimiter(true),
+            long_help = "Tags must be defined in the configuration file"
+        )]
+        tags: Vec<String>,
+    },
+    #[command(about = "Shows the changes the edits make to each file as a unified diff")]
+    Edits {
+        file: Option<String>,
+        #[arg(
+            short,
+            long,
+            use_value_delimiter(true),
+            long_help = "Tags must be defined in the configuration file"
+        )]
+        tags: Vec<String>,
+        #[arg(
+            short,
+            long,
+            use_value_delimiter(true),
+            long_help = "List of age (v1) key-files, private ssh keys or directories of key-files that might be used for decryption. The default key file of age is used as well."
+        )]
+        identity_files: Vec<PathBuf>,
+    },
     #[command(about = "Shows the contents of a single source (as utf8)")]
     Show {
+        #[arg(
+            help = "The source, or the config with --path",
+            long_help = "The source to show. With --path, this is the config file instead."
+        )]
         source: String,
         #[arg(
             short,
@@ -118,9 +638,156 @@ pub enum Commands {
             long_help = "Writes the contents to a file instead of printing them. This should be used for non-utf8 files instead of a pipe"
         )]
         output: Option<PathBuf>,
+        #[arg(
+            long,
+            help = "Shows the file at this path of the config, as sync would write it",
+            long_help = "Builds the file at this path of the config with all sources, hash checks, decryption and edits, like sync does."
+        )]
+        path: Option<PathBuf>,
+        #[arg(
+            short,
+            long,
+            use_value_delimiter(true),
+            requires = "path",
+            long_help = "Tags must be defined in the configuration file"
+        )]
+        tags: Vec<String>,
+        #[arg(
+            short,
+            long,
+            use_value_delimiter(true),
+            requires = "path",
+            long_help = "List of age (v1) key-files, private ssh keys or directories of key-files that might be used for decryption. The default key file of age is used as well."
+        )]
+        identity_files: Vec<PathBuf>,
     },
 }
 
+// The options of the sync command that control how the folder is built and written.
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    pub no_confirm: bool,
+    pub skip_first: bool,
+    pub identity_files: Vec<PathBuf>,
+    pub keep_going: bool,
+    pub config_hash: Option<String>,
+    pub parallel_clone: bool,
+    pub jobs: usize,
+    pub managed_list: bool,
+    pub no_hash_check: bool,
+    pub mem_budget: Option<usize>,
+    pub untracked: Option<UntrackedMode>,
+    pub from_bundle: Option<PathBuf>,
+    pub verbose: bool,
+    pub timing: bool,
+    pub replace_nondir: bool,
+    pub default_mode: Option<u32>,
+    pub confirm_each: bool,
+    pub dedup_links: bool,
+    pub config_lock: Option<PathBuf>,
+    pub exclude: Vec<glob::Pattern>,
+    pub require_nonempty: bool,
+    pub unchanged_exit_code: Option<i32>,
+    pub max_include_depth: Option<usize>,
+    pub print_includes: bool,
+    pub normalize_newlines: Option<NewlineStyle>,
+    pub warn_local_drift: bool,
+    pub durable: bool,
+    pub lenient_count: bool,
+    pub remember_confirmation: Option<u64>,
+    // --netrc, --require-pinned-refs and --trace are needed wherever sources are fetched.
+    pub fetch: FetchContext,
+}
+
+fn parse_mode(mode: &str) -> Result<u32> {
+    let parsed = u32::from_str_radix(mode, 8)
+        .map_err(|_| format_err!("{} is not an octal mode like 600", mode))?;
+    if parsed > 0o7777 {
+        return Err(format_err!("{} is not an octal mode like 600", mode));
+    }
+    Ok(parsed)
+}
+
+fn parse_config_var(assignment: &str) -> Result<(String, String)> {
+    match assignment.split_once('=') {
+        Some((key, value)) if !key.trim().is_empty() => {
+            Ok((key.trim().to_string(), value.to_string()))
+        }
+        _ => Err(format_err!("{} is not of the form KEY=VALUE", assignment)),
+    }
+}
+
+fn parse_exclude(pattern: &str) -> Result<glob::Pattern> {
+    glob::Pattern::new(pattern)
+        .map_err(|e| format_err!("Invalid exclude pattern {}: {}", pattern, e))
+}
+
+// What happens to files inside of tracked paths that are not part of the config (with -S).
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum UntrackedMode {
+    Report,
+    Prune,
+    Fail,
+}
+
+pub const DISCOVERED_CONFIG_NAME: &str = "lorevault.toml";
+
+// An explicit path always wins. Then LOREVAULT_CONFIG is used and only then the config is discovered.
+pub fn resolve_config(file: &Option<String>) -> Result<String> {
+    if let Some(f) = file {
+        return Ok(f.clone());
+    }
+    if let Ok(f) = std::env::var("LOREVAULT_CONFIG") {
+        if !f.trim().is_empty() {
+            return Ok(f);
+        }
+    }
+    let discovered = discover_config()?;
+    neutral(format!("Using config {}", discovered.display()));
+    Ok(discovered.to_string_lossy().to_string())
+}
+
+// For commands that take a config and an output, a single argument is the output.
+pub fn resolve_config_and_output(
+    file: &str,
+    output: &Option<PathBuf>,
+) -> Result<(String, PathBuf)> {
+    match output {
+        Some(o) => Ok((file.to_string(), o.clone())),
+        // Most likely the output was forgotten, which would write into a directory named like the config.
+        None if file.ends_with(".toml") || PathBuf::from(file).is_file() => Err(format_err!(
+            "{} looks like a config, but no output directory was given",
+            file
+        )),
+        None => Ok((resolve_config(&None)?, PathBuf::from(file))),
+    }
+}
+
+// Looks for lorevault.toml in the current directory and its parents up to the project root (the first directory with .git).
+// Falls back to lorevault/config.toml in the user's config directory ($XDG_CONFIG_HOME on Linux).
+pub fn discover_config() -> Result<PathBuf> {
+    let cwd = std::env::current_dir().context("Could not get the current directory")?;
+    for dir in cwd.ancestors() {
+        let candidate = dir.join(DISCOVERED_CONFIG_NAME);
+        if candidate.is_file() {
+            return Ok(candidate);
+        }
+        if dir.join(".git").exists() {
+            break;
+        }
+    }
+    if let Some(user_config) = config_dir() {
+        let candidate = user_config.join("lorevault").join("config.toml");
+        if candidate.is_file() {
+            return Ok(candidate);
+        }
+    }
+    Err(format_err!(
+        "No config file given and none found. Looked for {} up to the project root and for lorevault/config.toml in the user's config directory.",
+        DISCOVERED_CONFIG_NAME
+    ))
+}
+
 // A "general_path" is a string that might be a path or repo#id:subpath
 pub fn is_repo(general_path: &str) -> bool {
     general_path.contains('#') && general_path.contains(':')
@@ -177,12 +844,7 @@ pub fn get_confirmation(folder_path: &PathBuf, newcount: usize) -> bool {
         file_count.expect("unchecked file count"),
         newcount
     );
-    let status = match Confirm::new().with_prompt(prompt).interact() {
-        Ok(true) => true,
-        _ => false,
-    };
-
-    status
+    matches!(Confirm::new().with_prompt(prompt).interact(), Ok(true))
 }
 
 pub fn get_confirmation_skip_level(folder_path: &PathBuf, tracked_paths: &Vec<PathBuf>) -> bool {
@@ -199,12 +861,126 @@ pub fn get_confirmation_skip_level(folder_path: &PathBuf, tracked_paths: &Vec<Pa
         "All paths starting with:\n{}\nWill be overwritten!\nIs that OK?",
         list
     );
-    let status = match Confirm::new().with_prompt(prompt).report(false).interact() {
-        Ok(true) => true,
-        _ => false,
+    matches!(
+        Confirm::new().with_prompt(prompt).report(false).interact(),
+        Ok(true)
+    )
+}
+
+// Paths that do not exist yet are approved without asking, since nothing is overwritten.
+pub fn confirm_each_path(folder_path: &PathBuf, tracked_paths: &Vec<PathBuf>) -> Vec<PathBuf> {
+    let mut approved = vec![];
+    for tracked in tracked_paths {
+        let full_path = folder_path.join(tracked);
+        if !full_path.exists() && !full_path.is_symlink() {
+            approved.push(tracked.clone());
+            continue;
+        }
+        let prompt = format!("Overwrite {}?", full_path.display());
+        if let Ok(true) = Confirm::new().with_prompt(prompt).default(false).interact() {
+            approved.push(tracked.clone());
+        }
+    }
+    approved
+}
+
+// The record of a confirmed overwrite. There is one per config and output.
+fn confirmation_record(config: &str, output: &PathBuf) -> Option<PathBuf> {
+    let output = output.canonicalize().ok()?;
+    let config = fs::canonicalize(config).map_or(config.to_string(), |c| c.display().to_string());
+    let key = compute_hash(&format!("{}\n{}", config, output.display()).into_bytes());
+    Some(
+        dirs::cache_dir()?
+            .join("lorevault")
+            .join("confirmations")
+            .join(key),
+    )
+}
+
+// The hash of all files that an overwrite would delete, so a new file in the way asks again.
+fn overwritten_files(output: &PathBuf, scope: Option<&Vec<PathBuf>>) -> Result<String> {
+    let roots = match scope {
+        Some(tracked) => tracked.iter().map(|t| output.join(t)).collect(),
+        None => vec![output.clone()],
     };
+    let mut files = vec![];
+    for root in roots {
+        if root.is_dir() {
+            files.extend(files_below(&root)?);
+        } else if root.exists() || root.is_symlink() {
+            files.push(root);
+        }
+    }
+    let mut listing = files
+        .iter()
+        .map(|f| {
+            f.strip_prefix(output)
+                .unwrap_or(f)
+                .to_string_lossy()
+                .to_string()
+        })
+        .collect::<Vec<_>>();
+    listing.sort();
+    Ok(compute_hash(&listing.join("\n").into_bytes()))
+}
+
+pub fn unix_time() -> u64 {
+    std::time::SystemTime::now()
+        .duration_since(std::time::UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
+// The time of the confirmation, if the last sync to the output recorded exactly the files that are there now,
+// not longer ago than minutes.
+pub fn remembered_confirmation(
+    config: &str,
+    output: &PathBuf,
+    scope: Option<&Vec<PathBuf>>,
+    minutes: u64,
+) -> Option<u64> {
+    record_matches(
+        &confirmation_record(config, output)?,
+        output,
+        scope,
+        minutes,
+    )
+}
+
+fn record_matches(
+    record: &PathBuf,
+    output: &PathBuf,
+    scope: Option<&Vec<PathBuf>>,
+    minutes: u64,
+) -> Option<u64> {
+    let text = fs::read_to_string(record).ok()?;
+    let (time, files) = text.trim().split_once(' ')?;
+    let time = time.parse::<u64>().ok()?;
+    let recent = unix_time().saturating_sub(time) <= minutes * 60;
+    (recent && overwritten_files(output, scope).is_ok_and(|f| f == files)).then_some(time)
+}
+
+// Called after a sync, so the record lists the files that the sync wrote.
+// The time is the one of the interactive confirmation, a remembered one does not extend it.
+pub fn remember_confirmation(
+    config: &str,
+    output: &PathBuf,
+    scope: Option<&Vec<PathBuf>>,
+    confirmed_at: u64,
+) -> Result<()> {
+    let record = confirmation_record(config, output).context("No cache directory")?;
+    write_record(&record, output, scope, confirmed_at)
+}
 
-    status
+fn write_record(
+    record: &PathBuf,
+    output: &PathBuf,
+    scope: Option<&Vec<PathBuf>>,
+    confirmed_at: u64,
+) -> Result<()> {
+    fs::create_dir_all(record.parent().context("No parent")?)?;
+    let files = overwritten_files(output, scope)?;
+    fs::write(record, format!("{} {}\n", confirmed_at, files))
+        .context(format!("Could not write {}", record.display()))
 }
 
 // This ignores things that are not files.
@@ -231,13 +1007,61 @@ mod test {
     fn test_repo_string() {
         assert!(is_repo("https://github.com/some/repo.git#fb17a46eb92e8d779e57a10589e9012e9aa5f948:local/path.txt"));
         assert_eq!(extract_components("https://github.com/some/repo.git#fb17a46eb92e8d779e57a10589e9012e9aa5f948:local/path.txt"),
-        Some(("https://github.com/some/repo.git".into(),"fb17a46eb92e8d779e57a10589e9012e9aa5f948".into(),"local/path.txt".into())));
+        Some(("https://github.com/some/repo.git","fb17a46eb92e8d779e57a10589e9012e9aa5f948","local/path.txt")));
         assert!(!is_repo("https://github.com/some/repo.git:local/path.txt"));
         assert!(!is_repo("/home/somefile.toml"));
         assert_eq!(
             extract_components("internet://adress#hash#release:tag:/path.txt"),
-            Some(("internet://adress#hash", "release:tag", "/path.txt".into()))
+            Some(("internet://adress#hash", "release:tag", "/path.txt"))
+        );
+        assert_eq!(extract_components("r#t:p"), Some(("r", "t", "p")));
+    }
+
+    #[test]
+    fn test_single_argument_is_not_a_config() {
+        assert!(resolve_config_and_output("conf.toml", &None).is_err());
+        assert!(resolve_config_and_output("Cargo.toml", &None).is_err());
+        assert!(resolve_config_and_output("src/main.rs", &None).is_err());
+        assert_eq!(
+            resolve_config_and_output("conf.toml", &Some(PathBuf::from("out"))).unwrap(),
+            ("conf.toml".to_string(), PathBuf::from("out"))
+        );
+    }
+
+    #[test]
+    fn test_remembered_confirmation() {
+        let dir = tempfile::tempdir().unwrap();
+        let output = dir.path().join("out");
+        let record = dir.path().join("records").join("record");
+        fs::create_dir_all(output.join("keep")).unwrap();
+        fs::write(output.join("keep/a.txt"), "a").unwrap();
+        fs::write(output.join("b.txt"), "b").unwrap();
+        let now = unix_time();
+        assert!(record_matches(&record, &output, None, 10).is_none());
+        write_record(&record, &output, None, now).unwrap();
+        assert_eq!(record_matches(&record, &output, None, 10), Some(now));
+        // Changed content is not a reason to ask again, another file in the way is.
+        fs::write(output.join("b.txt"), "changed").unwrap();
+        assert!(record_matches(&record, &output, None, 10).is_some());
+        fs::write(output.join("new.txt"), "new").unwrap();
+        assert!(record_matches(&record, &output, None, 10).is_none());
+        // With -S, only the tracked paths count.
+        let tracked = vec![PathBuf::from("keep")];
+        write_record(&record, &output, Some(&tracked), now).unwrap();
+        fs::write(output.join("other.txt"), "other").unwrap();
+        assert!(record_matches(&record, &output, Some(&tracked), 10).is_some());
+        // An old record is not used.
+        write_record(&record, &output, Some(&tracked), now - 11 * 60).unwrap();
+        assert!(record_matches(&record, &output, Some(&tracked), 10).is_none());
+        // A sync without asking keeps the time of the confirmation, so the record still expires.
+        let confirmed = now - 9 * 60;
+        write_record(&record, &output, Some(&tracked), confirmed).unwrap();
+        let remembered = record_matches(&record, &output, Some(&tracked), 10).unwrap();
+        write_record(&record, &output, Some(&tracked), remembered).unwrap();
+        assert_eq!(
+            record_matches(&record, &output, Some(&tracked), 10),
+            Some(confirmed)
         );
-        assert_eq!(extract_components("r#t:p"), Some(("r", "t", "p".into())));
+        assert!(record_matches(&record, &output, Some(&tracked), 8).is_none());
     }
 }
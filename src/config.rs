@@ -1,3 +1,12 @@
+#![allow(
+    clippy::cmp_owned,
+    clippy::len_zero,
+    clippy::needless_borrow,
+    clippy::needless_question_mark,
+    clippy::needless_return,
+    clippy::option_as_ref_deref,
+    clippy::wrong_self_convention
+)]
 use crate::*;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -8,7 +17,13 @@ pub struct Config {
     #[serde(skip)]
     variables_set: bool, // This is just a flag to ensure that we do not work with a config before tha variables have been replaced.
     #[serde(default, alias = "var")] // The alias lets us write var.key=value in the toml file.
-    variables: HashMap<String, String>,
+    variables: HashMap<String, VariableValue>,
+    #[serde(skip)]
+    resolved_variables: HashMap<String, String>, // The final values after set_variables, including SELF_...
+    #[serde(skip)]
+    builtin_variables: HashMap<String, String>, // Only the SELF_... values.
+    #[serde(skip)]
+    unresolved: Option<Box<Config>>, // The config as it was read. It is resolved again if tags set variables.
     #[serde(rename = "file", default)]
     content: Vec<File>,
     #[serde(default)]
@@ -17,146 +32,461 @@ pub struct Config {
     #[serde(default)]
     #[serde(rename = "directory")]
     directories: Vec<Directory>,
+    #[serde(default)]
+    #[serde(rename = "manifest")]
+    manifests: Vec<Manifest>,
     #[serde(rename = "default", default)]
     pub default_tags: Vec<String>,
+    // Named groups of tags of which at most one can be active.
+    #[serde(default)]
+    exclusive_tags: BTreeMap<String, Vec<String>>,
+    // Variables that an including config is expected to set with with_vars. var can give defaults.
+    #[serde(default)]
+    parameters: Vec<String>,
+    #[serde(skip)]
+    passed_variables: HashMap<String, String>, // The with_vars of the inclusion that loaded this config.
+    // Named sets of tags and variables, one of them can be selected with --profile.
+    #[serde(default)]
+    profiles: BTreeMap<String, Profile>,
+    #[serde(skip)]
+    profile: Option<String>, // Only set for the config given on the command line.
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Profile {
+    // Active like default tags, so they can be negated.
+    #[serde(default)]
+    tags: Vec<String>,
+    // Replace the variables of the config before they refer to each other. --config-var still wins.
+    #[serde(default)]
+    vars: BTreeMap<String, String>,
 }
 
 impl Config {
     // This gets all files that should be included given the list of tags.
     // It should error if two tagged files or two untagged files have the same path.
     // If an untagged file and a tagged file have the same path, only the tagged one is active.
-    pub fn get_active(&self, given_tags: &Vec<String>) -> Result<Vec<File>> {
+    pub fn get_active(&self, given_tags: &Vec<String>, fetch: &FetchContext) -> Result<Vec<File>> {
+        self.get_active_with(given_tags, &ListContext::default(), fetch)
+    }
+
+    pub fn get_active_with(
+        &self,
+        given_tags: &Vec<String>,
+        context: &ListContext,
+        fetch: &FetchContext,
+    ) -> Result<Vec<File>> {
+        self.with_tag_variables(given_tags, fetch, |config, tags| {
+            config.collect_active(tags, context, fetch)
+        })
+    }
+
+    // The included configs as shown by --print-includes, each one followed by the ones it includes.
+    pub fn include_tree(
+        &self,
+        given_tags: &Vec<String>,
+        context: &ListContext,
+        fetch: &FetchContext,
+    ) -> Result<Vec<IncludedConfig>> {
+        self.with_tag_variables(given_tags, fetch, |config, _| {
+            config.collect_include_tree(context, fetch)
+        })
+    }
+
+    fn collect_include_tree(
+        &self,
+        context: &ListContext,
+        fetch: &FetchContext,
+    ) -> Result<Vec<IncludedConfig>> {
+        let mut tree = vec![];
+        for inc in &self.inclusions {
+            if !platform_matches(&inc.os, &inc.arch) {
+                continue;
+            }
+            let (source, inner) = inc.enter(context)?;
+            let config = inc.load_config(&source, fetch)?;
+            let nested = config.with_tag_variables(&inc.with_tags, fetch, |c, _| {
+                c.collect_include_tree(&inner, fetch)
+            })?;
+            tree.push(IncludedConfig {
+                depth: context.include_chain.len(),
+                source: source.to_string(),
+                subfolder: inc.subfolder.clone(),
+                files: inc.files_of(&config, &inner, fetch)?.len(),
+            });
+            tree.extend(nested);
+        }
+        Ok(tree)
+    }
+
+    // Runs f on the config as it is with the given tags, resolved again if the tags set variables.
+    fn with_tag_variables<T>(
+        &self,
+        given_tags: &Vec<String>,
+        fetch: &FetchContext,
+        f: impl FnOnce(&Config, &Vec<String>) -> Result<T>,
+    ) -> Result<T> {
         if !self.variables_set {
             return Err(format_err!("Variables must have been set to get file list"));
         }
-        let defined_tags = self.tags();
-
-        let given_tags = given_tags.iter().map(|t| t.trim()).collect::<Vec<_>>();
-        let positive_tags = given_tags
-            .iter()
-            .filter(|t| !t.starts_with("!"))
-            .map(|t| t.to_string())
-            .collect::<Vec<_>>();
-
-        let negative_tags = given_tags
-            .iter()
-            .filter(|t| t.starts_with("!"))
-            .map(|t| t.strip_prefix("!").expect("could not remove !").to_string())
-            .collect::<Vec<_>>();
+        let tags = &self.active_tags(given_tags)?;
+        fetch.trace(|| format!("active tags {}", tags.join(", ")));
+        let overrides = tag_overrides(&self.variables, tags)?;
+        if overrides.len() > 0 {
+            fetch.trace(|| {
+                format!(
+                    "resolving again, the tags set {} variable(s)",
+                    overrides.len()
+                )
+            });
+            let unresolved = self
+                .unresolved
+                .as_ref()
+                .context("The unresolved config is needed for tag variables")?;
+            let resolved = unresolved.resolve(self.builtin_variables.clone(), &overrides, fetch)?;
+            return f(&resolved, tags);
+        }
+        f(self, tags)
+    }
 
-        for nt in &negative_tags {
-            if positive_tags.contains(&nt) {
-                return Err(format_err!("You try to negate a tag while activating it"));
+    // The part of get_active after the tags have been checked.
+    fn collect_active(
+        &self,
+        tags: &Vec<String>,
+        context: &ListContext,
+        fetch: &FetchContext,
+    ) -> Result<Vec<File>> {
+        let mut new_content = vec![];
+        for candidate in self.candidates(tags, context, fetch)? {
+            match (candidate.decision, candidate.file) {
+                (Decision::Active, Some(file)) => new_content.push(file),
+                (Decision::Collision, _) => {
+                    return Err(format_err!(
+                        "There are two files for path {}",
+                        &candidate.path.to_string_lossy()
+                    ))
+                }
+                _ => {}
             }
         }
+        fetch.trace(|| format!("{} active file(s)", new_content.len()));
 
-        let tags = &vecset(vec![self.default_tags.clone(), positive_tags])
+        Ok(new_content)
+    }
+
+    // Every file that could be part of the folder and what was decided about it.
+    // Inclusions and directories that are not expanded at all are candidates for their whole folder.
+    fn candidates(
+        &self,
+        tags: &Vec<String>,
+        context: &ListContext,
+        fetch: &FetchContext,
+    ) -> Result<Vec<Candidate>> {
+        let mut candidates = vec![];
+        let mut file_list = self
+            .content
             .iter()
-            .filter(|p| !negative_tags.contains(p))
-            .map(|t| t.to_string())
+            .map(|f| (f.clone(), "the config".to_string()))
             .collect::<Vec<_>>();
-
-        for requested_tag in tags {
-            if !defined_tags.contains(requested_tag) {
-                return Err(format_err!(
-                    "The tag {} is not defined in the config file.",
-                    requested_tag
+        fetch.trace(|| format!("{} file(s) defined directly", self.content.len()));
+        for inc in &self.inclusions {
+            let origin = format!("include {}", inc.config);
+            if !platform_matches(&inc.os, &inc.arch) {
+                fetch.trace(|| format!("skipped include {} on this platform", inc.config));
+                candidates.push(Candidate::folder(
+                    format_subpath(&inc.subfolder),
+                    origin,
+                    inc.tags.clone().unwrap_or(vec![]),
+                    Decision::OtherPlatform,
+                ));
+                continue;
+            }
+            fetch.trace(|| {
+                format!(
+                    "include {} at {}",
+                    inc.config,
+                    format_subpath(&inc.subfolder).display()
+                )
+            });
+            let included = trace_nested(|| inc.get_files(context, fetch))?;
+            fetch.trace(|| format!("{} file(s) from {}", included.len(), inc.config));
+            file_list.extend(included.into_iter().map(|f| (f, origin.clone())))
+        }
+        for dir in &self.directories {
+            let origin = format!("directory {}", dir.get_path().display());
+            if !dir.platform_matches() {
+                fetch.trace(|| {
+                    format!(
+                        "skipped directory {} on this platform",
+                        dir.get_path().display()
+                    )
+                });
+                candidates.push(Candidate::folder(
+                    dir.get_path(),
+                    origin,
+                    dir.get_tags(),
+                    Decision::OtherPlatform,
+                ));
+                continue;
+            }
+            if !dir.is_active(tags) {
+                candidates.push(Candidate::folder(
+                    dir.get_path(),
+                    origin,
+                    dir.get_tags(),
+                    Decision::Inactive,
                 ));
+                continue;
             }
+            let listed = dir.get_active(&tags, context.lenient_count, fetch)?;
+            file_list.extend(listed.into_iter().map(|f| (f, origin.clone())))
         }
-        let mut new_content = vec![];
-        let mut file_list = self.content.clone();
-        for inc in &self.inclusions {
-            file_list.append(&mut inc.get_files()?)
+        for manifest in &self.manifests {
+            let origin = format!("manifest {}", manifest.get_path().display());
+            let decision = if !manifest.platform_matches() {
+                Decision::OtherPlatform
+            } else if !manifest.is_active(tags) {
+                Decision::Inactive
+            } else {
+                let listed = manifest.get_all_files(fetch)?;
+                file_list.extend(listed.into_iter().map(|f| (f, origin.clone())));
+                continue;
+            };
+            candidates.push(Candidate::folder(
+                manifest.get_path(),
+                origin,
+                manifest.get_tags(),
+                decision,
+            ));
         }
-        for dir in &self.directories {
-            file_list.append(&mut dir.get_active(&tags)?)
+        // Files for other platforms must not shadow the ones for this platform.
+        let (file_list, other_platform): (Vec<_>, Vec<_>) = file_list
+            .into_iter()
+            .partition(|(item, _)| platform_matches(&item.os, &item.arch));
+        for (item, origin) in other_platform {
+            fetch.trace(|| format!("skipped {} on this platform", item.get_path().display()));
+            candidates.push(Candidate::file(item, origin, Decision::OtherPlatform));
         }
         let mut paths = vec![];
         let tagged_paths = file_list
             .iter()
-            .filter(|i| i.get_tags().iter().any(|ct| tags.contains(ct)))
-            .map(|i| i.get_path().to_owned())
+            .filter(|(i, _)| i.get_tags().iter().any(|ct| tags.contains(ct)))
+            .map(|(i, _)| i.get_path().to_owned())
             .collect::<Vec<PathBuf>>();
-        for item in &file_list {
+        for (item, origin) in file_list {
             if !item.is_active(tags) {
+                fetch.trace(|| {
+                    format!(
+                        "inactive {} (tags {})",
+                        item.get_path().display(),
+                        item.get_tags().join(", ")
+                    )
+                });
+                candidates.push(Candidate::file(item, origin, Decision::Inactive));
                 continue;
             }
             if item.get_tags().is_empty() && tagged_paths.contains(&item.get_path()) {
+                fetch.trace(|| {
+                    format!(
+                        "shadowed {} (untagged, replaced by a tagged file)",
+                        item.get_path().display()
+                    )
+                });
+                candidates.push(Candidate::file(item, origin, Decision::Shadowed));
                 continue;
             }
             if paths.contains(&item.get_path()) {
-                return Err(format_err!(
-                    "There are two files for path {}",
-                    &item.get_path().to_string_lossy()
-                ));
+                candidates.push(Candidate::file(item, origin, Decision::Collision));
+                continue;
             }
 
-            new_content.push(item.clone());
-            paths.push(item.get_path().clone())
+            fetch.trace(|| {
+                format!(
+                    "file {} from {}",
+                    item.get_path().display(),
+                    item.sources
+                        .iter()
+                        .map(|s| s.to_string())
+                        .collect::<Vec<_>>()
+                        .join(" | ")
+                )
+            });
+            paths.push(item.get_path().clone());
+            candidates.push(Candidate::file(item, origin, Decision::Active));
         }
-
-        Ok(new_content)
+        Ok(candidates)
     }
 
-    fn from_filesource(source: &FileSource, allow_local: bool, hash: Option<&str>) -> Result<Self> {
-        let data = match source {
-            FileSource::Local { path } => {
-                if path.is_relative() && !allow_local {
-                    return Err(format_err!(
-                        "Trying to load config from relative path {:?}",
-                        path
-                    ));
-                }
+    // The active tags and the candidates for the path, in the order they were considered.
+    pub fn explain(
+        &self,
+        given_tags: &Vec<String>,
+        path: &PathBuf,
+        fetch: &FetchContext,
+    ) -> Result<(Vec<String>, Vec<Candidate>)> {
+        let path = format_subpath(path);
+        self.with_tag_variables(given_tags, fetch, |config, tags| {
+            let candidates = config
+                .candidates(tags, &ListContext::default(), fetch)?
+                .into_iter()
+                .filter(|c| match c.file {
+                    Some(_) => c.path == path,
+                    None => path.starts_with(&c.path),
+                })
+                .collect();
+            Ok((tags.clone(), candidates))
+        })
+    }
 
-                fs::read(path).context(format!("Could not load config {}", path.display()))?
-            }
-            FileSource::Git { .. } => source.fetch()?,
-            _ => {
-                return Err(format_err!("Loading config from unsupported filesource."));
-            }
-        };
-        // This is only relevant if the config was included.
-        if let Some(hash) = hash {
-            if compute_hash(&data) != hash {
+    fn from_filesource(
+        source: &FileSource,
+        allow_local: bool,
+        hash: Option<&str>,
+        source_root: Option<&str>,
+        passed_variables: &HashMap<String, String>,
+        profile: Option<&str>,
+        fetch: &FetchContext,
+    ) -> Result<Self> {
+        fetch.trace(|| format!("load config {}", source));
+        let data = read_config_source(source, allow_local, fetch)?;
+        // This is set if the config was included with a hash or the hash was given on the cli.
+        if let (Some(hash), true) = (hash, hash_check_enabled()) {
+            if !hash_matches(hash, &data)? {
                 return Err(format_err!("Hash of loaded config did not match."));
             }
         }
+        record_loaded_config(source, &data)?;
         let toml_string = String::from_utf8(data)?;
 
-        let conf: Self = toml::from_str(&toml_string)?;
+        let mut conf: Self = toml::from_str(&toml_string)?;
+        conf.check_sources()?;
+        conf.passed_variables = passed_variables.clone();
+        conf.profile = profile.map(|p| p.to_string());
 
-        Ok(conf.set_variables(source)?)
+        Ok(conf.set_variables(source, source_root, fetch)?)
     }
 
     // The allow_local flag is to make sure that local files are only valid, when the path was passed on the cli.
+    // Only the config given on the command line sees the values of --config-var and --profile.
+    // Included configs get what their inclusion passes with with_vars.
     pub fn from_general_path(
         general_path: &str,
         allow_local: bool,
         hash: Option<&str>,
+        fetch: &FetchContext,
     ) -> Result<Self> {
+        let config_vars = &fetch.config_vars;
         let source = cli::source_from_string_simple(general_path)?;
-        Self::from_filesource(&source, allow_local, hash)
+        let config = Self::from_filesource(
+            &source,
+            allow_local,
+            hash,
+            None,
+            config_vars,
+            fetch.profile.as_deref(),
+            fetch,
+        )?;
+        if let Some(unknown) = config_vars.keys().find(|k| !config.defines_variable(k)) {
+            return Err(format_err!(
+                "The config {} does not define the variable {} of --config-var.",
+                general_path,
+                unknown
+            ));
+        }
+        if let Some(profile) = config.selected_profile()? {
+            if let Some(unknown) = profile.vars.keys().find(|k| !config.defines_variable(k)) {
+                return Err(format_err!(
+                    "The config {} does not define the variable {} of the profile.",
+                    general_path,
+                    unknown
+                ));
+            }
+        }
+        Ok(config)
+    }
+
+    // Only a file that is verified in the output can leave out its sources.
+    fn check_sources(&self) -> Result<()> {
+        match self
+            .content
+            .iter()
+            .find(|f| f.sources.is_empty() && !f.verify_only)
+        {
+            Some(file) => Err(format_err!(
+                "The file {} has no sources. Only a file with verify_only = true can leave them out.",
+                file.get_path().display()
+            )),
+            None => Ok(()),
+        }
+    }
+
+    fn selected_profile(&self) -> Result<Option<&Profile>> {
+        let Some(name) = &self.profile else {
+            return Ok(None);
+        };
+        match self.profiles.get(name) {
+            Some(profile) => Ok(Some(profile)),
+            None => Err(format_err!(
+                "The profile {} is not defined in the config file. Defined profiles: {}",
+                name,
+                self.profiles.keys().cloned().collect::<Vec<_>>().join(", ")
+            )),
+        }
+    }
+
+    // A plain, file or secret variable, a value in a tag section or a parameter.
+    fn defines_variable(&self, name: &str) -> bool {
+        self.parameters.iter().any(|p| p == name)
+            || self.variables.iter().any(|(k, v)| match v {
+                VariableValue::Tagged(values) => values.contains_key(name),
+                _ => k == name,
+            })
     }
     #[allow(unused)] // This is handy if one wants to see what a new field looks like in a .toml file.
     pub fn write(&self, path: &PathBuf) -> Result<()> {
-        let toml = toml::to_string_pretty(self)?;
-        fs::write(path, toml)?;
+        fs::write(path, self.to_canonical_toml()?)?;
         Ok(())
     }
 
-    pub fn set_variables(&self, source: &FileSource) -> Result<Self> {
+    // The config as it is written, with sorted keys and the default values filled in.
+    // Nothing is resolved, so the variables are still in place.
+    pub fn canonical(general_path: &str, fetch: &FetchContext) -> Result<String> {
+        let source = cli::source_from_string_simple(general_path)?;
+        let data = read_config_source(&source, true, fetch)?;
+        let conf: Self = toml::from_str(&String::from_utf8(data)?)?;
+        conf.to_canonical_toml()
+    }
+
+    // Going through a toml::Value sorts the keys, also the ones of the variables.
+    fn to_canonical_toml(&self) -> Result<String> {
+        let mut value = toml::Value::try_from(self)?;
+        remove_empty(&mut value);
+        Ok(toml::to_string_pretty(&value)?)
+    }
+
+    // The source root replaces SELF_ROOT and SELF_PARENT before the config's own variables are resolved.
+    pub fn set_variables(
+        &self,
+        source: &FileSource,
+        source_root: Option<&str>,
+        fetch: &FetchContext,
+    ) -> Result<Self> {
         if self.variables_set {
             // This should never happen.
             return Err(format_err!(
                 "Trying to set variables twice for the same config."
             ));
         }
-        let mut new = self.clone();
+        let tagged_keys = self.variables.values().flat_map(|v| match v {
+            VariableValue::Tagged(values) => values.keys().collect(),
+            _ => vec![],
+        });
         if self
             .variables
             .keys()
-            .into_iter()
+            .chain(tagged_keys)
+            .chain(self.passed_variables.keys())
             .any(|k| k.starts_with("SELF_") || k.starts_with("#") || k.starts_with("!"))
         {
             return Err(format_err!(
@@ -164,7 +494,7 @@ impl Config {
             ));
         }
 
-        let mut vars = self.variables.clone();
+        let mut vars: HashMap<String, String> = HashMap::new();
         match source {
             FileSource::Git { repo, id, path } => {
                 vars.insert("SELF_ID".to_string(), id.to_string());
@@ -216,24 +546,255 @@ impl Config {
                 ));
             }
         }
-        vars = resolve_variable_inter_refs(&vars)?;
+        if let Some(root) = source_root {
+            if !is_url_or_ssh(root) && !root.contains("#") && PathBuf::from(root).is_relative() {
+                return Err(format_err!(
+                    "The source root {} must be an absolute path or a repo root.",
+                    root
+                ));
+            }
+            vars.insert("SELF_PARENT".to_string(), root.to_string());
+            vars.insert("SELF_ROOT".to_string(), root.to_string());
+        }
+        self.resolve(vars, &HashMap::new(), fetch)
+    }
+
+    // Fills in the variables. The overrides are the values of the tag sections.
+    fn resolve(
+        &self,
+        builtin: HashMap<String, String>,
+        overrides: &HashMap<String, String>,
+        fetch: &FetchContext,
+    ) -> Result<Self> {
+        let mut new = self.clone();
+        // The values passed by the inclusion (or --config-var) win over everything the config sets itself.
+        let mut overrides = overrides.clone();
+        if let Some(profile) = self.selected_profile()? {
+            overrides.extend(profile.vars.clone());
+        }
+        overrides.extend(self.passed_variables.clone());
+        let vars = evaluate_variables(
+            &self.variables,
+            builtin.clone(),
+            &overrides,
+            fetch.allow_exec,
+        )?;
+        if let Some(missing) = self.parameters.iter().find(|p| !vars.contains_key(*p)) {
+            return Err(MissingVariable(missing.clone()).into());
+        }
+        // The trace hides the values of secrets, like the variables command.
+        fetch.trace(|| {
+            let mut resolved = vars
+                .iter()
+                .map(|(k, v)| format!("{}={}", k, v))
+                .collect::<Vec<_>>();
+            resolved.sort();
+            format!("variables {}", resolved.join(", "))
+        });
+        let default_tags = self
+            .default_tags
+            .set_variables(&vars)?
+            .iter()
+            .map(|t| t.trim().to_string())
+            .collect();
 
+        for file in new.content.iter_mut() {
+            file.read_text_files(&vars)?;
+        }
+        for directory in new.directories.iter_mut() {
+            directory.read_text_files(&vars)?;
+        }
+        for manifest in new.manifests.iter_mut() {
+            manifest.read_text_files(&vars)?;
+        }
         new.content = new.content.set_variables(&vars)?;
         new.directories = new.directories.set_variables(&vars)?;
+        for file in new.content.iter_mut() {
+            file.edits = file.edits.iter().map(|e| e.with_variables(&vars)).collect();
+        }
+        for directory in new.directories.iter_mut() {
+            directory.set_edit_variables(&vars);
+        }
+        new.manifests = new.manifests.set_variables(&vars)?;
+        for manifest in new.manifests.iter_mut() {
+            manifest.variables = vars.clone();
+        }
         new.inclusions = new.inclusions.set_variables(&vars)?;
+        for inclusion in new.inclusions.iter_mut() {
+            inclusion.parent_variables = vars.clone();
+        }
         let conf = Self {
             variables: new.variables,
+            resolved_variables: vars,
+            builtin_variables: builtin,
+            unresolved: Some(Box::new(self.clone())),
             variables_set: true,
             content: new.content,
             inclusions: new.inclusions,
             directories: new.directories,
-            default_tags: self.default_tags.clone(),
+            manifests: new.manifests,
+            default_tags,
+            exclusive_tags: new.exclusive_tags,
+            parameters: new.parameters,
+            passed_variables: new.passed_variables,
+            profiles: new.profiles,
+            profile: new.profile,
         };
         // This is a little ugly and the validation might be missed.
         validate_tags(&conf.tags())?;
+        let defined_tags = conf.tags();
+        for (group, members) in &conf.exclusive_tags {
+            if let Some(undefined) = members.iter().find(|m| !defined_tags.contains(m)) {
+                return Err(format_err!(
+                    "The tag {} in the exclusive group {} is not defined in the config file.",
+                    undefined,
+                    group
+                ));
+            }
+        }
         Ok(conf)
     }
 
+    // The given tags (with negations and globs) combined with the default tags.
+    pub fn active_tags(&self, given_tags: &Vec<String>) -> Result<Vec<String>> {
+        let defined_tags = self.tags();
+
+        let given_tags = given_tags.iter().map(|t| t.trim()).collect::<Vec<_>>();
+        let mut positive_tags = given_tags
+            .iter()
+            .filter(|t| !t.starts_with("!") && !is_tag_glob(t))
+            .map(|t| t.to_string())
+            .collect::<Vec<_>>();
+
+        let mut negative_tags = vec![];
+        for nt in given_tags.iter().filter_map(|t| t.strip_prefix("!")) {
+            if is_tag_glob(nt) {
+                negative_tags.append(&mut expand_tag_glob(nt, &defined_tags)?);
+            } else {
+                negative_tags.push(nt.to_string());
+            }
+        }
+
+        for nt in &negative_tags {
+            if positive_tags.contains(&nt) {
+                return Err(format_err!("You try to negate a tag while activating it"));
+            }
+        }
+        // Tags matched by a glob can be subtracted again with a negation.
+        for glob in given_tags
+            .iter()
+            .filter(|t| !t.starts_with("!") && is_tag_glob(t))
+        {
+            positive_tags.append(&mut expand_tag_glob(glob, &defined_tags)?);
+        }
+
+        let profile_tags = match self.selected_profile()? {
+            Some(profile) => profile.tags.clone(),
+            None => vec![],
+        };
+        let tags = vecset(vec![self.default_tags.clone(), profile_tags, positive_tags])
+            .iter()
+            .filter(|p| !negative_tags.contains(p))
+            .map(|t| t.to_string())
+            .collect::<Vec<_>>();
+
+        for requested_tag in &tags {
+            if !defined_tags.contains(requested_tag) {
+                return Err(format_err!(
+                    "The tag {} is not defined in the config file.",
+                    requested_tag
+                ));
+            }
+        }
+        for (group, members) in &self.exclusive_tags {
+            let active = members
+                .iter()
+                .filter(|m| tags.contains(m))
+                .cloned()
+                .collect::<Vec<_>>();
+            if active.len() > 1 {
+                return Err(format_err!(
+                    "The tags {} can not be active together, they are in the exclusive group {}.",
+                    active.join(" and "),
+                    group
+                ));
+            }
+        }
+        Ok(tags)
+    }
+
+    // The output directory of sync can use the variables of the config, including the ones set by tags.
+    pub fn output_with_variables(
+        &self,
+        output: &PathBuf,
+        given_tags: &Vec<String>,
+        fetch: &FetchContext,
+    ) -> Result<PathBuf> {
+        let overrides = tag_overrides(&self.variables, &self.active_tags(given_tags)?)?;
+        if overrides.len() > 0 {
+            let vars = evaluate_variables(
+                &self.variables,
+                self.builtin_variables.clone(),
+                &overrides,
+                fetch.allow_exec,
+            )?;
+            return fill_in_output_path(output, &vars);
+        }
+        fill_in_output_path(output, &self.resolved_variables)
+    }
+
+    // Lists the resolved variables with a note on where they came from.
+    // The values of secrets are hidden, also inside of the variables that were built from them.
+    pub fn variable_overview(&self) -> Vec<(String, String, Option<&'static str>)> {
+        let mut overview = self
+            .resolved_variables
+            .iter()
+            .map(|(k, v)| {
+                let origin = match self.variables.get(k) {
+                    None => Some("built-in"),
+                    Some(VariableValue::File(_)) => Some("from file"),
+                    Some(VariableValue::Secret(_)) => Some("secret"),
+                    Some(VariableValue::Plain(_)) => None,
+                    Some(VariableValue::Tagged(_)) => None,
+                };
+                (k.clone(), hide_secrets(v), origin)
+            })
+            .collect::<Vec<_>>();
+        overview.sort();
+        overview
+    }
+
+    // The remote repos the config refers to directly. Included configs are not loaded for this.
+    pub fn remote_repos(&self) -> Vec<String> {
+        let mut repos = vec![];
+        for f in &self.content {
+            repos.extend(f.sources.iter().filter_map(|s| s.remote_repo()));
+        }
+        for d in self.directories.iter().filter(|d| d.platform_matches()) {
+            repos.extend(d.remote_repos());
+        }
+        for m in self.manifests.iter().filter(|m| m.platform_matches()) {
+            repos.extend(m.remote_repos());
+        }
+        for inc in &self.inclusions {
+            if !platform_matches(&inc.os, &inc.arch) {
+                continue;
+            }
+            if let Ok(source) = source_from_string_simple(&inc.config) {
+                repos.extend(source.remote_repo());
+            }
+        }
+        vecset(vec![repos])
+    }
+
+    // Local directories the config reads from. Files are not listed here, they are part of get_active.
+    pub fn local_directory_roots(&self) -> Vec<PathBuf> {
+        self.directories
+            .iter()
+            .flat_map(|d| d.local_roots())
+            .collect()
+    }
+
     pub fn tags(&self) -> Vec<String> {
         let mut taglists = vec![];
         for file in &self.content {
@@ -246,11 +807,117 @@ impl Config {
             taglists.push(inc.tags.clone().unwrap_or(vec![]));
         }
         for d in &self.directories {
-            taglists.push(d.get_tags());
+            taglists.push(d.defined_tags());
+            for e in d.get_edits() {
+                taglists.push(e.get_tags().clone())
+            }
+        }
+        for m in &self.manifests {
+            taglists.push(m.get_tags());
+        }
+        for (k, v) in &self.variables {
+            if let VariableValue::Tagged(_) = v {
+                taglists.push(vec![k.clone()])
+            }
         }
 
         vecset(taglists)
     }
+
+    // Tags that appear only on edits (path, number of the edit, tag). Nothing else selects them,
+    // so the edit only runs if exactly this tag is given. That is often a typo.
+    pub fn edit_only_tags(&self) -> Vec<(PathBuf, usize, String, String)> {
+        let mut selecting = self.default_tags.clone();
+        for file in &self.content {
+            selecting.extend(file.get_tags());
+        }
+        for inc in &self.inclusions {
+            selecting.extend(inc.tags.clone().unwrap_or(vec![]));
+        }
+        for d in &self.directories {
+            selecting.extend(d.defined_tags());
+        }
+        for m in &self.manifests {
+            selecting.extend(m.get_tags());
+        }
+        for profile in self.profiles.values() {
+            selecting.extend(profile.tags.clone());
+        }
+        for (k, v) in &self.variables {
+            if let VariableValue::Tagged(_) = v {
+                selecting.push(k.clone());
+            }
+        }
+        let edits = self.content.iter().map(|f| (f.get_path(), &f.edits)).chain(
+            self.directories
+                .iter()
+                .map(|d| (d.get_path(), d.get_edits())),
+        );
+        let mut found = vec![];
+        for (path, edits) in edits {
+            for (i, edit) in edits.iter().enumerate() {
+                for tag in edit.get_tags() {
+                    if selecting.contains(tag) {
+                        continue;
+                    }
+                    // A tag of its own (like flip) is fine, one that is close to a selected tag is likely a typo.
+                    if let Some(similar) = selecting.iter().find(|s| is_near_miss(tag, s)) {
+                        found.push((path.clone(), i + 1, tag.clone(), similar.clone()));
+                    }
+                }
+            }
+        }
+        found
+    }
+
+    // Compares the default selection with the one where the tag is toggled.
+    // Default tags are toggled off, all others are toggled on.
+    // Returns the number of added, removed and changed files.
+    pub fn tag_effect(&self, tag: &str, fetch: &FetchContext) -> Result<(usize, usize, usize)> {
+        let (toggled, toggled_tags) = if self.default_tags.contains(&tag.to_string()) {
+            let remaining = self
+                .default_tags
+                .iter()
+                .filter(|t| *t != tag)
+                .cloned()
+                .collect::<Vec<_>>();
+            (format!("!{}", tag), remaining)
+        } else {
+            (
+                tag.to_string(),
+                vecset(vec![self.default_tags.clone(), vec![tag.to_string()]]),
+            )
+        };
+        let before = self.get_active(&vec![], fetch)?;
+        let after = self.get_active(&vec![toggled], fetch)?;
+        let active_edits = |f: &File, tags: &Vec<String>| {
+            f.edits
+                .iter()
+                .filter(|e| e.is_active(tags))
+                .cloned()
+                .collect::<Vec<_>>()
+        };
+        let (mut added, mut removed, mut changed) = (0, 0, 0);
+        for file in &after {
+            match before.iter().find(|b| b.get_path() == file.get_path()) {
+                None => added += 1,
+                Some(b) => {
+                    let same_source = b.sources == file.sources && b.decrypt == file.decrypt;
+                    if !same_source
+                        || active_edits(b, &self.default_tags) != active_edits(file, &toggled_tags)
+                    {
+                        changed += 1
+                    }
+                }
+            }
+        }
+        for file in &before {
+            if !after.iter().any(|a| a.get_path() == file.get_path()) {
+                removed += 1
+            }
+        }
+        Ok((added, removed, changed))
+    }
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -259,12 +926,32 @@ pub struct File {
     pub path: PathBuf,
     pub tags: Option<Vec<String>>,
     pub hash: Option<String>,
-    #[serde(rename = "sources", alias = "source")]
+    // A file with the expected hash, like file.txt.sha3 next to file.txt.
+    pub hash_source: Option<FileSource>,
+    // Can only be left out for a file that is only verified, this is checked when the config is loaded.
+    #[serde(rename = "sources", alias = "source", default)]
     pub sources: Vec<FileSource>,
     #[serde(rename = "edit", default)]
     pub edits: Vec<FileEdit>,
     #[serde(rename = "decrypt", default)]
     pub decrypt: DecryptionMethod,
+    // Every fetched source is decompressed before its hash is checked.
+    #[serde(default)]
+    pub decompress: Compression,
+    // A detached minisign signature over the fetched (possibly encrypted) bytes.
+    pub signature: Option<FileSource>,
+    pub public_key: Option<String>,
+    // If no source can be fetched, the file is skipped with a warning. A wrong hash is still an error.
+    #[serde(default)]
+    pub optional: bool,
+    // The file in the output must already exist and match the hash. It is never fetched or changed.
+    #[serde(default)]
+    pub verify_only: bool,
+    // Only used on these systems and architectures, whatever the tags are. Empty means everywhere.
+    #[serde(default)]
+    pub os: Vec<String>,
+    #[serde(default)]
+    pub arch: Vec<String>,
 }
 
 impl File {
@@ -278,6 +965,22 @@ impl File {
     pub fn get_path(&self) -> PathBuf {
         format_subpath(&self.path)
     }
+    // Every text source with from_file is read, wherever it is used.
+    fn read_text_files(&mut self, vars: &HashMap<String, String>) -> Result<()> {
+        self.sources = self
+            .sources
+            .iter()
+            .map(|s| s.with_text_file(vars))
+            .collect::<Result<_>>()?;
+        self.hash_source = with_optional_text_file(&self.hash_source, vars)?;
+        self.signature = with_optional_text_file(&self.signature, vars)?;
+        self.edits = self
+            .edits
+            .iter()
+            .map(|e| e.with_text_file(vars))
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
     fn is_active(&self, reqtags: &Vec<String>) -> bool {
         let tags = self.get_tags();
         if tags.len() == 0 {
@@ -290,22 +993,106 @@ impl File {
         }
         false
     }
-    pub fn from_reference_unchecked(&self, data: &Vec<u8>, tags: &Vec<String>) -> Result<Vec<u8>> {
+    pub fn from_reference_unchecked(
+        &self,
+        data: &Vec<u8>,
+        tags: &Vec<String>,
+        fetch: &FetchContext,
+    ) -> Result<Vec<u8>> {
+        self.apply_edits(data, tags, fetch)
+    }
+    fn apply_edits(
+        &self,
+        data: &Vec<u8>,
+        tags: &Vec<String>,
+        fetch: &FetchContext,
+    ) -> Result<Vec<u8>> {
         if self.edits.len() == 0 {
             return Ok(data.clone());
         } else {
-            let mut strdata = String::from_utf8(data.clone())?;
-            for edit in &self.edits {
-                if !edit.is_active(tags) {
-                    continue;
-                }
-                strdata = edit.apply(&strdata)?;
-            }
-            return Ok(strdata.into_bytes());
+            return timed(
+                || format!("edit {}", self.get_path().display()),
+                || {
+                    let active = self
+                        .edits
+                        .iter()
+                        .filter(|e| e.is_active(tags))
+                        .collect::<Vec<_>>();
+                    // The text is decoded by the transcode edit and encoded again at the end.
+                    let transcode = match active.first() {
+                        Some(FileEdit::Transcode {
+                            from_encoding,
+                            to_encoding,
+                            ..
+                        }) => Some((from_encoding, to_encoding)),
+                        _ => None,
+                    };
+                    if active.iter().skip(1).any(|e| e.is_transcode()) {
+                        return Err(format_err!(
+                            "{}: The transcode edit must be the first edit.",
+                            self.get_path().display()
+                        ));
+                    }
+                    let mut strdata = match transcode {
+                        Some((from_encoding, _)) => decode_text(data, from_encoding)
+                            .map_err(|e| format_err!("{}: {}", self.get_path().display(), e))?,
+                        None => String::from_utf8(data.clone())?,
+                    };
+                    for edit in active {
+                        if !edit.condition_met(&strdata) {
+                            continue;
+                        }
+                        strdata = edit
+                            .apply(&strdata, fetch)
+                            .map_err(|e| format_err!("{}: {}", self.get_path().display(), e))?;
+                    }
+                    match transcode {
+                        Some((_, to_encoding)) => encode_text(&strdata, to_encoding)
+                            .map_err(|e| format_err!("{}: {}", self.get_path().display(), e)),
+                        None => Ok(strdata.into_bytes()),
+                    }
+                },
+            );
         }
     }
-    pub fn build(&self, tags: &Vec<String>, ids: &Vec<age::x25519::Identity>) -> Result<Vec<u8>> {
-        let data = fetch_first_valid(&self.sources, &self.hash)?;
+    pub fn build(
+        &self,
+        tags: &Vec<String>,
+        ids: &Vec<AgeIdentity>,
+        warn_local_drift: bool,
+        fetch: &FetchContext,
+    ) -> Result<Vec<u8>> {
+        let decrypted = self.fetch_unedited(ids, warn_local_drift, fetch)?;
+        self.from_reference_unchecked(&decrypted, tags, fetch)
+    }
+    // The checked and decrypted content before any edits are applied.
+    pub fn fetch_unedited(
+        &self,
+        ids: &Vec<AgeIdentity>,
+        warn_local_drift: bool,
+        fetch: &FetchContext,
+    ) -> Result<Vec<u8>> {
+        let hash = self.expected_hash(fetch)?;
+        let mut failed = vec![];
+        // An encrypted file can only be decompressed after it is decrypted.
+        let result = fetch_first_valid(
+            &self.sources,
+            &hash,
+            self.fetched_compression(),
+            &mut failed,
+            fetch,
+        );
+        record_source_outcome(SourceOutcome {
+            path: self.get_path().clone(),
+            used: result.as_ref().ok().map(|(index, _)| *index),
+            total: self.sources.len(),
+            failed,
+        });
+        let (used, data) = result?;
+        if let (Some(hash), true) = (&hash, warn_local_drift) {
+            self.warn_local_drift(hash, used + 1);
+        }
+        self.check_signature(&data, fetch)?;
         let decrypted = match self.decrypt {
             DecryptionMethod::None => data,
             DecryptionMethod::AgeV1 => {
@@ -315,47 +1102,420 @@ impl File {
                         .template("{spinner:.green}{spinner:.green} {msg}")
                         .context("Failed because progress bar")?,
                 );
-                spinner.set_message(format!("Decrypting: {}", self.get_path().to_string_lossy()));
+                spinner.set_message(hide_secrets(&format!(
+                    "Decrypting: {}",
+                    self.get_path().to_string_lossy()
+                )));
                 spinner.enable_steady_tick(Duration::from_millis(50));
 
                 let d = decrypt_agev1(&data, ids)?;
-                spinner.finish_with_message(format!(
+                spinner.finish_with_message(hide_secrets(&format!(
                     "Decrypted: {}",
                     self.get_path().to_string_lossy()
-                ));
+                )));
                 d
             }
+            // No spinner, it would draw over the prompt for the passphrase.
+            DecryptionMethod::AgeV1Passphrase => {
+                decrypt_age_passphrase(&data, &self.get_path().to_string_lossy())?
+            }
         };
+        match self.decrypt {
+            DecryptionMethod::None => Ok(decrypted),
+            _ => self
+                .decompress
+                .decompress_decrypted(&self.sources[used], decrypted),
+        }
+    }
+    // What is decompressed before the hash is checked. For encrypted files, the hash is the one of the fetched bytes.
+    fn fetched_compression(&self) -> Compression {
+        match self.decrypt {
+            DecryptionMethod::None => self.decompress,
+            _ => Compression::None,
+        }
+    }
+    // With --warn-local-drift, the local sources that were not fetched are compared with the pin as well.
+    // The sources before `first` were already tried and reported.
+    pub fn warn_local_drift(&self, hash: &str, first: usize) {
+        if !hash_check_enabled() {
+            return;
+        }
+        for source in self.sources.iter().skip(first) {
+            let Some(path) = source.local_path() else {
+                continue;
+            };
+            // A compressed source is compared like it is fetched, after decompressing it.
+            let matches = match self.fetched_compression() {
+                Compression::None => fs::File::open(&path)
+                    .map_err(Error::from)
+                    .and_then(|file| hash_matches_reader(hash, file)),
+                compression => fs::read(&path)
+                    .map_err(Error::from)
+                    .and_then(|data| compression.decompress(source, data))
+                    .and_then(|data| hash_matches(hash, &data)),
+            };
+            let Ok(matches) = matches else {
+                continue;
+            };
+            if !matches {
+                red(format!(
+                    "The local source {} of {} has changed from its pinned hash.",
+                    path.display(),
+                    self.get_path().display()
+                ));
+            }
+        }
+    }
+    // The literal hash or the one fetched from hash_source. Not both.
+    pub fn expected_hash(&self, fetch: &FetchContext) -> Result<Option<String>> {
+        match (&self.hash, &self.hash_source) {
+            (hash, None) => Ok(hash.clone()),
+            (None, Some(_)) if !hash_check_enabled() => Ok(None),
+            (None, Some(source)) => {
+                let text = source.fetch(fetch).map_err(|e| {
+                    format_err!(
+                        "Could not fetch the hash of {} from {}: {}",
+                        self.get_path().display(),
+                        source,
+                        e
+                    )
+                })?;
+                parse_hash_file(&text)
+                    .map(Some)
+                    .map_err(|e| format_err!("Invalid hash file {}: {}", source, e))
+            }
+            (Some(_), Some(_)) => Err(format_err!(
+                "The file {} can not have both a hash and a hash_source.",
+                self.get_path().display()
+            )),
+        }
+    }
+    fn check_signature(&self, data: &Vec<u8>, fetch: &FetchContext) -> Result<()> {
+        match (&self.signature, &self.public_key) {
+            (None, None) => Ok(()),
+            (Some(signature), Some(key)) => {
+                let signature_data = signature
+                    .fetch(fetch)
+                    .map_err(|e| format_err!("Could not fetch signature {}: {}", signature, e))?;
+                verify_minisign(data, &signature_data, key).map_err(|e| {
+                    format_err!("Invalid signature for {}: {}", self.get_path().display(), e)
+                })
+            }
+            _ => Err(format_err!(
+                "The file {} needs both a signature and a public_key.",
+                self.get_path().display()
+            )),
+        }
+    }
+}
+
+// Which source of a file was used and why the ones before it were not. Printed by sync --verbose.
+pub struct SourceOutcome {
+    pub path: PathBuf,
+    pub used: Option<usize>,
+    pub total: usize,
+    pub failed: Vec<(usize, String)>,
+}
 
-        self.from_reference_unchecked(&decrypted, tags)
+static SOURCE_OUTCOMES: std::sync::Mutex<Vec<SourceOutcome>> = std::sync::Mutex::new(vec![]);
+
+fn record_source_outcome(outcome: SourceOutcome) {
+    if let Ok(mut outcomes) = SOURCE_OUTCOMES.lock() {
+        outcomes.push(outcome);
     }
 }
 
-fn fetch_first_valid(sources: &Vec<FileSource>, hash: &Option<String>) -> Result<Vec<u8>> {
-    for s in sources {
-        let result = s.fetch();
+pub fn take_source_outcomes() -> Vec<SourceOutcome> {
+    match SOURCE_OUTCOMES.lock() {
+        Ok(mut outcomes) => std::mem::take(&mut *outcomes),
+        Err(_) => vec![],
+    }
+}
 
-        if result.is_ok() {
-            if hash.is_none() {
-                return result;
-            } else {
-                if hash.as_ref().expect("must be some")
-                    == &compute_hash(&result.as_ref().expect("ref must exist"))
-                {
-                    return result;
+// The hashes of all configs in the resolution tree, keyed by their source.
+#[derive(Debug, Default, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ConfigLock {
+    // The configs next to the root config (or below it) are listed by their relative path,
+    // so the lockfile still works when the checkout is moved.
+    #[serde(default)]
+    pub configs: BTreeMap<String, String>,
+    #[serde(skip)]
+    root: Option<FileSource>, // The config given on the command line.
+}
+
+impl ConfigLock {
+    // The root is the config that is synced with the lockfile.
+    pub fn read(path: &PathBuf, root: &str) -> Result<Self> {
+        let text = fs::read_to_string(path)
+            .context(format!("Could not read lockfile {}", path.display()))?;
+        let lock: Self = toml::from_str(&text)
+            .map_err(|e| format_err!("Invalid lockfile {}: {}", path.display(), e))?;
+        Ok(Self {
+            root: Some(cli::source_from_string_simple(root)?),
+            ..lock
+        })
+    }
+
+    fn key(&self, source: &FileSource) -> String {
+        match &self.root {
+            Some(root) => lock_key(root, source),
+            None => source.to_string(),
+        }
+    }
+}
+
+// The path of the config relative to the directory of the root config.
+// A config in another repo or outside of that directory keeps its full source.
+fn lock_key(root: &FileSource, source: &FileSource) -> String {
+    let relative = match (root, source) {
+        (FileSource::Local { path: root }, FileSource::Local { path }) => {
+            match (root.canonicalize(), path.canonicalize()) {
+                (Ok(root), Ok(path)) => root
+                    .parent()
+                    .and_then(|parent| path.strip_prefix(parent).ok())
+                    .map(|p| p.to_path_buf()),
+                _ => None,
+            }
+        }
+        (
+            FileSource::Git {
+                repo: root_repo,
+                id: root_id,
+                path: root,
+            },
+            FileSource::Git { repo, id, path },
+        ) if id == root_id && same_repo(repo, root_repo) => {
+            let path = format_subpath(path);
+            format_subpath(root)
+                .parent()
+                .and_then(|parent| path.strip_prefix(parent).ok())
+                .map(|p| p.to_path_buf())
+        }
+        _ => None,
+    };
+    match relative {
+        Some(p) => p.to_string_lossy().replace('\\', "/"),
+        None => source.to_string(),
+    }
+}
+
+// Local repos are written relative or absolute, SELF_ROOT always uses the absolute path.
+fn same_repo(a: &str, b: &str) -> bool {
+    if is_url_or_ssh(a) || is_url_or_ssh(b) {
+        return a == b;
+    }
+    match (
+        PathBuf::from(a).canonicalize(),
+        PathBuf::from(b).canonicalize(),
+    ) {
+        (Ok(a), Ok(b)) => a == b,
+        _ => a == b,
+    }
+}
+
+pub const DEFAULT_MAX_INCLUDE_DEPTH: usize = 32;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Decision {
+    Active,
+    // None of the tags is active.
+    Inactive,
+    // Untagged and replaced by an active tagged file.
+    Shadowed,
+    OtherPlatform,
+    // There already is an active file for the path.
+    Collision,
+}
+
+// A file, or an inclusion or directory that was not expanded, as considered by get_active.
+#[derive(Debug)]
+pub struct Candidate {
+    pub path: PathBuf,
+    pub origin: String,
+    pub tags: Vec<String>,
+    pub sources: Vec<String>,
+    pub decision: Decision,
+    // None for an inclusion or a directory.
+    pub file: Option<File>,
+}
+
+impl Candidate {
+    fn file(file: File, origin: String, decision: Decision) -> Self {
+        Candidate {
+            path: file.get_path(),
+            origin,
+            tags: file.get_tags(),
+            sources: file.sources.iter().map(|s| s.to_string()).collect(),
+            decision,
+            file: Some(file),
+        }
+    }
+    fn folder(path: PathBuf, origin: String, tags: Vec<String>, decision: Decision) -> Self {
+        Candidate {
+            path,
+            origin,
+            tags,
+            sources: vec![],
+            decision,
+            file: None,
+        }
+    }
+}
+
+// An included config as shown by --print-includes. Depth 0 is included by the top config.
+pub struct IncludedConfig {
+    pub depth: usize,
+    pub source: String,
+    pub subfolder: PathBuf,
+    pub files: usize,
+}
+
+// What is passed down while the active files of a config and the configs it includes are collected.
+#[derive(Debug, Clone, Default)]
+pub struct ListContext {
+    // The configs that are currently being included, from the outermost one.
+    include_chain: Vec<String>,
+    // Set by --lenient-count. A wrong count of a directory is only a warning.
+    lenient_count: bool,
+    // Set by --max-include-depth. How deep configs can be included in each other.
+    max_include_depth: Option<usize>,
+}
+
+impl ListContext {
+    pub fn new(lenient_count: bool, max_include_depth: Option<usize>) -> Self {
+        ListContext {
+            lenient_count,
+            max_include_depth,
+            ..Default::default()
+        }
+    }
+}
+
+static LOADED_CONFIGS: Mutex<Vec<(FileSource, String)>> = Mutex::new(vec![]);
+
+// Every loaded config is recorded for lock --configs and checked against --config-lock.
+fn record_loaded_config(source: &FileSource, data: &Vec<u8>) -> Result<()> {
+    let hash = compute_hash(data);
+    if let (Some(lock), true) = (CONFIG_LOCK.get(), hash_check_enabled()) {
+        let key = lock.key(source);
+        match lock.configs.get(&key) {
+            None => return Err(format_err!("The config {} is not in the lockfile.", key)),
+            Some(locked) if !locked.eq_ignore_ascii_case(&hash) => {
+                return Err(format_err!(
+                    "The config {} does not match the lockfile.",
+                    key
+                ))
+            }
+            Some(_) => {}
+        }
+    }
+    if let Ok(mut loaded) = LOADED_CONFIGS.lock() {
+        loaded.push((source.clone(), hash));
+    }
+    Ok(())
+}
+
+// The lock for everything that was loaded while resolving the root config.
+pub fn take_loaded_configs(root: &str) -> Result<ConfigLock> {
+    let root = cli::source_from_string_simple(root)?;
+    let loaded = match LOADED_CONFIGS.lock() {
+        Ok(mut loaded) => std::mem::take(&mut *loaded),
+        Err(_) => vec![],
+    };
+    Ok(ConfigLock {
+        configs: loaded
+            .iter()
+            .map(|(source, hash)| (lock_key(&root, source), hash.clone()))
+            .collect(),
+        root: Some(root),
+    })
+}
+
+// Removes the files matched by --exclude. A pattern that matches nothing is probably a typo.
+pub fn exclude_files(
+    active: Vec<File>,
+    patterns: &[glob::Pattern],
+    fetch: &FetchContext,
+) -> Vec<File> {
+    for pattern in patterns {
+        if !active.iter().any(|f| pattern.matches_path(&f.get_path())) {
+            yellow(format!("The exclude pattern {} matches no file.", pattern));
+        }
+    }
+    active
+        .into_iter()
+        .filter(|f| {
+            let excluded = patterns.iter().any(|p| p.matches_path(&f.get_path()));
+            if excluded {
+                fetch.trace(|| format!("excluded {}", f.get_path().display()));
+            }
+            !excluded
+        })
+        .collect()
+}
+
+// The hash is the first word of the first line that is not empty, so "HASH  file.txt" works too.
+fn parse_hash_file(text: &[u8]) -> Result<String> {
+    let text = std::str::from_utf8(text).context("The hash file is not utf8")?;
+    let hash = text
+        .lines()
+        .find_map(|l| l.split_whitespace().next())
+        .context("The hash file is empty")?;
+    HashAlgo::normalize(hash)
+}
+
+// No source of a file could be fetched. A hash mismatch is not unreachable.
+#[derive(Debug)]
+pub struct Unreachable;
+
+impl std::fmt::Display for Unreachable {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "No valid source in list.")
+    }
+}
+
+impl std::error::Error for Unreachable {}
+
+// Returns the index of the source that was used. The sources that were tried before are added to failed.
+pub fn fetch_first_valid(
+    sources: &Vec<FileSource>,
+    hash: &Option<String>,
+    compression: Compression,
+    failed: &mut Vec<(usize, String)>,
+    fetch: &FetchContext,
+) -> Result<(usize, Vec<u8>)> {
+    let mut hash_mismatch = false;
+    for (index, s) in sources.iter().enumerate() {
+        let result = timed(|| format!("fetch {}", s), || s.fetch(fetch))
+            .and_then(|data| compression.decompress(s, data));
+
+        match result {
+            Ok(data) => {
+                let hash_ok = match hash {
+                    Some(hash) if hash_check_enabled() => hash_matches(hash, &data)?,
+                    _ => true,
+                };
+                if hash_ok {
+                    return Ok((index, data));
                 } else {
                     red(format!("Invalid hash {}", &s)); // This might not kill the program, but it is bad enough to warrant red text.
+                    failed.push((index, format!("{}: Invalid hash", s)));
+                    hash_mismatch = true;
                 }
             }
-        } else {
-            yellow(format!(
-                "Invalid source {} \nError: {}",
-                &s,
-                result.err().expect("error branch")
-            ));
+            // A policy violation is not hidden by falling back to the next source.
+            Err(e) if e.is::<FloatingRef>() => return Err(e),
+            Err(e) => {
+                yellow(format!("Invalid source {} \nError: {}", &s, e));
+                failed.push((index, format!("{}: {}", s, e)));
+            }
         }
     }
-    return Err(format_err!("No valid source in list."));
+    if hash_mismatch {
+        return Err(format_err!("No valid source in list."));
+    }
+    Err(Unreachable.into())
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -365,28 +1525,95 @@ pub struct Inclusion {
     pub tags: Option<Vec<String>>,
     #[serde(default)]
     pub with_tags: Vec<String>,
+    // If false, the default tags of the included config are ignored and only with_tags is used.
+    #[serde(default = "default_true")]
+    pub include_default_tags: bool,
     #[serde(default, rename = "path")]
     pub subfolder: PathBuf,
     pub hash: Option<String>,
+    // Replaces SELF_ROOT and SELF_PARENT of the included config.
+    pub source_root: Option<String>,
+    // Only used on these systems and architectures, whatever the tags are. Empty means everywhere.
+    #[serde(default)]
+    pub os: Vec<String>,
+    #[serde(default)]
+    pub arch: Vec<String>,
+    // Variables for the included config. They can use the variables of this config.
+    #[serde(default)]
+    pub with_vars: BTreeMap<String, String>,
+    #[serde(skip)]
+    pub parent_variables: HashMap<String, String>, // Only used to suggest with_vars.
 }
 impl Inclusion {
-    pub fn get_files(&self) -> Result<Vec<File>> {
-        let config =
-            Config::from_general_path(&self.config, false, self.hash.as_ref().map(|s| s.as_str()))?;
+    // Files from directories of the included config are part of its active files,
+    // so they are moved to the subfolder and tagged like all others.
+    pub fn get_files(&self, context: &ListContext, fetch: &FetchContext) -> Result<Vec<File>> {
+        let (source, inner) = self.enter(context)?;
+        let config = self.load_config(&source, fetch)?;
+        self.files_of(&config, &inner, fetch)
+    }
+
+    // The context for the included config, with this inclusion added to the chain.
+    fn enter(&self, context: &ListContext) -> Result<(FileSource, ListContext)> {
+        let source = cli::source_from_string_simple(&self.config)?;
+        let max = context
+            .max_include_depth
+            .unwrap_or(DEFAULT_MAX_INCLUDE_DEPTH);
+        let mut inner = context.clone();
+        inner.include_chain.push(source.to_string());
+        if context.include_chain.len() >= max {
+            return Err(format_err!(
+                "Configs are included more than {} levels deep (--max-include-depth): {}",
+                max,
+                inner.include_chain.join(" -> ")
+            ));
+        }
+        Ok((source, inner))
+    }
+
+    fn load_config(&self, source: &FileSource, fetch: &FetchContext) -> Result<Config> {
+        let passed = self.with_vars.clone().into_iter().collect();
+        let mut config = Config::from_filesource(
+            source,
+            false,
+            self.hash.as_ref().map(|s| s.as_str()),
+            self.source_root.as_ref().map(|s| s.as_str()),
+            &passed,
+            None,
+            fetch,
+        )
+        .map_err(|e| self.explain_missing_variable(e))?;
+        if !self.include_default_tags {
+            config.default_tags.clear();
+        }
+        Ok(config)
+    }
+
+    fn files_of(
+        &self,
+        config: &Config,
+        context: &ListContext,
+        fetch: &FetchContext,
+    ) -> Result<Vec<File>> {
         let mut files: Vec<File> = vec![];
-        for original_file in config.get_active(&self.with_tags)? {
+        for original_file in config.get_active_with(&self.with_tags, context, fetch)? {
             files.push(File {
                 path: self.subfolder.join(format_subpath(&original_file.path)),
                 tags: self.tags.clone(),
                 hash: original_file.hash,
+                hash_source: original_file.hash_source,
                 sources: original_file.sources,
                 edits: include_edits(&original_file.edits, &self.tags.clone().unwrap_or(vec![])),
                 decrypt: DecryptionMethod::None,
+                decompress: original_file.decompress,
+                signature: original_file.signature,
+                public_key: original_file.public_key,
+                optional: original_file.optional,
+                verify_only: original_file.verify_only,
+                os: vec![],
+                arch: vec![],
             })
         }
-        for d in &config.directories {
-            files.append(&mut d.get_active(&self.with_tags)?);
-        }
         // Including an empty file is forbidden, because lorevault knows only files and no empty directories.
         if files.len() == 0 {
             return Err(format_err!(
@@ -397,6 +1624,141 @@ impl Inclusion {
 
         Ok(files)
     }
+
+    fn explain_missing_variable(&self, error: Error) -> Error {
+        let Some(MissingVariable(name)) = error.downcast_ref::<MissingVariable>() else {
+            return error;
+        };
+        if self.parent_variables.contains_key(name) {
+            format_err!(
+                "The included config {} needs the variable {}. It is defined here, so it can be passed with with_vars = {{ {} = \"{{{{{}}}}}\" }} on the inclusion.",
+                self.config,
+                name,
+                name,
+                name
+            )
+        } else {
+            format_err!(
+                "The included config {} needs the variable {}. Define it there or pass it with with_vars on the inclusion.",
+                self.config,
+                name
+            )
+        }
+    }
+}
+
+// Empty lists and tables are the same as leaving them out, but they make the output harder to read.
+fn remove_empty(value: &mut toml::Value) {
+    match value {
+        toml::Value::Table(table) => {
+            table.iter_mut().for_each(|(_, v)| remove_empty(v));
+            table.retain(|_, v| !is_empty_value(v));
+        }
+        toml::Value::Array(array) => array.iter_mut().for_each(remove_empty),
+        _ => {}
+    }
+}
+
+fn is_empty_value(value: &toml::Value) -> bool {
+    match value {
+        toml::Value::Table(table) => table.is_empty(),
+        toml::Value::Array(array) => array.is_empty(),
+        _ => false,
+    }
+}
+
+// The allow_local flag is to make sure that local files are only valid, when the path was passed on the cli.
+fn read_config_source(
+    source: &FileSource,
+    allow_local: bool,
+    fetch: &FetchContext,
+) -> Result<Vec<u8>> {
+    match source {
+        FileSource::Local { path } => {
+            if path.is_relative() && !allow_local {
+                return Err(format_err!(
+                    "Trying to load config from relative path {:?}",
+                    path
+                ));
+            }
+
+            fs::read(path).context(format!("Could not load config {}", path.display()))
+        }
+        FileSource::Git { .. } => source.fetch(fetch),
+        _ => Err(format_err!("Loading config from unsupported filesource.")),
+    }
+}
+
+// The names are the ones of std::env::consts, like linux, macos, windows or x86_64 and aarch64.
+// For os, the family (unix or windows) works as well.
+pub fn platform_matches(os: &Vec<String>, arch: &Vec<String>) -> bool {
+    let os_matches = os.is_empty()
+        || os.iter().any(|o| {
+            o.eq_ignore_ascii_case(std::env::consts::OS)
+                || o.eq_ignore_ascii_case(std::env::consts::FAMILY)
+        });
+    let arch_matches = arch.is_empty()
+        || arch
+            .iter()
+            .any(|a| a.eq_ignore_ascii_case(std::env::consts::ARCH));
+    os_matches && arch_matches
+}
+
+// A config that recreates the directory as it is now, from local sources.
+// Each file is pinned by its hash, or the whole directory is one [[directory]] with the number of files.
+pub fn scaffold_config(dir: &PathBuf, as_directory: bool) -> Result<String> {
+    let root = dir
+        .canonicalize()
+        .context(format!("Could not find the directory {}", dir.display()))?;
+    if !root.is_dir() {
+        return Err(format_err!("{} is not a directory.", dir.display()));
+    }
+    let mut paths = list_files_in_folder(&root)?;
+    paths.sort();
+    let quote = |s: String| toml::Value::String(s).to_string();
+    let mut conf = format!("# Generated by lorevault init from {}.\n", root.display());
+    if as_directory {
+        conf.push_str(&format!(
+            "\n[[directory]]\npath = \"\"\ncount = {}\nsources = [{}]\n",
+            paths.len(),
+            quote(root.display().to_string())
+        ));
+        return Ok(conf);
+    }
+    for path in paths {
+        let full = root.join(&path);
+        let hash = compute_hash_reader(
+            fs::File::open(&full).context(format!("Could not read {}", full.display()))?,
+        )?;
+        conf.push_str(&format!(
+            "\n[[file]]\npath = {}\nhash = {}\nsources = [{}]\n",
+            quote(path.display().to_string()),
+            quote(hash),
+            quote(full.display().to_string())
+        ));
+    }
+    Ok(conf)
+}
+
+fn is_tag_glob(tag: &str) -> bool {
+    tag.contains(['*', '?', '['])
+}
+
+// A glob on the CLI stands for all defined tags it matches. It must match at least one.
+fn expand_tag_glob(glob: &str, defined_tags: &Vec<String>) -> Result<Vec<String>> {
+    let pattern = glob::Pattern::new(glob).context(format!("Invalid tag pattern {}", glob))?;
+    let matched = defined_tags
+        .iter()
+        .filter(|t| pattern.matches(t))
+        .cloned()
+        .collect::<Vec<_>>();
+    if matched.len() == 0 {
+        return Err(format_err!(
+            "The tag pattern {} does not match any tag defined in the config file.",
+            glob
+        ));
+    }
+    Ok(matched)
 }
 
 // We don't want tags to start with a ! or be a variant of the word default.
@@ -414,3 +1776,693 @@ fn validate_tags(tags: &Vec<String>) -> Result<()> {
     }
     Ok(())
 }
+
+// The same tag in another case or with at most two characters changed.
+fn is_near_miss(tag: &str, other: &str) -> bool {
+    if tag.eq_ignore_ascii_case(other) {
+        return true;
+    }
+    let (a, b): (Vec<char>, Vec<char>) = (tag.chars().collect(), other.chars().collect());
+    if a.len().min(b.len()) < 3 {
+        return false;
+    }
+    let mut previous: Vec<usize> = (0..=b.len()).collect();
+    for (i, ca) in a.iter().enumerate() {
+        let mut current = vec![i + 1];
+        for (j, cb) in b.iter().enumerate() {
+            let substitution = previous[j] + usize::from(ca != cb);
+            current.push(substitution.min(previous[j + 1] + 1).min(current[j] + 1));
+        }
+        previous = current;
+    }
+    previous[b.len()] <= 2
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    #[test]
+    fn test_tag_globs() {
+        let conf: Config = toml::from_str(
+            r#"
+            [[file]]
+            path = "web.txt"
+            tags = ["app:web"]
+            sources = ["/web"]
+            [[file]]
+            path = "db.txt"
+            tags = ["app:db"]
+            sources = ["/db"]
+            [[file]]
+            path = "other.txt"
+            tags = ["other"]
+            sources = ["/other"]
+            "#,
+        )
+        .unwrap();
+        let conf = conf
+            .set_variables(
+                &FileSource::Local {
+                    path: PathBuf::from("Cargo.toml"),
+                },
+                None,
+                &FetchContext::default(),
+            )
+            .unwrap();
+        let paths = |tags: Vec<&str>| -> Result<Vec<PathBuf>> {
+            let tags = tags.iter().map(|t| t.to_string()).collect();
+            let mut paths = conf
+                .get_active(&tags, &FetchContext::default())?
+                .iter()
+                .map(|f| f.get_path())
+                .collect::<Vec<_>>();
+            paths.sort();
+            Ok(paths)
+        };
+        assert_eq!(
+            paths(vec!["app:*"]).unwrap(),
+            vec![PathBuf::from("db.txt"), PathBuf::from("web.txt")]
+        );
+        assert_eq!(
+            paths(vec!["app:*", "!app:db"]).unwrap(),
+            vec![PathBuf::from("web.txt")]
+        );
+        assert_eq!(paths(vec!["!app:*"]).unwrap(), Vec::<PathBuf>::new());
+        let err = paths(vec!["ap:*"]).unwrap_err();
+        assert!(err.to_string().contains("does not match any tag"));
+        assert!(paths(vec!["!ap?"]).is_err());
+    }
+
+    #[test]
+    fn test_source_fallback() {
+        let sources = vec![
+            FileSource::Local {
+                path: PathBuf::from("/does_not_exist.txt"),
+            },
+            FileSource::Local {
+                path: PathBuf::from(env!("CARGO_MANIFEST_DIR"))
+                    .join("testing/testfolder/file1.txt"),
+            },
+        ];
+        let mut failed = vec![];
+        let (index, _) = fetch_first_valid(
+            &sources,
+            &None,
+            Compression::None,
+            &mut failed,
+            &FetchContext::default(),
+        )
+        .unwrap();
+        assert_eq!(index, 1);
+        assert_eq!(failed.len(), 1);
+        assert_eq!(failed[0].0, 0);
+        let mut failed = vec![];
+        let e = fetch_first_valid(
+            &sources[..1].to_vec(),
+            &None,
+            Compression::None,
+            &mut failed,
+            &FetchContext::default(),
+        )
+        .unwrap_err();
+        assert!(e.is::<Unreachable>());
+        assert_eq!(failed.len(), 1);
+        // A wrong hash is not the same as an unreachable source.
+        let e = fetch_first_valid(
+            &sources,
+            &Some("wrong".to_string()),
+            Compression::None,
+            &mut vec![],
+            &FetchContext::default(),
+        )
+        .unwrap_err();
+        assert!(!e.is::<Unreachable>());
+    }
+
+    #[test]
+    fn test_parse_hash_file() {
+        let hash = "741C077E70E4869ADBC29CCC34B7935B58DDAC16A4B8007AC127181E2148F468";
+        assert_eq!(parse_hash_file(hash.as_bytes()).unwrap(), hash);
+        assert_eq!(
+            parse_hash_file(format!("\n{}  file.txt\n", hash.to_lowercase()).as_bytes()).unwrap(),
+            hash
+        );
+        assert!(parse_hash_file(b"").is_err());
+        assert!(parse_hash_file(b"abc file.txt").is_err());
+        assert!(parse_hash_file(hash.replace('7', "x").as_bytes()).is_err());
+    }
+
+    #[test]
+    fn test_default_tag_variable() {
+        let conf: Config = toml::from_str(
+            r#"
+            default = ["{{profile}}"]
+            var.profile = "work"
+            [[file]]
+            path = "work.txt"
+            tags = ["work"]
+            sources = ["/work"]
+            [[file]]
+            path = "home.txt"
+            tags = ["home"]
+            sources = ["/home"]
+            "#,
+        )
+        .unwrap();
+        let source = FileSource::Local {
+            path: PathBuf::from("Cargo.toml"),
+        };
+        let conf = conf
+            .set_variables(&source, None, &FetchContext::default())
+            .unwrap();
+        assert_eq!(conf.default_tags, vec!["work".to_string()]);
+        let active = conf.get_active(&vec![], &FetchContext::default()).unwrap();
+        assert_eq!(active.len(), 1);
+        assert_eq!(active[0].get_path(), PathBuf::from("work.txt"));
+
+        // The resolved tag must be defined.
+        let undefined: Config = toml::from_str(
+            r#"
+            default = ["{{profile}}"]
+            var.profile = "gaming"
+            [[file]]
+            path = "work.txt"
+            tags = ["work"]
+            sources = ["/work"]
+            "#,
+        )
+        .unwrap();
+        let undefined = undefined
+            .set_variables(&source, None, &FetchContext::default())
+            .unwrap();
+        assert!(undefined
+            .get_active(&vec![], &FetchContext::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_edit_only_tags() {
+        let conf: Config = toml::from_str(
+            r#"
+            default = ["base"]
+            [[file]]
+            path = "a.txt"
+            tags = ["work"]
+            sources = ["/a"]
+            [[file.edit]]
+            type = "insert"
+            content = "x"
+            position = "append"
+            tags = ["work", "base"]
+            [[file.edit]]
+            type = "insert"
+            content = "y"
+            position = "append"
+            tags = ["wrok"]
+            [[file.edit]]
+            type = "insert"
+            content = "z"
+            position = "append"
+            tags = ["flip"]
+            "#,
+        )
+        .unwrap();
+        // flip is only used by an edit on purpose, it is not close to any selected tag.
+        assert_eq!(
+            conf.edit_only_tags(),
+            vec![(
+                PathBuf::from("a.txt"),
+                2,
+                "wrok".to_string(),
+                "work".to_string()
+            )]
+        );
+        assert!(is_near_miss("Work", "work"));
+        assert!(is_near_miss("servers", "server"));
+        assert!(!is_near_miss("flip", "work"));
+        assert!(!is_near_miss("a", "b"));
+    }
+
+    #[test]
+    fn test_platform_matches() {
+        let none: Vec<String> = vec![];
+        let here = vec![std::env::consts::OS.to_uppercase()];
+        let family = vec![std::env::consts::FAMILY.to_string()];
+        let elsewhere = vec!["no_such_os".to_string()];
+        let arch = vec![std::env::consts::ARCH.to_string()];
+        assert!(platform_matches(&none, &none));
+        assert!(platform_matches(&here, &none));
+        assert!(platform_matches(&family, &arch));
+        assert!(!platform_matches(&elsewhere, &none));
+        assert!(!platform_matches(&here, &elsewhere));
+    }
+
+    #[test]
+    fn test_check_sources() {
+        let conf: Config = toml::from_str(
+            r#"
+            [[file]]
+            path = "managed.txt"
+            hash = "abc"
+            verify_only = true
+            "#,
+        )
+        .unwrap();
+        assert!(conf.check_sources().is_ok());
+        let conf: Config = toml::from_str(
+            r#"
+            [[file]]
+            path = "lost.txt"
+            hash = "abc"
+            "#,
+        )
+        .unwrap();
+        let error = conf.check_sources().unwrap_err();
+        assert!(error.to_string().contains("lost.txt has no sources"));
+    }
+
+    #[test]
+    fn test_include_tree() {
+        let path =
+            PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testing/included_directory.toml");
+        let conf =
+            Config::from_general_path(path.to_str().unwrap(), true, None, &FetchContext::default())
+                .unwrap();
+        // Nothing is left over from an earlier resolution.
+        for _ in 0..2 {
+            let tree = conf
+                .include_tree(&vec![], &ListContext::default(), &FetchContext::default())
+                .unwrap();
+            assert_eq!(tree.len(), 1);
+            assert_eq!(tree[0].depth, 0);
+            assert_eq!(tree[0].subfolder, PathBuf::from("sub"));
+            assert_eq!(tree[0].files, 4);
+        }
+    }
+
+    #[test]
+    fn test_scaffold_config() {
+        let dir = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testing/testfolder");
+        for as_directory in [false, true] {
+            let text = scaffold_config(&dir, as_directory).unwrap();
+            let _: Config = toml::from_str(&text).unwrap();
+            let out = tempfile::tempdir().unwrap();
+            let path = out.path().join("lorevault.toml");
+            fs::write(&path, &text).unwrap();
+            let conf = Config::from_general_path(
+                path.to_str().unwrap(),
+                true,
+                None,
+                &FetchContext::default(),
+            )
+            .unwrap();
+            let mut paths = conf
+                .get_active(&vec![], &FetchContext::default())
+                .unwrap()
+                .iter()
+                .map(|f| f.get_path())
+                .collect::<Vec<_>>();
+            paths.sort();
+            assert_eq!(
+                paths,
+                vec![
+                    PathBuf::from("file1.txt"),
+                    PathBuf::from("subfolder/file2.txt")
+                ]
+            );
+        }
+        let text = scaffold_config(&dir, false).unwrap();
+        let file1 = fs::read(dir.join("file1.txt")).unwrap();
+        assert!(text.contains(&compute_hash(&file1)));
+    }
+
+    #[test]
+    fn test_exclusive_tags() {
+        let conf: Config = toml::from_str(
+            r#"
+            default = ["staging"]
+            [exclusive_tags]
+            environment = ["prod", "staging"]
+            [[file]]
+            path = "server.txt"
+            tags = ["prod"]
+            sources = ["/prod"]
+            [[file]]
+            path = "server.txt"
+            tags = ["staging"]
+            sources = ["/staging"]
+            [[file]]
+            path = "extra.txt"
+            tags = ["extra"]
+            sources = ["/extra"]
+            "#,
+        )
+        .unwrap();
+        let source = FileSource::Local {
+            path: PathBuf::from("Cargo.toml"),
+        };
+        let conf = conf
+            .set_variables(&source, None, &FetchContext::default())
+            .unwrap();
+        let tags = |t: Vec<&str>| t.iter().map(|s| s.to_string()).collect::<Vec<_>>();
+        assert!(conf
+            .get_active(&tags(vec!["extra"]), &FetchContext::default())
+            .is_ok());
+        assert!(conf
+            .get_active(&tags(vec!["prod", "!staging"]), &FetchContext::default())
+            .is_ok());
+        let error = conf
+            .get_active(&tags(vec!["prod"]), &FetchContext::default())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "The tags prod and staging can not be active together, they are in the exclusive group environment."
+        );
+
+        let typo: Config = toml::from_str(
+            r#"
+            exclusive_tags = { environment = ["prod", "stagign"] }
+            [[file]]
+            path = "server.txt"
+            tags = ["prod"]
+            sources = ["/prod"]
+            "#,
+        )
+        .unwrap();
+        assert!(typo
+            .set_variables(&source, None, &FetchContext::default())
+            .is_err());
+    }
+
+    #[test]
+    fn test_explain() {
+        let conf: Config = toml::from_str(
+            r#"
+            [[file]]
+            path = "a.txt"
+            sources = ["/untagged"]
+            [[file]]
+            path = "a.txt"
+            tags = ["special"]
+            sources = ["/tagged"]
+            [[file]]
+            path = "twice.txt"
+            sources = ["/first"]
+            [[file]]
+            path = "twice.txt"
+            sources = ["/second"]
+            [[directory]]
+            path = "dir"
+            tags = ["extra"]
+            sources = ["/missing/dir"]
+            "#,
+        )
+        .unwrap();
+        let conf = conf
+            .set_variables(
+                &FileSource::Local {
+                    path: PathBuf::from("Cargo.toml"),
+                },
+                None,
+                &FetchContext::default(),
+            )
+            .unwrap();
+        let decisions = |tags: Vec<&str>, path: &str| {
+            let tags = tags.iter().map(|t| t.to_string()).collect();
+            let (_, candidates) = conf
+                .explain(&tags, &PathBuf::from(path), &FetchContext::default())
+                .unwrap();
+            candidates
+                .iter()
+                .map(|c| (c.sources.clone(), c.decision))
+                .collect::<Vec<_>>()
+        };
+        let source = |s: &str| vec![s.to_string()];
+        assert_eq!(
+            decisions(vec![], "a.txt"),
+            vec![
+                (source("/untagged"), Decision::Active),
+                (source("/tagged"), Decision::Inactive)
+            ]
+        );
+        assert_eq!(
+            decisions(vec!["special"], "a.txt"),
+            vec![
+                (source("/untagged"), Decision::Shadowed),
+                (source("/tagged"), Decision::Active)
+            ]
+        );
+        assert_eq!(
+            decisions(vec![], "twice.txt"),
+            vec![
+                (source("/first"), Decision::Active),
+                (source("/second"), Decision::Collision)
+            ]
+        );
+        // The inactive directory is not listed, so it stands for everything below it.
+        assert_eq!(
+            decisions(vec![], "dir/sub/file.txt"),
+            vec![(vec![], Decision::Inactive)]
+        );
+        assert!(decisions(vec![], "other.txt").is_empty());
+        assert!(conf.get_active(&vec![], &FetchContext::default()).is_err());
+    }
+
+    #[test]
+    fn test_with_vars() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("child.toml"),
+            r#"
+            parameters = ["region", "size"]
+            var.size = "small"
+            [[file]]
+            path = "{{region}}-{{size}}.txt"
+            sources = [{ type = "text", content = "{{region}}" }]
+            "#,
+        )
+        .unwrap();
+        let parent = |include: &str| {
+            let path = dir.path().join("parent.toml");
+            fs::write(
+                &path,
+                format!(
+                    "var.region = \"eu\"\n[[include]]\nconfig = \"{{{{SELF_ROOT}}}}/child.toml\"\n{}",
+                    include
+                ),
+            )
+            .unwrap();
+            Config::from_general_path(path.to_str().unwrap(), true, None, &FetchContext::default())
+                .and_then(|c| c.get_active(&vec![], &FetchContext::default()))
+                .map(|files| files.iter().map(|f| f.get_path()).collect::<Vec<_>>())
+        };
+        assert_eq!(
+            parent("with_vars = { region = \"{{region}}\" }").unwrap(),
+            vec![PathBuf::from("eu-small.txt")]
+        );
+        assert_eq!(
+            parent("with_vars = { region = \"us\", size = \"large\" }").unwrap(),
+            vec![PathBuf::from("us-large.txt")]
+        );
+        let error = parent("").unwrap_err().to_string();
+        assert!(error.contains("needs the variable region"));
+        assert!(error.contains("with_vars = { region = \"{{region}}\" }"));
+        let error = parent("with_vars = { size = \"large\", SELF_ROOT = \"/\" }").unwrap_err();
+        assert!(error.to_string().contains("protected"));
+    }
+
+    #[test]
+    fn test_config_vars() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::write(
+            dir.path().join("child.toml"),
+            "var.name = \"child\"\n[[file]]\npath = \"{{name}}.txt\"\nsources = [{ type = \"text\" }]\n",
+        )
+        .unwrap();
+        let root = dir.path().join("root.toml");
+        fs::write(
+            &root,
+            r#"
+            var.name = "root"
+            var.greeting = "hello-{{name}}"
+            [[file]]
+            path = "{{greeting}}.txt"
+            sources = [{ type = "text" }]
+            [[include]]
+            config = "{{SELF_ROOT}}/child.toml"
+            path = "isolated"
+            [[include]]
+            config = "{{SELF_ROOT}}/child.toml"
+            path = "passed"
+            with_vars = { name = "{{name}}" }
+            "#,
+        )
+        .unwrap();
+        let paths = |vars: &[(&str, &str)]| {
+            let vars = vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            let fetch = FetchContext {
+                config_vars: vars,
+                ..Default::default()
+            };
+            Config::from_general_path(root.to_str().unwrap(), true, None, &fetch)
+                .and_then(|c| c.get_active(&vec![], &FetchContext::default()))
+                .map(|files| {
+                    let mut paths = files.iter().map(|f| f.get_path()).collect::<Vec<_>>();
+                    paths.sort();
+                    paths
+                })
+        };
+        assert_eq!(
+            paths(&[]).unwrap(),
+            vec![
+                PathBuf::from("hello-root.txt"),
+                PathBuf::from("isolated/child.txt"),
+                PathBuf::from("passed/root.txt"),
+            ]
+        );
+        // The override is in place before greeting refers to it, but the include only sees it through with_vars.
+        assert_eq!(
+            paths(&[("name", "cli")]).unwrap(),
+            vec![
+                PathBuf::from("hello-cli.txt"),
+                PathBuf::from("isolated/child.txt"),
+                PathBuf::from("passed/cli.txt"),
+            ]
+        );
+        let error = paths(&[("nmae", "cli")]).unwrap_err().to_string();
+        assert!(error.contains("does not define the variable nmae"));
+        assert!(paths(&[("SELF_ROOT", "/")]).is_err());
+    }
+
+    #[test]
+    fn test_lock_key() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir(dir.path().join("sub")).unwrap();
+        for name in ["root.toml", "sub/inner.toml"] {
+            fs::write(dir.path().join(name), "").unwrap();
+        }
+        let local = |path: PathBuf| FileSource::Local { path };
+        let root = local(dir.path().join("root.toml"));
+        assert_eq!(lock_key(&root, &root), "root.toml");
+        assert_eq!(
+            lock_key(&root, &local(dir.path().join("sub/inner.toml"))),
+            "sub/inner.toml"
+        );
+        let outside = local(PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("Cargo.toml"));
+        assert_eq!(lock_key(&root, &outside), outside.to_string());
+        let git = |id: &str, path: &str| FileSource::Git {
+            repo: "https://example.com/configs.git".to_string(),
+            id: id.to_string(),
+            path: PathBuf::from(path),
+        };
+        let root = git("main", "configs/root.toml");
+        assert_eq!(
+            lock_key(&root, &git("main", "configs/inc/other.toml")),
+            "inc/other.toml"
+        );
+        let other_id = git("develop", "configs/inc/other.toml");
+        assert_eq!(lock_key(&root, &other_id), other_id.to_string());
+    }
+
+    #[test]
+    fn test_canonical() {
+        let dir = tempfile::tempdir().unwrap();
+        let canonical = |name: &str, text: &str| {
+            let path = dir.path().join(name);
+            fs::write(&path, text).unwrap();
+            Config::canonical(path.to_str().unwrap(), &FetchContext::default()).unwrap()
+        };
+        let first = canonical(
+            "first.toml",
+            r#"
+            var.b = "2"
+            var.a = "{{b}}"
+            [[file]]
+            path = "{{a}}.txt"
+            tags = ["x"]
+            sources = ["/some/file"]
+            "#,
+        );
+        let second = canonical(
+            "second.toml",
+            r#"
+            [var]
+            a="{{b}}"
+            b="2"
+
+            [[file]]
+            sources=["/some/file"]
+            # The default is written out in the canonical form anyway.
+            optional=false
+            tags=["x"]
+            path="{{a}}.txt"
+            "#,
+        );
+        assert_eq!(first, second);
+        assert!(first.contains("path = \"{{a}}.txt\""));
+        assert_eq!(canonical("again.toml", &first), first);
+    }
+
+    #[test]
+    fn test_profiles() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("config.toml");
+        fs::write(
+            &path,
+            r#"
+            var.server = "localhost"
+            var.url = "https://{{server}}"
+            [profiles.prod]
+            tags = ["prod"]
+            vars = { server = "prod.example.com" }
+            [[file]]
+            path = "url.txt"
+            sources = [{ type = "text", content = "{{url}}" }]
+            [[file]]
+            path = "prod_only.txt"
+            tags = ["prod"]
+            sources = [{ type = "text" }]
+            "#,
+        )
+        .unwrap();
+        let load = |vars: &[(&str, &str)], profile: Option<&str>| {
+            let vars = vars
+                .iter()
+                .map(|(k, v)| (k.to_string(), v.to_string()))
+                .collect();
+            let fetch = FetchContext {
+                config_vars: vars,
+                profile: profile.map(|p| p.to_string()),
+                ..Default::default()
+            };
+            Config::from_general_path(path.to_str().unwrap(), true, None, &fetch)
+        };
+        let content = |conf: &Config| {
+            let files = conf.get_active(&vec![], &FetchContext::default()).unwrap();
+            let url = files
+                .iter()
+                .find(|f| f.get_path() == PathBuf::from("url.txt"))
+                .unwrap()
+                .build(&vec![], &vec![], false, &FetchContext::default())
+                .unwrap();
+            (String::from_utf8(url).unwrap(), files.len())
+        };
+        let conf = load(&[], None).unwrap();
+        assert_eq!(content(&conf), ("https://localhost".to_string(), 1));
+        let conf = load(&[], Some("prod")).unwrap();
+        assert_eq!(conf.active_tags(&vec![]).unwrap(), vec!["prod"]);
+        assert_eq!(content(&conf), ("https://prod.example.com".to_string(), 2));
+        assert!(conf
+            .active_tags(&vec!["!prod".to_string()])
+            .unwrap()
+            .is_empty());
+        // --config-var wins over the profile.
+        let conf = load(&[("server", "cli.example.com")], Some("prod")).unwrap();
+        assert_eq!(content(&conf), ("https://cli.example.com".to_string(), 2));
+        let error = load(&[], Some("staging")).unwrap_err().to_string();
+        assert!(error.contains("The profile staging is not defined"));
+    }
+}
@@ -0,0 +1,137 @@
+use crate::*;
+
+// How the fetched bytes of a file are decompressed. The hash is checked afterwards.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Compression {
+    #[default]
+    None,
+    // Chosen for each source by its extension (.gz, .zst or .xz). Other sources are used as they are.
+    Auto,
+    Gzip,
+    Zstd,
+    Xz,
+}
+
+// A small compressed source can expand to any size, so the output is limited.
+const MAX_DECOMPRESSED_SIZE: u64 = 4 << 30;
+
+impl Compression {
+    fn for_name(self, name: &str) -> Compression {
+        if self != Compression::Auto {
+            return self;
+        }
+        let name = name.split('?').next().unwrap_or_default();
+        if name.ends_with(".gz") {
+            Compression::Gzip
+        } else if name.ends_with(".zst") {
+            Compression::Zstd
+        } else if name.ends_with(".xz") {
+            Compression::Xz
+        } else {
+            Compression::None
+        }
+    }
+
+    pub fn decompress(self, source: &FileSource, data: Vec<u8>) -> Result<Vec<u8>> {
+        self.decompress_limited(&source.to_string(), data, MAX_DECOMPRESSED_SIZE)
+    }
+
+    // Files are compressed before they are encrypted, so an encrypted source is named like tool.gz.age.
+    pub fn decompress_decrypted(self, source: &FileSource, data: Vec<u8>) -> Result<Vec<u8>> {
+        let name = source.to_string();
+        let name = name.split('?').next().unwrap_or_default();
+        let name = name.strip_suffix(".age").unwrap_or(name);
+        self.decompress_limited(name, data, MAX_DECOMPRESSED_SIZE)
+    }
+
+    fn decompress_limited(self, name: &str, data: Vec<u8>, limit: u64) -> Result<Vec<u8>> {
+        let (reader, format): (Box<dyn Read + '_>, &str) = match self.for_name(name) {
+            Compression::None | Compression::Auto => return Ok(data),
+            Compression::Gzip => (
+                Box::new(flate2::read::MultiGzDecoder::new(&data[..])),
+                "gzip",
+            ),
+            Compression::Zstd => (
+                Box::new(zstd::stream::read::Decoder::new(&data[..])?),
+                "zstd",
+            ),
+            Compression::Xz => (
+                Box::new(xz2::read::XzDecoder::new_multi_decoder(&data[..])),
+                "xz",
+            ),
+        };
+        let mut decompressed = vec![];
+        reader
+            .take(limit + 1)
+            .read_to_end(&mut decompressed)
+            .context(format!("Could not decompress {}", format))?;
+        if decompressed.len() as u64 > limit {
+            return Err(format_err!(
+                "{} decompresses to more than {} bytes",
+                name,
+                limit
+            ));
+        }
+        Ok(decompressed)
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decompress() {
+        let testing = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testing");
+        let local = |name: &str| FileSource::Local {
+            path: testing.join(name),
+        };
+        let expected = b"This file was compressed.\n".to_vec();
+        for (name, compression) in [
+            ("compressed.txt.zst", Compression::Zstd),
+            ("compressed.txt.xz", Compression::Xz),
+        ] {
+            let source = local(name);
+            let data = source.fetch(&FetchContext::default()).unwrap();
+            assert_eq!(
+                compression.decompress(&source, data.clone()).unwrap(),
+                expected
+            );
+            assert_eq!(
+                Compression::Auto.decompress(&source, data.clone()).unwrap(),
+                expected
+            );
+            assert_eq!(
+                Compression::None.decompress(&source, data.clone()).unwrap(),
+                data
+            );
+        }
+        // With auto, a source without a known extension is not touched.
+        let plain = local("testfolder/file1.txt");
+        let data = plain.fetch(&FetchContext::default()).unwrap();
+        assert_eq!(
+            Compression::Auto.decompress(&plain, data.clone()).unwrap(),
+            data
+        );
+        assert!(Compression::Zstd.decompress(&plain, data).is_err());
+    }
+
+    #[test]
+    fn test_decompress_limit() {
+        let mut encoder = flate2::write::GzEncoder::new(vec![], flate2::Compression::best());
+        encoder.write_all(&[0; 10_000]).unwrap();
+        let data = encoder.finish().unwrap();
+        assert_eq!(
+            Compression::Gzip
+                .decompress_limited("zeros.gz", data.clone(), 10_000)
+                .unwrap()
+                .len(),
+            10_000
+        );
+        let error = Compression::Gzip
+            .decompress_limited("zeros.gz", data, 9_999)
+            .unwrap_err();
+        assert!(error.to_string().contains("more than 9999 bytes"));
+    }
+}
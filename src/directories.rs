@@ -1,23 +1,61 @@
+#![allow(
+    clippy::comparison_to_empty,
+    clippy::len_zero,
+    clippy::needless_borrow,
+    clippy::needless_return,
+    clippy::redundant_closure
+)]
 use crate::*;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
 pub struct Directory {
     count: Option<usize>,
+    // A different number of files is only a warning, like with --lenient-count.
+    #[serde(default)]
+    count_is_warning: bool,
     path: PathBuf,
     tags: Option<Vec<String>>,
     #[serde(rename = "sources", alias = "source")]
     sources: Vec<DirSource>,
     #[serde(default)]
     ignore_hidden: bool,
+    // Combine the files of all sources instead of using the first valid one. Later sources win.
+    #[serde(default)]
+    merge: bool,
+    #[serde(rename = "edit", default)]
+    edits: Vec<FileEdit>,
+    edit_pattern: Option<String>, // Glob for the subpaths the edits apply to.
+    // Regex on the subpath (with /) -> tag. Matching files get the tag in addition to the tags of the directory.
+    #[serde(default)]
+    tag_by_subpath: BTreeMap<String, String>,
+    // Like for files, the directory is only listed on these systems and architectures.
+    #[serde(default)]
+    os: Vec<String>,
+    #[serde(default)]
+    arch: Vec<String>,
 }
 
 impl Directory {
     pub fn get_tags(&self) -> Vec<String> {
         self.tags.clone().unwrap_or(vec![])
     }
+    pub fn get_path(&self) -> PathBuf {
+        format_subpath(&self.path)
+    }
+
+    // The tags of the directory and the ones its files can get by tag_by_subpath.
+    pub fn defined_tags(&self) -> Vec<String> {
+        let mut tags = self.get_tags();
+        tags.extend(self.tag_by_subpath.values().cloned());
+        tags
+    }
+
+    pub fn platform_matches(&self) -> bool {
+        platform_matches(&self.os, &self.arch)
+    }
 
-    fn is_active(&self, tags: &Vec<String>) -> bool {
+    pub fn is_active(&self, tags: &Vec<String>) -> bool {
         if self.get_tags().len() == 0 {
             return true;
         }
@@ -28,44 +66,139 @@ impl Directory {
         }
         return false;
     }
-    pub fn get_active(&self, tags: &Vec<String>) -> Result<Vec<File>> {
+    pub fn get_active(
+        &self,
+        tags: &Vec<String>,
+        lenient_count: bool,
+        fetch: &FetchContext,
+    ) -> Result<Vec<File>> {
         if self.is_active(tags) {
-            self.get_all_files()
+            self.get_all_files(lenient_count, fetch)
         } else {
             Ok(vec![])
         }
     }
 
-    pub fn get_all_files(&self) -> Result<Vec<File>> {
-        let anyhow::Result::Ok((source, list)) = list_first_valid(&self.sources) else {
-            return Err(format_err!(
-                "No valid source for directory: {}",
-                self.path.display()
-            ));
+    pub fn read_text_files(&mut self, vars: &HashMap<String, String>) -> Result<()> {
+        self.edits = self
+            .edits
+            .iter()
+            .map(|e| e.with_text_file(vars))
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+    pub fn set_edit_variables(&mut self, vars: &HashMap<String, String>) {
+        self.edits = self.edits.iter().map(|e| e.with_variables(vars)).collect();
+    }
+    pub fn get_edits(&self) -> &Vec<FileEdit> {
+        &self.edits
+    }
+
+    pub fn remote_repos(&self) -> Vec<String> {
+        self.sources
+            .iter()
+            .filter_map(|s| s.remote_repo())
+            .collect()
+    }
+
+    pub fn local_roots(&self) -> Vec<PathBuf> {
+        self.sources.iter().filter_map(|s| s.local_path()).collect()
+    }
+
+    // With lenient_count, a wrong count is only a warning for every directory.
+    pub fn get_all_files(&self, lenient_count: bool, fetch: &FetchContext) -> Result<Vec<File>> {
+        let list = if self.merge {
+            let merged = list_merged(&self.sources, fetch)?;
+            fetch.trace(|| {
+                format!(
+                    "directory {}: {} file(s) merged from {} source(s)",
+                    self.path.display(),
+                    merged.len(),
+                    self.sources.len()
+                )
+            });
+            merged
+        } else {
+            match list_first_valid(&self.sources, fetch) {
+                Ok((source, list)) => {
+                    fetch.trace(|| {
+                        format!(
+                            "directory {}: {} file(s) listed from {}",
+                            self.path.display(),
+                            list.len(),
+                            source
+                        )
+                    });
+                    list.into_iter().map(|p| (p, source)).collect()
+                }
+                Err(e) if e.is::<FloatingRef>() => return Err(e),
+                Err(_) => {
+                    return Err(format_err!(
+                        "No valid source for directory: {}",
+                        self.path.display()
+                    ))
+                }
+            }
         };
 
         if let Some(c) = self.count {
             if c != list.len() {
-                return Err(format_err!(
+                let message = format!(
                     "Expected {} files for directory {}, found {}",
                     c,
                     &self.path.display(),
                     list.len()
-                ));
+                );
+                if !self.count_is_warning && !lenient_count {
+                    return Err(format_err!(message));
+                }
+                yellow(message);
             }
         }
+        let edit_pattern = match &self.edit_pattern {
+            Some(p) => Some(glob::Pattern::new(p).context(format!("Invalid edit pattern {}", p))?),
+            None => None,
+        };
+        let mut tag_rules = vec![];
+        for (pattern, tag) in &self.tag_by_subpath {
+            let regex = Regex::new(pattern).context(format!(
+                "Invalid pattern {} in tag_by_subpath of directory {}",
+                pattern,
+                self.path.display()
+            ))?;
+            tag_rules.push((regex, tag));
+        }
         let mut files: Vec<File> = vec![];
-        for subpath in list {
+        for (subpath, source) in list {
             if self.ignore_hidden && subpath.display().to_string().starts_with(".") {
                 continue;
             }
+            let mut tags = self.get_tags();
+            let slashed = subpath.to_string_lossy().replace('\\', "/");
+            for (regex, tag) in &tag_rules {
+                if regex.is_match(&slashed) && !tags.contains(tag) {
+                    tags.push(tag.to_string());
+                }
+            }
+            let edits = match &edit_pattern {
+                Some(p) if !p.matches_path(&subpath) => vec![],
+                _ => self.edits.clone(),
+            };
             files.push(File {
                 path: self.path.clone().join(&subpath),
-                tags: self.tags.clone(),
+                tags: if tags.is_empty() { None } else { Some(tags) },
                 hash: None,
+                hash_source: None,
                 sources: vec![source.get_single_file_source(&subpath)?],
-                edits: vec![],
+                edits,
                 decrypt: DecryptionMethod::None,
+                decompress: Compression::None,
+                signature: None,
+                public_key: None,
+                optional: false,
+                verify_only: false,
+                os: vec![],
+                arch: vec![],
             })
         }
         if files.len() == 0 {
@@ -78,13 +211,37 @@ impl Directory {
     }
 }
 
-fn list_first_valid(ds: &Vec<DirSource>) -> Result<(&DirSource, Vec<PathBuf>)> {
+// Every source must be valid. A path listed by several sources is taken from the last one.
+fn list_merged<'a>(
+    ds: &'a Vec<DirSource>,
+    fetch: &FetchContext,
+) -> Result<Vec<(PathBuf, &'a DirSource)>> {
+    let mut merged: Vec<(PathBuf, &DirSource)> = vec![];
+    let mut positions: HashMap<PathBuf, usize> = HashMap::new();
     for s in ds {
-        if let anyhow::Result::Ok(l) = s.list() {
-            return Ok((s, l));
+        let list = timed(|| format!("list {}", s), || s.list(fetch))
+            .map_err(|e| format_err!("Invalid directory source {}: {}", s, e))?;
+        for subpath in list {
+            match positions.get(&subpath) {
+                Some(i) => merged[*i].1 = s,
+                None => {
+                    positions.insert(subpath.clone(), merged.len());
+                    merged.push((subpath, s));
+                }
+            }
         }
-        match s.list() {
+    }
+    Ok(merged)
+}
+
+fn list_first_valid<'a>(
+    ds: &'a Vec<DirSource>,
+    fetch: &FetchContext,
+) -> Result<(&'a DirSource, Vec<PathBuf>)> {
+    for s in ds {
+        match timed(|| format!("list {}", s), || s.list(fetch)) {
             Ok(l) => return Ok((s, l)),
+            Err(e) if e.is::<FloatingRef>() => return Err(e),
             Err(msg) => yellow(format!("Invalid directory source {} \nError: {}", &s, msg)),
         }
     }
@@ -102,30 +259,89 @@ pub enum DirSource {
         repo: String,
         id: String,
         path: PathBuf,
+        changed_since: Option<String>,
+    },
+    #[serde(rename = "sftp")]
+    Sftp {
+        user: String,
+        service: String,
+        path: PathBuf,
+        port: Option<usize>,
+    },
+    // A server can not be asked for its files, so an index lists them (one path per line).
+    #[serde(rename = "http")]
+    Http {
+        url: String,
+        #[serde(default = "default_index")]
+        index: String,
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user_agent: Option<String>,
     },
     #[serde(untagged)]
     Auto(String),
 }
 
+fn default_index() -> String {
+    "index.txt".to_string()
+}
+
 impl fmt::Display for DirSource {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::Local { path } => write!(f, "{}", path.display()),
             Self::Auto(a) => write!(f, "{}", a),
-            Self::Git { repo, id, path } => write!(f, "{}#{}:{}", repo, id, path.display()),
+            Self::Git {
+                repo,
+                id,
+                path,
+                changed_since: None,
+            } => write!(f, "{}#{}:{}", repo, id, path.display()),
+            Self::Git {
+                repo,
+                id,
+                path,
+                changed_since: Some(since),
+            } => write!(
+                f,
+                "{}#{}:{} (changed since {})",
+                repo,
+                id,
+                path.display(),
+                since
+            ),
+            Self::Sftp {
+                user,
+                service,
+                path,
+                ..
+            } => write!(f, "{}@{}:{}", user, service, path.display()),
+            Self::Http { url, index, .. } => write!(f, "{} (index {})", url, index),
         }
     }
 }
 impl DirSource {
-    pub fn list(&self) -> Result<Vec<PathBuf>> {
+    pub fn list(&self, fetch: &FetchContext) -> Result<Vec<PathBuf>> {
+        source_backend().list_dir(self, fetch)
+    }
+
+    // Lists the source without going through the backend.
+    pub fn list_directly(&self, fetch: &FetchContext) -> Result<Vec<PathBuf>> {
         let list = match self {
-            DirSource::Git { repo, id, path } => {
+            DirSource::Git {
+                repo,
+                id,
+                path,
+                changed_since,
+            } => {
                 if !is_url_or_ssh(&repo) & PathBuf::from(repo).is_relative() {
                     return Err(format_err!("Path to repo must be absolute {}", repo));
                 }
-                let repo = get_git_repo(&repo)?;
+                let repo = get_git_repo(&repo, fetch)?;
 
-                list_files_in_repo(&repo, id, path)?
+                match changed_since {
+                    Some(since) => list_changed_in_repo(&repo, since, id, path, fetch)?,
+                    None => list_files_in_repo(&repo, id, path, fetch)?,
+                }
             }
             DirSource::Local { path } => {
                 if path.is_relative() {
@@ -136,17 +352,51 @@ impl DirSource {
                 }
                 list_files_in_folder(path)?
             }
+            DirSource::Sftp {
+                user,
+                service,
+                path,
+                port,
+            } => list_files_over_sftp(user, service, path, *port)?,
+            DirSource::Http {
+                url,
+                index,
+                user_agent,
+            } => {
+                let index_source = FileSource::Download {
+                    url: join_url(url, index),
+                    redirects: Redirects::default(),
+                    user_agent: user_agent.clone(),
+                };
+                let content = String::from_utf8(index_source.fetch(fetch)?)
+                    .context(format!("The index {} is not utf8", index))?;
+                parse_index(&content)?
+            }
             DirSource::Auto(auto) => {
                 let parsed = parse_auto_dir_source(auto)?;
-                parsed.list()?
+                parsed.list(fetch)?
             }
         };
         Ok(list.iter().map(|p| format_subpath(p)).collect())
     }
+    pub fn remote_repo(&self) -> Option<String> {
+        match self {
+            DirSource::Git { repo, .. } if is_url_or_ssh(repo) => Some(repo.clone()),
+            DirSource::Auto(auto) => parse_auto_dir_source(auto).ok()?.remote_repo(),
+            _ => None,
+        }
+    }
+    pub fn local_path(&self) -> Option<PathBuf> {
+        match self {
+            DirSource::Local { path } => Some(path.clone()),
+            DirSource::Auto(auto) => parse_auto_dir_source(auto).ok()?.local_path(),
+            _ => None,
+        }
+    }
     fn get_single_file_source(&self, subpath: &PathBuf) -> Result<FileSource> {
         let subpath = format_subpath(subpath);
         match self {
-            DirSource::Git { repo, id, path } => Ok(FileSource::Git {
+            DirSource::Git { repo, id, path, .. } => Ok(FileSource::Git {
                 repo: repo.to_string(),
                 id: id.to_string(),
                 path: path.join(subpath),
@@ -154,6 +404,24 @@ impl DirSource {
             DirSource::Local { path } => Ok(FileSource::Local {
                 path: path.join(subpath),
             }),
+            DirSource::Sftp {
+                user,
+                service,
+                path,
+                port,
+            } => Ok(FileSource::Sftp {
+                user: user.clone(),
+                service: service.clone(),
+                path: path.join(subpath),
+                port: *port,
+            }),
+            DirSource::Http {
+                url, user_agent, ..
+            } => Ok(FileSource::Download {
+                url: join_url(url, &subpath.to_string_lossy()),
+                redirects: Redirects::default(),
+                user_agent: user_agent.clone(),
+            }),
             DirSource::Auto(auto) => {
                 let parsed = parse_auto_dir_source(auto)?;
                 parsed.get_single_file_source(&subpath)
@@ -162,13 +430,18 @@ impl DirSource {
     }
 }
 
-fn list_files_in_repo(repo: &Repository, id: &str, folder_path: &PathBuf) -> Result<Vec<PathBuf>> {
+fn list_files_in_repo(
+    repo: &Repository,
+    id: &str,
+    folder_path: &PathBuf,
+    fetch: &FetchContext,
+) -> Result<Vec<PathBuf>> {
     let folder_path = match folder_path.strip_prefix("/") {
         Ok(s) => s,
         _ => folder_path,
     }
     .to_owned();
-    let mut full_paths = full_paths_in_repo(repo, id, &folder_path)?;
+    let mut full_paths = full_paths_in_repo(repo, id, &folder_path, fetch)?;
     let to_remove = format_subpath(&folder_path);
     for p in &mut full_paths {
         *p = p.strip_prefix(&to_remove)?.to_path_buf();
@@ -176,8 +449,53 @@ fn list_files_in_repo(repo: &Repository, id: &str, folder_path: &PathBuf) -> Res
     Ok(full_paths)
 }
 
-fn full_paths_in_repo(repo: &Repository, id: &str, folder_path: &PathBuf) -> Result<Vec<PathBuf>> {
-    let commit_string = get_commit_from_string(repo, id)?;
+// Lists the files that were added or modified between the two commits.
+// Deleted files can not be part of the directory, so they are only reported.
+fn list_changed_in_repo(
+    repo: &Repository,
+    since: &str,
+    id: &str,
+    folder_path: &PathBuf,
+    fetch: &FetchContext,
+) -> Result<Vec<PathBuf>> {
+    let old_commit =
+        repo.find_commit(Oid::from_str(&get_commit_from_string(repo, since, fetch)?)?)?;
+    let new_commit = repo.find_commit(Oid::from_str(&get_commit_from_string(repo, id, fetch)?)?)?;
+    let diff =
+        repo.diff_tree_to_tree(Some(&old_commit.tree()?), Some(&new_commit.tree()?), None)?;
+    let prefix = format_subpath(folder_path);
+    let mut paths = vec![];
+    for delta in diff.deltas() {
+        match delta.status() {
+            git2::Delta::Added
+            | git2::Delta::Modified
+            | git2::Delta::Renamed
+            | git2::Delta::Copied
+            | git2::Delta::Typechange => {
+                let path = delta.new_file().path().context("Diff entry has no path")?;
+                if let Ok(sub) = path.strip_prefix(&prefix) {
+                    paths.push(sub.to_path_buf());
+                }
+            }
+            git2::Delta::Deleted => {
+                let path = delta.old_file().path().context("Diff entry has no path")?;
+                if path.starts_with(&prefix) {
+                    yellow(format!("Deleted since {}: {}", since, path.display()));
+                }
+            }
+            _ => {}
+        }
+    }
+    Ok(paths)
+}
+
+fn full_paths_in_repo(
+    repo: &Repository,
+    id: &str,
+    folder_path: &PathBuf,
+    fetch: &FetchContext,
+) -> Result<Vec<PathBuf>> {
+    let commit_string = get_commit_from_string(repo, id, fetch)?;
     let commit = repo.find_commit(Oid::from_str(&commit_string)?)?;
     let mut paths = Vec::new();
     let tree = commit.tree()?;
@@ -206,6 +524,7 @@ fn full_paths_in_repo(repo: &Repository, id: &str, folder_path: &PathBuf) -> Res
                 repo,
                 id,
                 &format_subpath(&PathBuf::from(subfolder_path)),
+                fetch,
             )?);
         } else if entry.kind() == Some(git2::ObjectType::Blob) {
             let full_path = format!(
@@ -220,7 +539,7 @@ fn full_paths_in_repo(repo: &Repository, id: &str, folder_path: &PathBuf) -> Res
     Ok(paths)
 }
 
-fn list_files_in_folder(folder_path: &PathBuf) -> Result<Vec<PathBuf>> {
+pub fn list_files_in_folder(folder_path: &PathBuf) -> Result<Vec<PathBuf>> {
     let full_paths = get_full_paths_in_folder(folder_path)?;
     let mut trimmed = vec![];
     for p in &full_paths {
@@ -255,13 +574,54 @@ fn get_full_paths_in_folder(folder_path: &PathBuf) -> Result<Vec<PathBuf>> {
     Ok(files)
 }
 
+fn join_url(base: &str, subpath: &str) -> String {
+    format!(
+        "{}/{}",
+        base.trim_end_matches('/'),
+        subpath.trim_start_matches('/')
+    )
+}
+
+// Empty lines and lines starting with # are ignored. The paths must stay inside of the directory.
+fn parse_index(content: &str) -> Result<Vec<PathBuf>> {
+    let mut paths = vec![];
+    for line in content.lines().map(|l| l.trim()) {
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        let path = PathBuf::from(line);
+        if path.is_absolute()
+            || path
+                .components()
+                .any(|c| c == std::path::Component::ParentDir)
+        {
+            return Err(format_err!("Invalid path {} in index", line));
+        }
+        paths.push(path);
+    }
+    if paths.is_empty() {
+        return Err(format_err!("The index lists no files"));
+    }
+    Ok(paths)
+}
+
 fn parse_auto_dir_source(auto: &str) -> Result<DirSource> {
+    if !is_repo(auto) && !is_url(auto) && auto.contains("@") && auto.contains(":") {
+        let (user, service, path) = parse_sftp(auto)?;
+        return Ok(DirSource::Sftp {
+            user,
+            service,
+            path: PathBuf::from(path),
+            port: None,
+        });
+    }
     if is_repo(auto) {
         match extract_components(auto) {
             Some((repo, id, path)) => Ok(DirSource::Git {
                 repo: repo.into(),
                 id: id.into(),
                 path: path.into(),
+                changed_since: None,
             }),
             None => Err(format_err!(format!("Could not parse repo string {}", auto))),
         }
@@ -275,12 +635,14 @@ impl VariableCompletion for Directory {
         Ok(vecset(vec![
             self.sources.required_variables()?,
             self.path.required_variables()?,
+            self.edits.required_variables()?,
         ]))
     }
     fn set_single_variable(&mut self, key: &str, value: &str) -> Result<Self> {
         *self = Directory {
             path: self.path.set_single_variable(key, value)?,
             sources: self.sources.set_single_variable(key, value)?,
+            edits: self.edits.set_single_variable(key, value)?,
             ..self.clone()
         };
         return Ok(self.to_owned());
@@ -291,25 +653,77 @@ impl VariableCompletion for DirSource {
     fn required_variables(&self) -> Result<Vec<String>> {
         match self {
             DirSource::Auto(auto) => auto.required_variables(),
-            DirSource::Git { repo, id, path } => Ok(vecset(vec![
+            DirSource::Git {
+                repo,
+                id,
+                path,
+                changed_since,
+            } => Ok(vecset(vec![
                 path.required_variables()?,
                 repo.required_variables()?,
                 id.required_variables()?,
+                changed_since
+                    .clone()
+                    .unwrap_or_default()
+                    .required_variables()?,
             ])),
             DirSource::Local { path } => path.required_variables(),
+            DirSource::Sftp {
+                user,
+                service,
+                path,
+                ..
+            } => Ok(vecset(vec![
+                user.required_variables()?,
+                service.required_variables()?,
+                path.required_variables()?,
+            ])),
+            DirSource::Http { url, index, .. } => Ok(vecset(vec![
+                url.required_variables()?,
+                index.required_variables()?,
+            ])),
         }
     }
     fn set_single_variable(&mut self, key: &str, value: &str) -> Result<Self> {
         *self = match self {
             DirSource::Auto(a) => DirSource::Auto(a.set_single_variable(key, value)?),
-            DirSource::Git { repo, id, path } => DirSource::Git {
+            DirSource::Git {
+                repo,
+                id,
+                path,
+                changed_since,
+            } => DirSource::Git {
                 repo: repo.set_single_variable(key, value)?,
                 id: id.set_single_variable(key, value)?,
                 path: path.set_single_variable(key, value)?,
+                changed_since: match changed_since {
+                    Some(since) => Some(since.set_single_variable(key, value)?),
+                    None => None,
+                },
             },
             DirSource::Local { path } => DirSource::Local {
                 path: path.set_single_variable(key, value)?,
             },
+            DirSource::Sftp {
+                user,
+                service,
+                path,
+                port,
+            } => DirSource::Sftp {
+                user: user.set_single_variable(key, value)?,
+                service: service.set_single_variable(key, value)?,
+                path: path.set_single_variable(key, value)?,
+                port: *port,
+            },
+            DirSource::Http {
+                url,
+                index,
+                user_agent,
+            } => DirSource::Http {
+                url: url.set_single_variable(key, value)?,
+                index: index.set_single_variable(key, value)?,
+                user_agent: user_agent.clone(),
+            },
         };
         Ok(self.clone())
     }
@@ -326,4 +740,143 @@ mod test {
         assert!(list.contains(&PathBuf::from("file1.txt")));
         assert!(list.contains(&PathBuf::from("subfolder/file2.txt")));
     }
+    #[test]
+    fn test_parse_index() {
+        let list = parse_index("# files\nfile1.txt\n\n  subfolder/file2.txt \n").unwrap();
+        assert_eq!(
+            list,
+            vec![
+                PathBuf::from("file1.txt"),
+                PathBuf::from("subfolder/file2.txt")
+            ]
+        );
+        assert!(parse_index("../secret").is_err());
+        assert!(parse_index("/etc/passwd").is_err());
+        assert!(parse_index("# nothing").is_err());
+    }
+
+    #[test]
+    fn test_merge() {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testing");
+        let sources = vec![
+            DirSource::Local {
+                path: root.join("testfolder"),
+            },
+            DirSource::Local {
+                path: root.join("overridefolder"),
+            },
+        ];
+        let merged = list_merged(&sources, &FetchContext::default()).unwrap();
+        assert_eq!(merged.len(), 3);
+        let source_of = |name: &str| {
+            merged
+                .iter()
+                .find(|(p, _)| p == &PathBuf::from(name))
+                .map(|(_, s)| *s)
+                .unwrap()
+        };
+        assert_eq!(source_of("file1.txt"), &sources[1]);
+        assert_eq!(source_of("extra.txt"), &sources[1]);
+        assert_eq!(source_of("subfolder/file2.txt"), &sources[0]);
+    }
+
+    #[test]
+    fn test_count_is_warning() {
+        let root = PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("testing/testfolder");
+        let directory = |count_is_warning: bool| Directory {
+            count: Some(3),
+            count_is_warning,
+            path: PathBuf::from("dir"),
+            tags: None,
+            sources: vec![DirSource::Local { path: root.clone() }],
+            ignore_hidden: false,
+            merge: false,
+            edits: vec![],
+            edit_pattern: None,
+            tag_by_subpath: BTreeMap::new(),
+            os: vec![],
+            arch: vec![],
+        };
+        let error = directory(false)
+            .get_all_files(false, &FetchContext::default())
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "Expected 3 files for directory dir, found 2"
+        );
+        assert_eq!(
+            directory(true)
+                .get_all_files(false, &FetchContext::default())
+                .unwrap()
+                .len(),
+            2
+        );
+        assert_eq!(
+            directory(false)
+                .get_all_files(true, &FetchContext::default())
+                .unwrap()
+                .len(),
+            2
+        );
+    }
+
+    #[test]
+    fn test_tag_by_subpath_git() {
+        let dir = TempDir::new().unwrap();
+        let repo = git2::Repository::init(dir.path()).unwrap();
+        for (path, content) in [
+            ("configs/work/git.conf", "work"),
+            ("configs/home/git.conf", "home"),
+            ("configs/shared.conf", "shared"),
+        ] {
+            let full = dir.path().join(path);
+            fs::create_dir_all(full.parent().unwrap()).unwrap();
+            fs::write(full, content).unwrap();
+        }
+        let mut index = repo.index().unwrap();
+        index
+            .add_all(["*"].iter(), git2::IndexAddOption::DEFAULT, None)
+            .unwrap();
+        let tree = repo.find_tree(index.write_tree().unwrap()).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        let commit = repo
+            .commit(Some("HEAD"), &signature, &signature, "fixture", &tree, &[])
+            .unwrap();
+        let directory: Directory = toml::from_str(&format!(
+            r#"
+            path = "dots"
+            tag_by_subpath = {{ "^work/" = "work", "^home/" = "home" }}
+            [[sources]]
+            type = "git"
+            repo = "{}"
+            id = "{}"
+            path = "configs"
+            "#,
+            dir.path().display(),
+            commit
+        ))
+        .unwrap();
+        let mut tags = directory
+            .get_active(&vec![], false, &FetchContext::default())
+            .unwrap()
+            .into_iter()
+            .map(|f| (f.get_path(), f.tags))
+            .collect::<Vec<_>>();
+        tags.sort();
+        assert_eq!(
+            tags,
+            vec![
+                (
+                    PathBuf::from("dots/home/git.conf"),
+                    Some(vec!["home".to_string()])
+                ),
+                (PathBuf::from("dots/shared.conf"), None),
+                (
+                    PathBuf::from("dots/work/git.conf"),
+                    Some(vec!["work".to_string()])
+                ),
+            ]
+        );
+        assert_eq!(directory.defined_tags(), vec!["home", "work"]);
+    }
 }
@@ -1,7 +1,15 @@
+#![allow(
+    clippy::empty_line_after_outer_attr,
+    clippy::len_zero,
+    clippy::needless_return
+)]
 use crate::*;
-const fn default_true() -> bool {
+pub const fn default_true() -> bool {
     true
 }
+fn default_encoding() -> String {
+    "utf-8".to_string()
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
 #[serde(deny_unknown_fields)]
@@ -10,7 +18,13 @@ pub enum FileEdit {
     #[serde(rename = "replace")]
     Replace {
         from: String,
-        to: String,
+        #[serde(default)]
+        to: Option<String>,
+        // The replacement is fetched when the edit is applied.
+        to_source: Option<FileSource>,
+        // The variables of the config, for the fetched replacement.
+        #[serde(skip)]
+        variables: HashMap<String, String>,
         #[serde(default = "default_true")]
         required: bool,
         #[serde(default)]
@@ -19,6 +33,12 @@ pub enum FileEdit {
         ignore_variables: bool,
         #[serde(default)]
         ignore_case: bool,
+        #[serde(default)]
+        whole_word: bool,
+        // The edit is skipped unless the text (at this point of the edits) contains this.
+        if_contains: Option<String>,
+        // The edit is skipped if the text contains this. This makes appending idempotent.
+        if_missing: Option<String>,
     },
     #[serde(rename = "insert")]
     Insert {
@@ -28,6 +48,8 @@ pub enum FileEdit {
         tags: Vec<String>,
         #[serde(default)]
         ignore_variables: bool,
+        if_contains: Option<String>,
+        if_missing: Option<String>,
     },
     #[serde(rename = "delete")]
     Delete {
@@ -35,6 +57,32 @@ pub enum FileEdit {
         end: usize,
         #[serde(default)]
         tags: Vec<String>,
+        if_contains: Option<String>,
+        if_missing: Option<String>,
+    },
+    // Deletes the block from the first line matching start_pattern to the next line matching end_pattern.
+    #[serde(rename = "delete_between")]
+    DeleteBetween {
+        start_pattern: String,
+        end_pattern: String,
+        // Whether the marker lines are deleted too.
+        #[serde(default)]
+        inclusive: bool,
+        #[serde(default = "default_true")]
+        required: bool,
+        #[serde(default)]
+        tags: Vec<String>,
+        if_contains: Option<String>,
+        if_missing: Option<String>,
+    },
+    // Must be the first edit. The other edits work on the decoded text.
+    #[serde(rename = "transcode")]
+    Transcode {
+        from_encoding: String,
+        #[serde(default = "default_encoding")]
+        to_encoding: String,
+        #[serde(default)]
+        tags: Vec<String>,
     },
 }
 
@@ -46,23 +94,57 @@ pub enum EditPosition {
     Append,
     #[serde(rename = "prepend", alias = "start")]
     Prepend,
+    // Written as position = { byte = 12 }.
+    #[serde(rename = "byte")]
+    Byte(usize),
     #[serde(rename = "at_line", untagged)]
     Line(usize),
 }
 
 impl FileEdit {
-    pub fn apply(&self, input: impl AsRef<str>) -> Result<String> {
+    pub fn apply(&self, input: impl AsRef<str>, fetch: &FetchContext) -> Result<String> {
         let str = input.as_ref();
         match &self {
             Self::Replace {
                 from,
                 to,
+                to_source,
+                variables,
                 required,
                 ignore_case,
+                whole_word,
+                ignore_variables,
                 ..
             } => {
-                if *ignore_case {
-                    let pattern_string = format!("(?i){}", from);
+                let to = match (to, to_source) {
+                    (Some(to), None) => to.clone(),
+                    (None, Some(source)) => {
+                        let fetched = String::from_utf8(source.fetch(fetch).map_err(|e| {
+                            format_err!("Could not fetch replacement {}: {}", source, e)
+                        })?)
+                        .context(format!("The replacement {} is not utf8", source))?;
+                        if *ignore_variables {
+                            fetched
+                        } else {
+                            fetched.set_variables(variables)?
+                        }
+                    }
+                    _ => {
+                        return Err(format_err!(
+                            "Replacement {} needs either to or to_source",
+                            from
+                        ))
+                    }
+                };
+                if *ignore_case || *whole_word {
+                    // The search string is always taken literally, the regex only handles the options.
+                    let mut pattern_string = regex::escape(from);
+                    if *whole_word {
+                        pattern_string = format!(r"\b{}\b", pattern_string);
+                    }
+                    if *ignore_case {
+                        pattern_string = format!("(?i){}", pattern_string);
+                    }
                     let pattern = Regex::new(&pattern_string)
                         .context("Could not build regex for replacement")?;
                     if *required && pattern.find(input.as_ref()).is_none() {
@@ -71,7 +153,8 @@ impl FileEdit {
                             from
                         ))
                     } else {
-                        let replaced = pattern.replace_all(input.as_ref(), to);
+                        let replaced =
+                            pattern.replace_all(input.as_ref(), regex::NoExpand(to.as_str()));
                         Ok(replaced.to_string())
                     }
                 } else {
@@ -81,7 +164,7 @@ impl FileEdit {
                             from
                         ))
                     } else {
-                        Ok(str.replace(from, to))
+                        Ok(str.replace(from, &to))
                     }
                 }
             }
@@ -101,6 +184,22 @@ impl FileEdit {
 
                     Ok(lines.join("\n"))
                 }
+                EditPosition::Byte(offset) => {
+                    if *offset > str.len() {
+                        return Err(format_err!(
+                            "Not enough bytes to insert at byte {} (the file has {})",
+                            offset,
+                            str.len()
+                        ));
+                    }
+                    if !str.is_char_boundary(*offset) {
+                        return Err(format_err!(
+                            "Can not insert at byte {}, it is inside of a character",
+                            offset
+                        ));
+                    }
+                    Ok(format!("{}{}{}", &str[..*offset], content, &str[*offset..]))
+                }
             },
             Self::Delete { start, end, .. } => {
                 let lines: Vec<&str> = str.lines().collect();
@@ -124,13 +223,131 @@ impl FileEdit {
                 }
                 return Ok(new.join("\n"));
             }
+            Self::DeleteBetween {
+                start_pattern,
+                end_pattern,
+                inclusive,
+                required,
+                ..
+            } => {
+                let start = Regex::new(start_pattern)
+                    .context(format!("Invalid start pattern {}", start_pattern))?;
+                let end = Regex::new(end_pattern)
+                    .context(format!("Invalid end pattern {}", end_pattern))?;
+                // The lines keep their endings, so the rest of the text is left exactly as it was.
+                let lines: Vec<&str> = str.split_inclusive('\n').collect();
+                let first = lines
+                    .iter()
+                    .position(|l| start.is_match(without_line_ending(l)));
+                let last = first.and_then(|first| {
+                    lines[first + 1..]
+                        .iter()
+                        .position(|l| end.is_match(without_line_ending(l)))
+                        .map(|i| first + 1 + i)
+                });
+                let (Some(first), Some(last)) = (first, last) else {
+                    if !*required {
+                        return Ok(str.to_string());
+                    }
+                    let missing = if first.is_none() {
+                        start_pattern
+                    } else {
+                        end_pattern
+                    };
+                    return Err(format_err!(
+                        "Deletion was required but no line matches {}",
+                        missing
+                    ));
+                };
+                let (from, to) = if *inclusive {
+                    (first, last + 1)
+                } else {
+                    (first + 1, last)
+                };
+                Ok(format!(
+                    "{}{}",
+                    lines[..from].concat(),
+                    lines[to..].concat()
+                ))
+            }
+            // The conversion itself happens before and after all edits.
+            Self::Transcode { .. } => Ok(str.to_string()),
         }
     }
+    fn conditions(&self) -> (Option<&String>, Option<&String>) {
+        match self {
+            Self::Replace {
+                if_contains,
+                if_missing,
+                ..
+            }
+            | Self::Insert {
+                if_contains,
+                if_missing,
+                ..
+            }
+            | Self::Delete {
+                if_contains,
+                if_missing,
+                ..
+            }
+            | Self::DeleteBetween {
+                if_contains,
+                if_missing,
+                ..
+            } => (if_contains.as_ref(), if_missing.as_ref()),
+            Self::Transcode { .. } => (None, None),
+        }
+    }
+    fn condition_variables(&self) -> Result<Vec<String>> {
+        let (if_contains, if_missing) = self.conditions();
+        let mut variables = vec![];
+        for text in [if_contains, if_missing].into_iter().flatten() {
+            variables.append(&mut text.required_variables()?);
+        }
+        Ok(variables)
+    }
+    // Checked against the text as it is at this point of the edits.
+    pub fn condition_met(&self, text: &str) -> bool {
+        let (if_contains, if_missing) = self.conditions();
+        if_contains.is_none_or(|c| text.contains(c.as_str()))
+            && if_missing.is_none_or(|m| !text.contains(m.as_str()))
+    }
+    pub fn is_transcode(&self) -> bool {
+        matches!(self, Self::Transcode { .. })
+    }
     pub fn get_tags(&self) -> &Vec<String> {
         match self {
             Self::Replace { tags, .. } => tags,
             Self::Insert { tags, .. } => tags,
             Self::Delete { tags, .. } => tags,
+            Self::DeleteBetween { tags, .. } => tags,
+            Self::Transcode { tags, .. } => tags,
+        }
+    }
+    // A text source with from_file as the replacement is read like the sources of the file.
+    pub fn with_text_file(&self, vars: &HashMap<String, String>) -> Result<FileEdit> {
+        let mut new = self.clone();
+        if let Self::Replace { to_source, .. } = &mut new {
+            *to_source = with_optional_text_file(to_source, vars)?;
+        }
+        Ok(new)
+    }
+    // Fetched replacements are completed with the variables of the config when the edit is applied.
+    pub fn with_variables(&self, vars: &HashMap<String, String>) -> FileEdit {
+        match self {
+            Self::Replace {
+                to_source: Some(_),
+                ignore_variables: false,
+                ..
+            } => {
+                let mut new = self.clone();
+                if let Self::Replace { variables, .. } = &mut new {
+                    *variables = vars.clone();
+                }
+                new
+            }
+            _ => self.clone(),
         }
     }
     fn without_tags(&self) -> FileEdit {
@@ -138,33 +355,81 @@ impl FileEdit {
             Self::Replace {
                 from,
                 to,
+                to_source,
+                variables,
                 required,
                 ignore_variables,
                 ignore_case,
+                whole_word,
+                if_contains,
+                if_missing,
                 ..
             } => Self::Replace {
                 from: from.clone(),
                 to: to.clone(),
+                to_source: to_source.clone(),
+                variables: variables.clone(),
                 tags: vec![],
                 required: *required,
                 ignore_variables: *ignore_variables,
                 ignore_case: *ignore_case,
+                whole_word: *whole_word,
+                if_contains: if_contains.clone(),
+                if_missing: if_missing.clone(),
             },
             Self::Insert {
                 content,
                 position,
                 ignore_variables,
+                if_contains,
+                if_missing,
                 ..
             } => Self::Insert {
                 content: content.clone(),
                 position: position.clone(),
                 tags: vec![],
                 ignore_variables: *ignore_variables,
+                if_contains: if_contains.clone(),
+                if_missing: if_missing.clone(),
             },
-            Self::Delete { start, end, .. } => Self::Delete {
+            Self::Delete {
+                start,
+                end,
+                if_contains,
+                if_missing,
+                ..
+            } => Self::Delete {
                 start: *start,
                 end: *end,
                 tags: vec![],
+                if_contains: if_contains.clone(),
+                if_missing: if_missing.clone(),
+            },
+            Self::DeleteBetween {
+                start_pattern,
+                end_pattern,
+                inclusive,
+                required,
+                if_contains,
+                if_missing,
+                ..
+            } => Self::DeleteBetween {
+                start_pattern: start_pattern.clone(),
+                end_pattern: end_pattern.clone(),
+                inclusive: *inclusive,
+                required: *required,
+                tags: vec![],
+                if_contains: if_contains.clone(),
+                if_missing: if_missing.clone(),
+            },
+            Self::Transcode {
+                from_encoding,
+                to_encoding,
+                ..
+            } => Self::Transcode {
+                from_encoding: from_encoding.clone(),
+                to_encoding: to_encoding.clone(),
+                tags: vec![],
             },
         }
     }
@@ -183,6 +448,77 @@ impl FileEdit {
     }
 }
 
+fn without_line_ending(line: &str) -> &str {
+    line.trim_end_matches('\n').trim_end_matches('\r')
+}
+
+// The line endings that --normalize-newlines writes.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, clap::ValueEnum)]
+pub enum NewlineStyle {
+    Lf,
+    Crlf,
+}
+
+// Like git, a file with a null byte near the start is treated as binary.
+fn looks_binary(data: &[u8]) -> bool {
+    data.iter().take(8000).any(|b| *b == 0)
+}
+
+// Binary files are returned unchanged. A lone \r is not a line ending and is kept.
+pub fn normalize_newlines(data: Vec<u8>, style: NewlineStyle) -> Vec<u8> {
+    if looks_binary(&data) {
+        return data;
+    }
+    let mut out = Vec::with_capacity(data.len());
+    let mut bytes = data.iter().peekable();
+    while let Some(b) = bytes.next() {
+        let newline = match b {
+            b'\n' => true,
+            b'\r' if bytes.peek() == Some(&&b'\n') => {
+                bytes.next();
+                true
+            }
+            _ => false,
+        };
+        if !newline {
+            out.push(*b);
+        } else if style == NewlineStyle::Crlf {
+            out.extend(b"\r\n");
+        } else {
+            out.push(b'\n');
+        }
+    }
+    out
+}
+
+fn find_encoding(label: &str) -> Result<&'static encoding_rs::Encoding> {
+    encoding_rs::Encoding::for_label(label.trim().as_bytes())
+        .context(format!("Unknown encoding {}", label))
+}
+
+pub fn decode_text(data: &[u8], label: &str) -> Result<String> {
+    let encoding = find_encoding(label)?;
+    encoding
+        .decode_without_bom_handling_and_without_replacement(data)
+        .map(|text| text.into_owned())
+        .context(format!("The content is not valid {}", encoding.name()))
+}
+
+pub fn encode_text(text: &str, label: &str) -> Result<Vec<u8>> {
+    let encoding = find_encoding(label)?;
+    let (bytes, used, unmappable) = encoding.encode(text);
+    if used != encoding {
+        return Err(format_err!("Can not encode text as {}", encoding.name()));
+    }
+    if unmappable {
+        return Err(format_err!(
+            "The text contains characters that {} can not represent",
+            encoding.name()
+        ));
+    }
+    Ok(bytes.into_owned())
+}
+
 pub fn include_edits(edits: &Vec<FileEdit>, tags: &Vec<String>) -> Vec<FileEdit> {
     let mut new: Vec<FileEdit> = vec![];
     for e in edits {
@@ -193,21 +529,42 @@ pub fn include_edits(edits: &Vec<FileEdit>, tags: &Vec<String>) -> Vec<FileEdit>
     new
 }
 
+fn set_optional_variable(text: &Option<String>, key: &str, value: &str) -> Result<Option<String>> {
+    match text {
+        Some(t) => Ok(Some(t.clone().set_single_variable(key, value)?)),
+        None => Ok(None),
+    }
+}
+
 impl VariableCompletion for FileEdit {
     fn required_variables(&self) -> Result<Vec<String>> {
         match self {
             Self::Replace {
                 from,
                 to,
+                to_source,
                 ignore_variables,
                 ..
             } => {
+                // The source itself is always completed, like all other sources.
+                let rb_source = match to_source {
+                    Some(s) => s.required_variables()?,
+                    None => vec![],
+                };
                 if *ignore_variables {
-                    return Ok(vec![]);
+                    return Ok(rb_source);
                 }
                 let rb_from = from.required_variables()?;
-                let rb_to = to.required_variables()?;
-                Ok(vecset(vec![rb_from, rb_to]))
+                let rb_to = match to {
+                    Some(t) => t.required_variables()?,
+                    None => vec![],
+                };
+                Ok(vecset(vec![
+                    rb_from,
+                    rb_to,
+                    rb_source,
+                    self.condition_variables()?,
+                ]))
             }
             Self::Insert {
                 content,
@@ -217,10 +574,23 @@ impl VariableCompletion for FileEdit {
                 if *ignore_variables {
                     Ok(vec![])
                 } else {
-                    content.required_variables()
+                    Ok(vecset(vec![
+                        content.required_variables()?,
+                        self.condition_variables()?,
+                    ]))
                 }
             }
-            Self::Delete { .. } => Ok(vec![]),
+            Self::Delete { .. } => self.condition_variables(),
+            Self::DeleteBetween {
+                start_pattern,
+                end_pattern,
+                ..
+            } => Ok(vecset(vec![
+                start_pattern.required_variables()?,
+                end_pattern.required_variables()?,
+                self.condition_variables()?,
+            ])),
+            Self::Transcode { .. } => Ok(vec![]),
         }
     }
     fn set_single_variable(&mut self, key: &str, value: &str) -> Result<Self> {
@@ -228,30 +598,345 @@ impl VariableCompletion for FileEdit {
             Self::Replace {
                 from,
                 to,
+                to_source,
+                variables,
                 required: optional,
                 tags,
                 ignore_variables,
                 ignore_case,
-            } => Ok(Self::Replace {
+                whole_word,
+                if_contains,
+                if_missing,
+            } if !*ignore_variables => Ok(Self::Replace {
                 from: from.set_single_variable(key, value)?,
-                to: to.set_single_variable(key, value)?,
+                to: match to {
+                    Some(t) => Some(t.set_single_variable(key, value)?),
+                    None => None,
+                },
+                to_source: match to_source {
+                    Some(s) => Some(s.set_single_variable(key, value)?),
+                    None => None,
+                },
+                variables: variables.clone(),
                 required: *optional,
                 tags: tags.clone(),
                 ignore_variables: *ignore_variables,
                 ignore_case: *ignore_case,
+                whole_word: *whole_word,
+                if_contains: set_optional_variable(if_contains, key, value)?,
+                if_missing: set_optional_variable(if_missing, key, value)?,
             }),
+            // Protected, only the source is completed.
+            Self::Replace { to_source, .. } => {
+                if let Some(s) = to_source {
+                    *s = s.set_single_variable(key, value)?;
+                }
+                Ok(self.clone())
+            }
             Self::Insert {
                 content,
                 position,
                 tags,
                 ignore_variables,
-            } => Ok(Self::Insert {
+                if_contains,
+                if_missing,
+            } if !*ignore_variables => Ok(Self::Insert {
                 content: content.set_single_variable(key, value)?,
                 position: position.clone(),
                 tags: tags.clone(),
                 ignore_variables: *ignore_variables,
+                if_contains: set_optional_variable(if_contains, key, value)?,
+                if_missing: set_optional_variable(if_missing, key, value)?,
+            }),
+            Self::Delete {
+                start,
+                end,
+                tags,
+                if_contains,
+                if_missing,
+            } => Ok(Self::Delete {
+                start: *start,
+                end: *end,
+                tags: tags.clone(),
+                if_contains: set_optional_variable(if_contains, key, value)?,
+                if_missing: set_optional_variable(if_missing, key, value)?,
             }),
-            Self::Delete { .. } => Ok(self.clone()),
+            Self::DeleteBetween {
+                start_pattern,
+                end_pattern,
+                inclusive,
+                required,
+                tags,
+                if_contains,
+                if_missing,
+            } => Ok(Self::DeleteBetween {
+                start_pattern: start_pattern.set_single_variable(key, value)?,
+                end_pattern: end_pattern.set_single_variable(key, value)?,
+                inclusive: *inclusive,
+                required: *required,
+                tags: tags.clone(),
+                if_contains: set_optional_variable(if_contains, key, value)?,
+                if_missing: set_optional_variable(if_missing, key, value)?,
+            }),
+            Self::Insert { .. } | Self::Transcode { .. } => Ok(self.clone()),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn replace(from: &str, to: &str, ignore_case: bool, whole_word: bool) -> FileEdit {
+        FileEdit::Replace {
+            from: from.to_string(),
+            to: Some(to.to_string()),
+            to_source: None,
+            variables: HashMap::new(),
+            required: false,
+            tags: vec![],
+            ignore_variables: false,
+            ignore_case,
+            whole_word,
+            if_contains: None,
+            if_missing: None,
+        }
+    }
+
+    #[test]
+    fn test_insert_at_byte() {
+        #[derive(Deserialize)]
+        struct Edits {
+            edit: Vec<FileEdit>,
+        }
+        let edits: Edits = toml::from_str(
+            "[[edit]]\ntype=\"insert\"\ncontent=\"--\"\nposition={ byte = 3 }\n[[edit]]\ntype=\"insert\"\ncontent=\"!\"\nposition=2",
+        )
+        .unwrap();
+        assert_eq!(
+            edits.edit[0]
+                .apply("abcdef", &FetchContext::default())
+                .unwrap()
+                .as_bytes(),
+            b"abc--def"
+        );
+        assert_eq!(
+            edits.edit[0]
+                .apply("abc", &FetchContext::default())
+                .unwrap()
+                .as_bytes(),
+            b"abc--"
+        );
+        assert!(edits.edit[0].apply("ab", &FetchContext::default()).is_err());
+        assert!(edits.edit[0].apply("ää", &FetchContext::default()).is_err());
+        assert_eq!(
+            edits.edit[1]
+                .apply("a\nb", &FetchContext::default())
+                .unwrap(),
+            "a\nb\n!"
+        );
+    }
+
+    #[test]
+    fn test_edit_conditions() {
+        let append: FileEdit = toml::from_str(
+            "type=\"insert\"\ncontent=\"\\nexport PATH\"\nposition=\"append\"\nif_missing=\"export PATH\"",
+        )
+        .unwrap();
+        assert!(append.condition_met("a=1"));
+        assert!(!append.condition_met("a=1\nexport PATH"));
+        let mut replace = replace("debug", "release", false, false);
+        if let FileEdit::Replace { if_contains, .. } = &mut replace {
+            *if_contains = Some("[build]".to_string());
         }
+        assert!(replace.condition_met("[build]\ndebug"));
+        assert!(!replace.condition_met("debug"));
+        // The conditions are completed with the variables, like the content.
+        let vars = HashMap::from([("name".to_string(), "alice".to_string())]);
+        let templated: FileEdit =
+            toml::from_str("type=\"delete\"\nstart=1\nend=1\nif_contains=\"{{name}}\"").unwrap();
+        let templated = vec![templated].set_variables(&vars).unwrap();
+        assert!(templated[0].condition_met("user alice"));
+        assert!(!templated[0].condition_met("user bob"));
+        // A protected insert is left alone, even if another edit needs the same variable.
+        let edits: Vec<FileEdit> = vec![
+            toml::from_str("type=\"insert\"\ncontent=\"{{name}}\"\nposition=\"append\"\nif_missing=\"{{name}}\"\nignore_variables=true").unwrap(),
+            toml::from_str("type=\"insert\"\ncontent=\"{{name}}\"\nposition=\"prepend\"").unwrap(),
+        ];
+        let edits = edits.set_variables(&vars).unwrap();
+        assert_eq!(
+            edits[0].apply("x", &FetchContext::default()).unwrap(),
+            "x{{name}}"
+        );
+        assert!(edits[0].condition_met("alice"));
+        assert!(!edits[0].condition_met("{{name}}"));
+        assert_eq!(
+            edits[1].apply("x", &FetchContext::default()).unwrap(),
+            "alicex"
+        );
+    }
+
+    #[test]
+    fn test_replace_options() {
+        let text = "Cat cat catalog CAT (c.t)";
+        assert_eq!(
+            replace("cat", "dog", false, false)
+                .apply(text, &FetchContext::default())
+                .unwrap(),
+            "Cat dog dogalog CAT (c.t)"
+        );
+        assert_eq!(
+            replace("cat", "dog", true, false)
+                .apply(text, &FetchContext::default())
+                .unwrap(),
+            "dog dog dogalog dog (c.t)"
+        );
+        assert_eq!(
+            replace("cat", "dog", false, true)
+                .apply(text, &FetchContext::default())
+                .unwrap(),
+            "Cat dog catalog CAT (c.t)"
+        );
+        assert_eq!(
+            replace("cat", "$1", true, true)
+                .apply(text, &FetchContext::default())
+                .unwrap(),
+            "$1 $1 catalog $1 (c.t)"
+        );
+        // The search string is not a regex.
+        assert_eq!(
+            replace("c.t", "x", true, false)
+                .apply(text, &FetchContext::default())
+                .unwrap(),
+            "Cat cat catalog CAT (x)"
+        );
+    }
+
+    #[test]
+    fn test_replace_ignore_case_no_regex() {
+        // Before whole_word, ignore_case took the search string as a regex.
+        let text = "Debug = true\nname = (x)";
+        let mut old_style = replace("^debug = .*$", "debug = false", true, false);
+        assert_eq!(
+            old_style.apply(text, &FetchContext::default()).unwrap(),
+            text
+        );
+        if let FileEdit::Replace { required, .. } = &mut old_style {
+            *required = true;
+        }
+        assert!(old_style.apply(text, &FetchContext::default()).is_err());
+        // The escaped form it needed before is now searched for as it is.
+        assert_eq!(
+            replace(r"\(x\)", "y", true, false)
+                .apply(text, &FetchContext::default())
+                .unwrap(),
+            text
+        );
+        assert_eq!(
+            replace("(X)", "${1}", true, false)
+                .apply(text, &FetchContext::default())
+                .unwrap(),
+            "Debug = true\nname = ${1}"
+        );
+    }
+
+    #[test]
+    fn test_replace_source_variables() {
+        let vars = HashMap::from([("name".to_string(), "alice".to_string())]);
+        // The source is completed, the protected search string is not.
+        let edit: FileEdit = toml::from_str(
+            "type=\"replace\"\nfrom=\"{{name}}\"\nto_source={ type=\"text\", content=\"{{name}}!\" }\nignore_variables=true",
+        )
+        .unwrap();
+        let edit = edit.set_variables(&vars).unwrap();
+        assert_eq!(
+            edit.apply("hi {{name}}", &FetchContext::default()).unwrap(),
+            "hi alice!"
+        );
+        assert!(edit.apply("hi alice", &FetchContext::default()).is_err());
+    }
+
+    fn delete_between(inclusive: bool, required: bool) -> FileEdit {
+        FileEdit::DeleteBetween {
+            start_pattern: "^# BEGIN".to_string(),
+            end_pattern: "^# END".to_string(),
+            inclusive,
+            required,
+            tags: vec![],
+            if_contains: None,
+            if_missing: None,
+        }
+    }
+
+    #[test]
+    fn test_delete_between() {
+        let text = "keep\r\n# BEGIN generated\r\nold\r\n# END generated\r\ntail\r\n# END\r\n";
+        assert_eq!(
+            delete_between(true, true)
+                .apply(text, &FetchContext::default())
+                .unwrap(),
+            "keep\r\ntail\r\n# END\r\n"
+        );
+        assert_eq!(
+            delete_between(false, true)
+                .apply(text, &FetchContext::default())
+                .unwrap(),
+            "keep\r\n# BEGIN generated\r\n# END generated\r\ntail\r\n# END\r\n"
+        );
+        // The end marker has to come after the start marker.
+        let reversed = "# END\nold\n# BEGIN\n";
+        assert!(delete_between(true, true)
+            .apply(reversed, &FetchContext::default())
+            .unwrap_err()
+            .to_string()
+            .contains("^# END"));
+        assert_eq!(
+            delete_between(true, false)
+                .apply(reversed, &FetchContext::default())
+                .unwrap(),
+            reversed
+        );
+        assert!(delete_between(true, true)
+            .apply("no markers", &FetchContext::default())
+            .unwrap_err()
+            .to_string()
+            .contains("^# BEGIN"));
+    }
+
+    #[test]
+    fn test_transcode_latin1() {
+        let latin1 = include_bytes!("../testing/latin1.txt");
+        assert!(String::from_utf8(latin1.to_vec()).is_err());
+        let text = decode_text(latin1, "latin1").unwrap();
+        assert_eq!(text, "Grüße aus dem Café\n");
+        assert_eq!(encode_text(&text, "ISO-8859-1").unwrap(), latin1.to_vec());
+        assert_eq!(encode_text(&text, "utf-8").unwrap(), text.as_bytes());
+        assert!(encode_text("€ and 日本", "latin1").is_err());
+        assert!(decode_text(latin1, "utf-8").is_err());
+        assert!(decode_text(latin1, "no-such-encoding").is_err());
+    }
+
+    #[test]
+    fn test_normalize_newlines() {
+        let mixed = b"one\r\ntwo\nthree\rstill three\n".to_vec();
+        assert_eq!(
+            normalize_newlines(mixed.clone(), NewlineStyle::Lf),
+            b"one\ntwo\nthree\rstill three\n"
+        );
+        assert_eq!(
+            normalize_newlines(mixed, NewlineStyle::Crlf),
+            b"one\r\ntwo\r\nthree\rstill three\r\n"
+        );
+        assert_eq!(
+            normalize_newlines(b"a\nb".to_vec(), NewlineStyle::Crlf),
+            b"a\r\nb"
+        );
+        // Binary files are passed through.
+        let binary = vec![0x89, b'P', b'N', b'G', b'\r', b'\n', 0, b'\n'];
+        assert_eq!(normalize_newlines(binary.clone(), NewlineStyle::Lf), binary);
+        assert_eq!(
+            normalize_newlines(binary.clone(), NewlineStyle::Crlf),
+            binary
+        );
     }
 }
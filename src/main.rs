@@ -1,3 +1,5 @@
+// References to Vec and PathBuf are passed between all modules.
+#![allow(clippy::ptr_arg)]
 //------------------------------------------------------------
 //External dependencies
 //------------------------------------------------------------
@@ -5,11 +7,10 @@ use anyhow::{format_err, Context, Error, Result};
 use auth_git2::GitAuthenticator;
 use clap::{Parser, Subcommand};
 use colored::*;
-use ctrlc;
 use dialoguer::Confirm;
 use dirs::config_dir;
 use git2::{Oid, Repository};
-use indicatif::{ProgressBar, ProgressStyle};
+use indicatif::{MultiProgress, ProgressBar, ProgressStyle};
 use once_cell::sync::OnceCell;
 use regex::Regex;
 use serde::{Deserialize, Serialize};
@@ -17,14 +18,16 @@ use serde_with::serde_as;
 use sha3::{Digest, Sha3_256};
 use ssh2::Session;
 use std::{
-    collections::{HashMap, HashSet},
+    collections::{BTreeMap, HashMap, HashSet},
     env::consts::OS,
     fmt, fs,
     io::prelude::*,
     net::TcpStream,
     path::PathBuf,
     process::exit,
-    time::Duration,
+    sync::atomic::{AtomicUsize, Ordering},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
 };
 use tempfile::TempDir;
 use termion::terminal_size;
@@ -32,27 +35,97 @@ use termion::terminal_size;
 //------------------------------------------------------------
 //Internal dependencies
 //------------------------------------------------------------
+mod backend;
 mod cli;
 mod config;
+mod decompress;
 mod decrypt;
 mod directories;
 mod edits;
+mod manifest;
 mod memfolder;
+mod secrets;
+mod signature;
 mod sources;
 mod variables;
 use {
-    cli::*, config::*, decrypt::*, directories::*, edits::*, memfolder::*, sources::*, variables::*,
+    backend::*, cli::*, config::*, decompress::*, decrypt::*, directories::*, edits::*,
+    manifest::*, memfolder::*, secrets::*, signature::*, sources::*, variables::*,
 };
 
 //------------------------------------------------------------
 //constants
 //------------------------------------------------------------
 pub static CACHEDIR: OnceCell<TempDir> = OnceCell::new();
+// Set by --no-hash-check. It is global, because hashes are checked deep inside of loading configs and files.
+pub static SKIP_HASH_CHECK: OnceCell<bool> = OnceCell::new();
+
+// Set by --config-lock. Every loaded config must have the hash recorded by lock --configs.
+pub static CONFIG_LOCK: OnceCell<ConfigLock> = OnceCell::new();
+
+pub fn hash_check_enabled() -> bool {
+    !SKIP_HASH_CHECK.get().copied().unwrap_or(false)
+}
+
+// With --trace, every resolution step is printed to stderr, nested inclusions are indented.
+static TRACE_DEPTH: AtomicUsize = AtomicUsize::new(0);
+
+impl FetchContext {
+    pub fn trace(&self, message: impl FnOnce() -> String) {
+        if self.trace {
+            let indent = "  ".repeat(TRACE_DEPTH.load(Ordering::Relaxed));
+            eprintln!(
+                "{}",
+                format!("{}trace: {}", indent, hide_secrets(&message())).dimmed()
+            );
+        }
+    }
+}
+
+pub fn trace_nested<T>(f: impl FnOnce() -> T) -> T {
+    TRACE_DEPTH.fetch_add(1, Ordering::Relaxed);
+    let result = f();
+    TRACE_DEPTH.fetch_sub(1, Ordering::Relaxed);
+    result
+}
+
+// Wall-clock times of fetches, directory listings and edits. Printed by sync --timing.
+// Nothing is recorded until sync --timing starts the list.
+static TIMINGS: Mutex<Option<Vec<(String, Duration)>>> = Mutex::new(None);
+
+pub fn timed<T>(label: impl FnOnce() -> String, f: impl FnOnce() -> T) -> T {
+    let start = Instant::now();
+    let result = f();
+    if let Ok(mut guard) = TIMINGS.lock() {
+        if let Some(timings) = guard.as_mut() {
+            timings.push((label(), start.elapsed()));
+        }
+    }
+    result
+}
 
 fn main() {
     let cli = Cli::parse();
+    let keep_cache = cli.keep_cache;
+    let mut fetch = FetchContext {
+        user_agent: cli.user_agent.clone(),
+        allow_exec: cli.allow_exec,
+        config_vars: cli.config_var.iter().cloned().collect(),
+        profile: cli.profile.clone(),
+        ..Default::default()
+    };
+    // A broken bundle is reported before anything else is done.
+    if let Some(path) = &cli.ca_bundle {
+        match load_ca_bundle(path) {
+            Ok(certificates) => fetch.ca_certificates = certificates,
+            Err(e) => {
+                red(format!("Error: {}", e));
+                exit(1)
+            }
+        }
+    }
     ctrlc::set_handler(move || {
-        if let Err(_) = clean_cache_dir() {
+        if clean_cache_dir(keep_cache).is_err() {
             red("Canceled. Cache directory could not be cleaned up");
         } else {
             red("Canceled");
@@ -69,13 +142,102 @@ fn main() {
             no_confirm,
             skip_first_level,
             identity_files,
-        } => sync_folder(
+            keep_going,
+            config_hash,
+            parallel_clone,
+            jobs,
+            managed_list,
+            no_hash_check,
+            mem_budget,
+            untracked,
+            prune,
+            from_bundle,
+            verbose,
+            timing,
+            replace_nondir,
+            default_mode,
+            require_pinned_refs,
+            confirm_each,
+            dedup_links,
+            trace,
+            config_lock,
+            exclude,
+            netrc,
+            require_nonempty,
+            unchanged_exit_code,
+            max_include_depth,
+            print_includes,
+            normalize_newlines,
+            warn_local_drift,
+            durable,
+            lenient_count,
+            remember_confirmation,
+        } => match (from_bundle, output) {
+            (Some(_), None) => Ok((String::new(), PathBuf::from(file))),
+            (Some(_), Some(_)) => Err(format_err!(
+                "With --from-bundle, only the output directory is given."
+            )),
+            (None, _) => resolve_config_and_output(file, output),
+        }
+        .and_then(|(file, output)| {
+            sync_folder(
+                &output,
+                &file,
+                tags,
+                &SyncOptions {
+                    no_confirm: *no_confirm,
+                    skip_first: *skip_first_level,
+                    identity_files: identity_files.clone(),
+                    keep_going: *keep_going,
+                    config_hash: config_hash.clone(),
+                    parallel_clone: *parallel_clone,
+                    jobs: *jobs,
+                    managed_list: *managed_list,
+                    no_hash_check: *no_hash_check,
+                    mem_budget: *mem_budget,
+                    untracked: if *prune {
+                        Some(UntrackedMode::Prune)
+                    } else {
+                        *untracked
+                    },
+                    from_bundle: from_bundle.clone(),
+                    verbose: *verbose,
+                    timing: *timing,
+                    replace_nondir: *replace_nondir,
+                    default_mode: *default_mode,
+                    confirm_each: *confirm_each,
+                    dedup_links: *dedup_links,
+                    config_lock: config_lock.clone(),
+                    exclude: exclude.clone(),
+                    require_nonempty: *require_nonempty,
+                    unchanged_exit_code: *unchanged_exit_code,
+                    max_include_depth: Some(*max_include_depth),
+                    print_includes: *print_includes,
+                    normalize_newlines: *normalize_newlines,
+                    warn_local_drift: *warn_local_drift,
+                    durable: *durable,
+                    lenient_count: *lenient_count,
+                    remember_confirmation: *remember_confirmation,
+                    fetch: FetchContext {
+                        netrc: *netrc,
+                        require_pinned_refs: *require_pinned_refs,
+                        trace: *trace,
+                        ..fetch.clone()
+                    },
+                },
+            )
+        }),
+        Commands::Clean {
             output,
             file,
-            tags,
+            no_confirm,
+            dry_run,
+            from_managed_list: true,
+            ..
+        } => clean_managed_files(
+            output.as_ref().unwrap_or(&PathBuf::from(file)),
             *no_confirm,
-            *skip_first_level,
-            identity_files,
+            *dry_run,
         ),
         Commands::Clean {
             output,
@@ -83,27 +245,132 @@ fn main() {
             tags,
             no_confirm,
             skip_first_level,
-        } => clean_command(file, output, tags, *skip_first_level, *no_confirm),
+            dry_run,
+            from_managed_list: false,
+        } => resolve_config_and_output(file, output).and_then(|(file, output)| {
+            clean_command(
+                &file,
+                &output,
+                tags,
+                *skip_first_level,
+                *no_confirm,
+                *dry_run,
+                &fetch,
+            )
+        }),
         Commands::Config {
             file,
             tags,
             no_confirm,
-        } => sync_dotconf(file, tags, *no_confirm),
-        Commands::Show { source, output } => show(source, output),
-        Commands::Example {} => write_example_config(),
-        Commands::Hash { file } => print_hash(file),
-        Commands::Tags { file } => print_tags(file),
-        Commands::List { file, tags } => print_list(file, tags),
+        } => resolve_config(file).and_then(|file| sync_dotconf(&file, tags, *no_confirm, &fetch)),
+        Commands::Show {
+            source,
+            output,
+            path: None,
+            ..
+        } => show(source, output, &fetch),
+        Commands::Show {
+            source,
+            output,
+            path: Some(path),
+            tags,
+            identity_files,
+        } => show_from_config(source, path, tags, identity_files, output, &fetch),
+        Commands::Example {
+            path,
+            force,
+            from: None,
+            ..
+        } => write_example_config(path, *force),
+        Commands::Example {
+            path,
+            force,
+            from: Some(from),
+            as_directory,
+        } => write_scaffold_config(from, path, *as_directory, *force),
+        Commands::Hash { file, short, algo } => print_hash(file, *short, *algo),
+        Commands::Tags { file, effective } => {
+            resolve_config(file).and_then(|file| print_tags(&file, *effective, &fetch))
+        }
+        Commands::Variables { file } => {
+            resolve_config(file).and_then(|file| print_variables(&file, &fetch))
+        }
+        Commands::Canonicalize { file } => resolve_config(file)
+            .and_then(|file| Config::canonical(&file, &fetch))
+            .map(|canonical| print!("{}", canonical)),
+        Commands::Refs { file, tags } => {
+            resolve_config(file).and_then(|file| print_refs(&file, tags, &fetch))
+        }
+        Commands::List {
+            file,
+            tags,
+            trace,
+            exclude,
+            max_include_depth,
+            print_includes,
+        } => {
+            fetch.trace = *trace;
+            let context = ListContext::new(false, Some(*max_include_depth));
+            resolve_config(file).and_then(|file| {
+                print_list(&file, tags, exclude, &context, &fetch)?;
+                if *print_includes {
+                    let config = Config::from_general_path(&file, true, None, &fetch)?;
+                    print_include_tree(config.include_tree(tags, &context, &fetch)?);
+                }
+                Ok(())
+            })
+        }
+        Commands::Explain { file, path, tags } => print_explanation(file, path, tags, &fetch),
+        Commands::Bundle {
+            file,
+            output,
+            tags,
+            identity_files,
+        } => write_bundle(file, output, tags, identity_files, &fetch),
+        Commands::Fingerprint {
+            file,
+            tags,
+            identity_files,
+        } => resolve_config(file)
+            .and_then(|file| print_fingerprint(&file, tags, identity_files, &fetch)),
+        Commands::Verify {
+            file,
+            output,
+            tags,
+            skip_first_level,
+            identity_files,
+        } => verify_folder(
+            file,
+            output,
+            tags,
+            *skip_first_level,
+            identity_files,
+            &fetch,
+        ),
+        Commands::Lock {
+            file, output, tags, ..
+        } => write_config_lock(file, output, tags, &fetch),
+        Commands::Edits {
+            file,
+            tags,
+            identity_files,
+        } => resolve_config(file)
+            .and_then(|file| print_edit_diffs(&file, tags, identity_files, &fetch)),
     };
-    if let Err(_) = clean_cache_dir() {
+    if clean_cache_dir(keep_cache).is_err() {
         yellow("Cache directory could not be cleaned up");
     }
     if let Err(e) = result {
+        if let Some(Unchanged(code)) = e.downcast_ref::<Unchanged>() {
+            exit(*code)
+        }
         red(format!("Error: {}", e));
         exit(1)
     } else {
         match &cli.command {
-            Commands::Show { output: None, .. } => {}
+            Commands::Show { output: None, .. }
+            | Commands::Fingerprint { .. }
+            | Commands::Canonicalize { .. } => {}
             _ => green("Operation completed"),
         }
     }
@@ -113,51 +380,359 @@ fn sync_folder(
     output: &PathBuf,
     config_path: &str,
     tags: &Vec<String>,
-    no_confirm: bool,
-    skip_fist: bool,
-    identity_files: &Vec<PathBuf>,
+    options: &SyncOptions,
 ) -> Result<()> {
-    let ids = load_agev1keys(identity_files)?;
+    if options.no_hash_check {
+        let _ = SKIP_HASH_CHECK.set(true);
+        red("WARNING: Hashes are not checked (--no-hash-check). The integrity of the files is not verified!");
+    }
+    let fetch = &options.fetch;
+    if options.timing {
+        if let Ok(mut timings) = TIMINGS.lock() {
+            *timings = Some(vec![]);
+        }
+    }
+    if let Some(lockfile) = &options.config_lock {
+        let _ = CONFIG_LOCK.set(ConfigLock::read(lockfile, config_path)?);
+    }
+    let ids = load_agev1keys(&options.identity_files)?;
+    let input = match &options.from_bundle {
+        Some(bundle) => SyncInput::Bundle(bundle),
+        None => SyncInput::Config(Box::new(Config::from_general_path(
+            config_path,
+            true,
+            options.config_hash.as_deref(),
+            fetch,
+        )?)),
+    };
+    // The guard has to see the output path with the variables filled in.
+    let output = &match &input {
+        SyncInput::Config(conf) => conf.output_with_variables(output, tags, fetch)?,
+        SyncInput::Bundle(_) => fill_in_output_path(output, &HashMap::new())?,
+    };
     if let (Ok(c_output), Ok(cwd)) = (output.canonicalize(), std::env::current_dir()) {
-        if c_output == cwd && !skip_fist {
+        if c_output == cwd && !options.skip_first {
             return Err(format_err!(
                 "This would overwrite your current working directory!"
             ));
         }
     }
 
-    let conf = Config::from_general_path(config_path, true, None)?;
-
-    let memfolder = MemFolder::load_first_valid_with_ref(&conf, tags, &output, &ids)?;
-    if !skip_fist {
-        if !no_confirm && output.exists() && !get_confirmation(output, memfolder.0.keys().count()) {
-            return Err(format_err!("Folder overwrite not confirmed."));
+    let include_tree = match (&input, options.print_includes) {
+        (SyncInput::Config(conf), true) => Some(conf.include_tree(
+            tags,
+            &ListContext::new(options.lenient_count, options.max_include_depth),
+            fetch,
+        )?),
+        _ => None,
+    };
+    let (mut memfolder, failures) = match input {
+        SyncInput::Bundle(bundle) => (MemFolder::read_bundle(bundle)?, vec![]),
+        SyncInput::Config(conf) => {
+            warn_edit_only_tags(&conf, tags);
+            if options.parallel_clone {
+                prefetch_repos(&conf.remote_repos(), options.jobs, fetch)?;
+            }
+            let active = active_files(&conf, tags, options)?;
+            // Most likely the tags are wrong and the directory would be cleared.
+            if active.is_empty() {
+                let message = format!(
+                    "No file is active with the tags [{}].",
+                    conf.active_tags(tags)?.join(", ")
+                );
+                if options.require_nonempty {
+                    return Err(format_err!(message));
+                }
+                red(message);
+            }
+            MemFolder::load_active_with_ref(&conf, &active, tags, output, &ids, options)?
         }
-
-        memfolder.write_to_folder(output)?;
-        Ok(())
+    };
+    if let Some(tree) = include_tree {
+        print_include_tree(tree);
+    }
+    let previously_managed = if options.managed_list {
+        read_managed_list(output)?
+    } else {
+        vec![]
+    };
+    // Nothing would change, so the confirmation and the rewrite are skipped.
+    if memfolder.matches_disk(output, options.skip_first, options.default_mode)?
+        && (!options.managed_list || same_paths(&previously_managed, &memfolder.paths()))
+    {
+        green("Already up to date, nothing to do.");
+        finish_sync(options, &failures)?;
+        return match options.unchanged_exit_code {
+            Some(code) => Err(Unchanged(code).into()),
+            None => Ok(()),
+        };
+    }
+    // With -S, only what is below the tracked paths would be overwritten.
+    let scope = match options.skip_first {
+        true => Some(memfolder.tracked_subpaths()?),
+        false => None,
+    };
+    let remembered = match options.remember_confirmation {
+        Some(minutes) => remembered_confirmation(config_path, output, scope.as_ref(), minutes),
+        None => None,
+    };
+    if remembered.is_some() {
+        neutral("The overwrite was confirmed recently, not asking again.");
+    }
+    // Only an answer to the prompt is remembered, not -Y.
+    let mut confirmed_at = remembered;
+    if !options.skip_first {
+        if !options.no_confirm && remembered.is_none() && output.exists() {
+            if !get_confirmation(output, memfolder.paths().len()) {
+                return Err(format_err!("Folder overwrite not confirmed."));
+            }
+            confirmed_at = Some(unix_time());
+        }
+        if options.replace_nondir {
+            remove_nondir(output)?;
+        }
+        memfolder.write_to_folder(output, options)?;
     } else {
+        // Declined paths are removed before anything else looks at them, so they stay untouched.
+        if options.confirm_each {
+            let approved = confirm_each_path(output, &memfolder.tracked_subpaths()?);
+            memfolder.retain_tracked(&approved);
+        }
+        let untracked = match options.untracked {
+            Some(_) => memfolder.untracked_files(output)?,
+            None => vec![],
+        };
+        if options.untracked == Some(UntrackedMode::Fail) && !untracked.is_empty() {
+            report_untracked(&untracked);
+            return Err(format_err!(
+                "{} file(s) inside of tracked paths are not part of the config.",
+                untracked.len()
+            ));
+        }
         let tracked = memfolder.tracked_subpaths()?;
-        if !no_confirm && output.exists() && !get_confirmation_skip_level(output, &tracked) {
-            return Err(format_err!("Folder overwrite not confirmed."));
+        if !options.no_confirm && !options.confirm_each && remembered.is_none() && output.exists() {
+            if !get_confirmation_skip_level(output, &tracked) {
+                return Err(format_err!("Folder overwrite not confirmed."));
+            }
+            confirmed_at = Some(unix_time());
         }
-        memfolder.write_to_folder_skip_first(output)?;
-        Ok(())
+        if options.replace_nondir {
+            remove_nondir(output)?;
+        }
+        match options.untracked {
+            None => memfolder.write_to_folder_skip_first(output, options)?,
+            Some(mode) => {
+                memfolder.write_to_folder_file_by_file(output, options)?;
+                if mode == UntrackedMode::Prune {
+                    remove_untracked(output, &untracked)?;
+                } else if !untracked.is_empty() {
+                    report_untracked(&untracked);
+                }
+            }
+        }
+        if options.managed_list {
+            memfolder.remove_no_longer_managed(output, &previously_managed)?;
+        }
+    }
+    if options.managed_list {
+        memfolder.write_managed_list(output)?;
+    }
+    if let (Some(_), Some(time)) = (options.remember_confirmation, confirmed_at) {
+        if let Err(e) = remember_confirmation(config_path, output, scope.as_ref(), time) {
+            yellow(format!("Could not remember the confirmation: {}", e));
+        }
+    }
+    finish_sync(options, &failures)
+}
+
+// The reports at the end of a sync, whether anything was written or not.
+fn finish_sync(options: &SyncOptions, failures: &Vec<(PathBuf, Error)>) -> Result<()> {
+    if options.verbose {
+        report_source_outcomes(take_source_outcomes());
+    }
+    if options.timing {
+        report_timings();
     }
+    report_failures(failures)
+}
+
+fn same_paths(a: &Vec<PathBuf>, b: &Vec<PathBuf>) -> bool {
+    let mut a = a.iter().map(format_subpath).collect::<Vec<_>>();
+    let mut b = b.iter().map(format_subpath).collect::<Vec<_>>();
+    a.sort();
+    b.sort();
+    a == b
 }
 
-fn sync_dotconf(config_path: &str, tags: &Vec<String>, no_confirm: bool) -> Result<()> {
+// What a sync reads its files from.
+enum SyncInput<'a> {
+    Bundle(&'a PathBuf),
+    Config(Box<Config>),
+}
+
+// Returned by a sync that did not change anything, if a special exit code was requested.
+#[derive(Debug)]
+pub struct Unchanged(pub i32);
+
+impl std::fmt::Display for Unchanged {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "The output is already up to date.")
+    }
+}
+
+impl std::error::Error for Unchanged {}
+
+fn report_timings() {
+    let mut timings = match TIMINGS.lock() {
+        Ok(mut timings) => timings.take().unwrap_or_default(),
+        Err(_) => return,
+    };
+    timings.sort_by_key(|(_, duration)| std::cmp::Reverse(*duration));
+    let total: Duration = timings.iter().map(|(_, d)| *d).sum();
+    break_line();
+    neutral("Slowest operations:");
+    for (label, duration) in timings.iter().take(20) {
+        neutral(format!(
+            "{:>10.1}ms {}",
+            duration.as_secs_f64() * 1000.0,
+            label
+        ));
+    }
+    neutral(format!(
+        "{} operations took {:.3}s in total.",
+        timings.len(),
+        total.as_secs_f64()
+    ));
+    break_line();
+}
+
+fn report_source_outcomes(mut outcomes: Vec<SourceOutcome>) {
+    outcomes.sort_by(|a, b| a.path.cmp(&b.path));
+    break_line();
+    for outcome in outcomes {
+        match outcome.used {
+            Some(0) => neutral(format!(
+                "- {}: source 1 of {}",
+                outcome.path.display(),
+                outcome.total
+            )),
+            Some(index) => yellow(format!(
+                "- {}: source {} of {}",
+                outcome.path.display(),
+                index + 1,
+                outcome.total
+            )),
+            None => red(format!("- {}: no valid source", outcome.path.display())),
+        }
+        for (index, e) in outcome.failed {
+            neutral(format!("    source {} failed: {}", index + 1, e));
+        }
+    }
+    break_line();
+}
+
+fn print_include_tree(tree: Vec<IncludedConfig>) {
+    break_line();
+    if tree.is_empty() {
+        neutral("No configs are included.");
+    }
+    for inc in tree {
+        let subfolder = match format_subpath(&inc.subfolder) {
+            p if p.as_os_str().is_empty() => PathBuf::from("."),
+            p => p,
+        };
+        neutral(format!(
+            "{}- {} at {} ({} file(s))",
+            "  ".repeat(inc.depth),
+            inc.source,
+            subfolder.display(),
+            inc.files
+        ));
+    }
+    break_line();
+}
+
+fn report_untracked(untracked: &Vec<PathBuf>) {
+    yellow("Inside of tracked paths, but not part of the config:");
+    break_line();
+    for path in untracked {
+        yellow(format!("- {}", path.display()));
+    }
+    break_line();
+}
+
+// With --keep-going, the files that could not be built are listed at the end.
+fn report_failures(failures: &Vec<(PathBuf, Error)>) -> Result<()> {
+    if failures.is_empty() {
+        return Ok(());
+    }
+    break_line();
+    for (path, e) in failures {
+        red(format!("- {}: {}", path.display(), e));
+    }
+    break_line();
+    Err(format_err!(
+        "{} file(s) could not be built and were skipped.",
+        failures.len()
+    ))
+}
+
+fn sync_dotconf(
+    config_path: &str,
+    tags: &Vec<String>,
+    no_confirm: bool,
+    fetch: &FetchContext,
+) -> Result<()> {
     if OS != "linux" {
         return Err(format_err!(
             "Detecting the config-directory is currently only supported on linux."
         ));
     }
     let dotconf = config_dir().context("Could not detect config directory")?;
-    sync_folder(&dotconf, config_path, tags, no_confirm, true, &vec![])
+    sync_folder(
+        &dotconf,
+        config_path,
+        tags,
+        &SyncOptions {
+            no_confirm,
+            skip_first: true,
+            fetch: fetch.clone(),
+            ..Default::default()
+        },
+    )
 }
 
-fn show(source: &String, output: &Option<PathBuf>) -> Result<()> {
-    let content = FileSource::Auto(source.clone()).fetch()?;
+fn show(source: &String, output: &Option<PathBuf>, fetch: &FetchContext) -> Result<()> {
+    let content = FileSource::Auto(source.clone()).fetch(fetch)?;
+    print_or_write(content, output)
+}
+
+fn show_from_config(
+    configpath: &str,
+    path: &PathBuf,
+    tags: &Vec<String>,
+    identity_files: &Vec<PathBuf>,
+    output: &Option<PathBuf>,
+    fetch: &FetchContext,
+) -> Result<()> {
+    let ids = load_agev1keys(identity_files)?;
+    let config = Config::from_general_path(configpath, true, None, fetch)?;
+    let wanted = format_subpath(path);
+    let file = config
+        .get_active(tags, fetch)?
+        .into_iter()
+        .find(|f| f.get_path() == wanted)
+        .context(format!(
+            "The path {} is not part of the config with these tags.",
+            wanted.display()
+        ))?;
+    let content = file
+        .build(tags, &ids, false, fetch)
+        .map_err(|e| format_err!("Could not build {}: {}", wanted.display(), e))?;
+    print_or_write(content, output)
+}
+
+fn print_or_write(content: Vec<u8>, output: &Option<PathBuf>) -> Result<()> {
     match output {
         None => {
             let text = String::from_utf8(content)?;
@@ -168,43 +743,144 @@ fn show(source: &String, output: &Option<PathBuf>) -> Result<()> {
     Ok(())
 }
 
-fn write_example_config() -> Result<()> {
-    let conf = include_str!("lorevault_example.toml");
-    if PathBuf::from("lorevault_example.toml").exists() {
-        return Err(format_err!("lorevault_example.toml already exists."));
-    }
-    fs::write("lorevault_example.toml", conf)?;
-    green("Saved example as lorevault_example.toml");
+fn write_example_config(path: &PathBuf, force: bool) -> Result<()> {
+    write_new_config(path, include_str!("lorevault_example.toml"), force)?;
+    green(format!("Saved example as {}", path.display()));
     Ok(())
 }
 
-fn print_hash(path: &str) -> Result<()> {
-    let content = fs::read(path)?;
-    let hash = compute_hash(&content);
-    neutral(format!("hash = \"{}\"", hash));
+fn write_scaffold_config(
+    from: &PathBuf,
+    path: &PathBuf,
+    as_directory: bool,
+    force: bool,
+) -> Result<()> {
+    write_new_config(path, &scaffold_config(from, as_directory)?, force)?;
+    green(format!(
+        "Saved the config for {} as {}",
+        from.display(),
+        path.display()
+    ));
+    Ok(())
+}
+
+fn write_new_config(path: &PathBuf, conf: &str, force: bool) -> Result<()> {
+    if path.exists() && !force {
+        return Err(format_err!(
+            "{} already exists. Use --force to overwrite it.",
+            path.display()
+        ));
+    }
+    if path.is_dir() {
+        return Err(format_err!("{} is a directory.", path.display()));
+    }
+    fs::write(path, conf).context(format!("Could not write {}", path.display()))
+}
+
+fn print_hash(path: &str, short: bool, algo: HashAlgo) -> Result<()> {
+    let file = fs::File::open(path).context(format!("Could not open {}", path))?;
+    let hash = compute_hash_with(algo, file)?;
+    if short {
+        neutral(short_hash(&hash));
+        return Ok(());
+    }
+    neutral(format!("hash = \"{}\"", algo.format(&hash)));
     Ok(())
 }
-fn print_tags(configpath: &str) -> Result<()> {
-    let config = Config::from_general_path(configpath, true, None)?;
+fn print_tags(configpath: &str, effective: bool, fetch: &FetchContext) -> Result<()> {
+    let config = Config::from_general_path(configpath, true, None, fetch)?;
 
     let mut tags = config.tags();
     tags.sort();
     break_line();
     for tag in &tags {
-        if config.default_tags.contains(tag) {
-            neutral(format!("- {} (default)", tag));
+        let name = if config.default_tags.contains(tag) {
+            format!("{} (default)", tag)
         } else {
-            neutral(format!("- {}", tag));
+            tag.to_string()
+        };
+        if !effective {
+            neutral(format!("- {}", name));
+            continue;
+        }
+        match config.tag_effect(tag, fetch) {
+            Ok((0, 0, 0)) => neutral(format!("- {}: no effect", name)),
+            Ok((added, removed, changed)) => {
+                neutral(format!("- {}: +{} -{} ~{}", name, added, removed, changed))
+            }
+            Err(e) => yellow(format!("- {}: {}", name, e)),
         }
     }
     break_line();
     Ok(())
 }
 
-fn get_active_paths(configpath: &str, tags: &Vec<String>) -> Result<Vec<PathBuf>> {
-    let config = Config::from_general_path(configpath, true, None)?;
-    let mut active_paths = config
-        .get_active(tags)?
+fn print_variables(configpath: &str, fetch: &FetchContext) -> Result<()> {
+    let config = Config::from_general_path(configpath, true, None, fetch)?;
+    break_line();
+    for (key, value, origin) in config.variable_overview() {
+        match origin {
+            Some(o) => neutral(format!("- {} = \"{}\" ({})", key, value, o)),
+            None => neutral(format!("- {} = \"{}\"", key, value)),
+        }
+    }
+    break_line();
+    Ok(())
+}
+
+// Only the tags that are not given are reported, those edits do not run.
+fn warn_edit_only_tags(config: &Config, tags: &Vec<String>) {
+    for (path, number, tag, similar) in config.edit_only_tags() {
+        if !tags.iter().any(|t| t.trim() == tag) {
+            yellow(format!(
+                "Edit {} of {} has the tag {}, which nothing else selects. Did you mean {}? It only runs with -t {}.",
+                number,
+                path.display(),
+                tag,
+                similar,
+                tag
+            ));
+        }
+    }
+}
+
+// Resolves the git ids of all sources of the active files. No file content is read.
+fn print_refs(configpath: &str, tags: &Vec<String>, fetch: &FetchContext) -> Result<()> {
+    let config = Config::from_general_path(configpath, true, None, fetch)?;
+    let mut refs = vecset(
+        config
+            .get_active(tags, fetch)?
+            .iter()
+            .map(|f| f.sources.iter().filter_map(|s| s.git_ref()).collect())
+            .collect(),
+    );
+    refs.sort();
+    break_line();
+    for (repo, id) in refs {
+        let resolved =
+            get_git_repo(&repo, fetch).and_then(|r| get_commit_from_string(&r, &id, fetch));
+        match resolved {
+            Ok(commit) if commit.eq_ignore_ascii_case(id.trim()) => {
+                neutral(format!("- {}#{} (pinned)", repo, id))
+            }
+            Ok(commit) => neutral(format!("- {}#{} -> {}", repo, id, commit)),
+            Err(e) => yellow(format!("- {}#{}: {}", repo, id, e)),
+        }
+    }
+    break_line();
+    Ok(())
+}
+
+fn get_active_paths(
+    config: &Config,
+    tags: &Vec<String>,
+    exclude: &[glob::Pattern],
+    context: &ListContext,
+    fetch: &FetchContext,
+) -> Result<Vec<PathBuf>> {
+    warn_edit_only_tags(config, tags);
+    let active = config.get_active_with(tags, context, fetch)?;
+    let mut active_paths = exclude_files(active, exclude, fetch)
         .iter()
         .map(|f| format_subpath(&f.path))
         .collect::<Vec<PathBuf>>();
@@ -222,8 +898,15 @@ fn get_active_paths(configpath: &str, tags: &Vec<String>) -> Result<Vec<PathBuf>
     Ok(active_paths)
 }
 
-fn print_list(configpath: &str, tags: &Vec<String>) -> Result<()> {
-    let active_paths = get_active_paths(configpath, tags)?;
+fn print_list(
+    configpath: &str,
+    tags: &Vec<String>,
+    exclude: &[glob::Pattern],
+    context: &ListContext,
+    fetch: &FetchContext,
+) -> Result<()> {
+    let config = Config::from_general_path(configpath, true, None, fetch)?;
+    let active_paths = get_active_paths(&config, tags, exclude, context, fetch)?;
     break_line();
     for path in active_paths {
         neutral(format!("- {}", path.display()));
@@ -232,14 +915,281 @@ fn print_list(configpath: &str, tags: &Vec<String>) -> Result<()> {
     Ok(())
 }
 
+fn print_explanation(
+    configpath: &str,
+    path: &PathBuf,
+    given_tags: &Vec<String>,
+    fetch: &FetchContext,
+) -> Result<()> {
+    let config = Config::from_general_path(configpath, true, None, fetch)?;
+    let (tags, candidates) = config.explain(given_tags, path, fetch)?;
+    let path = format_subpath(path);
+    neutral(format!("Active tags: [{}]", tags.join(", ")));
+    break_line();
+    for c in &candidates {
+        let what = match c.file {
+            Some(_) => format!("{} from {}", c.path.display(), c.origin),
+            None => format!("{} at {}", c.origin, c.path.display()),
+        };
+        match c.decision {
+            Decision::Active => green(format!(
+                "{}: active, sources {}",
+                what,
+                c.sources.join(" | ")
+            )),
+            Decision::Inactive => {
+                let negated = c
+                    .tags
+                    .iter()
+                    .filter(|t| given_tags.contains(&format!("!{}", t)))
+                    .map(|t| format!("!{}", t))
+                    .collect::<Vec<_>>();
+                let mut reason = format!(
+                    "{}: inactive, none of its tags [{}] is active",
+                    what,
+                    c.tags.join(", ")
+                );
+                if !negated.is_empty() {
+                    reason.push_str(&format!(" ({} given)", negated.join(", ")));
+                }
+                yellow(reason)
+            }
+            Decision::Shadowed => yellow(format!(
+                "{}: shadowed, it is untagged and an active tagged file has the same path",
+                what
+            )),
+            Decision::OtherPlatform => yellow(format!("{}: skipped on this platform", what)),
+            Decision::Collision => red(format!(
+                "{}: collision, there already is an active file for this path",
+                what
+            )),
+        }
+    }
+    break_line();
+    if candidates.is_empty() {
+        yellow(format!(
+            "No file, inclusion or directory of the config can produce {}.",
+            path.display()
+        ));
+    } else if let Some(active) = candidates.iter().find(|c| c.decision == Decision::Active) {
+        green(format!(
+            "{} is synced from {}.",
+            path.display(),
+            active.origin
+        ));
+    } else {
+        yellow(format!("{} is not synced.", path.display()));
+    }
+    if candidates.iter().any(|c| c.decision == Decision::Collision) {
+        red("Syncing fails, there are two files for this path.");
+    }
+    Ok(())
+}
+
+fn write_bundle(
+    configpath: &str,
+    output: &PathBuf,
+    tags: &Vec<String>,
+    identity_files: &Vec<PathBuf>,
+    fetch: &FetchContext,
+) -> Result<()> {
+    let ids = load_agev1keys(identity_files)?;
+    let conf = Config::from_general_path(configpath, true, None, fetch)?;
+    let options = SyncOptions {
+        fetch: fetch.clone(),
+        ..Default::default()
+    };
+    // The bundle path is used as the reference, so a bundle inside of a local source is caught.
+    let (memfolder, _) = MemFolder::load_active_with_ref(
+        &conf,
+        &without_verify_only(active_files(&conf, tags, &options)?),
+        tags,
+        output,
+        &ids,
+        &options,
+    )?;
+    fs::write(output, memfolder.to_bundle()?)
+        .context(format!("Could not write bundle {}", output.display()))?;
+    green(format!(
+        "Saved {} files as {}",
+        memfolder.paths().len(),
+        output.display()
+    ));
+    Ok(())
+}
+
+// Verify-only files only exist in an output directory, so a bundle or a fingerprint leaves them out.
+fn without_verify_only(active: Vec<File>) -> Vec<File> {
+    active.into_iter().filter(|f| !f.verify_only).collect()
+}
+
+fn print_fingerprint(
+    configpath: &str,
+    tags: &Vec<String>,
+    identity_files: &Vec<PathBuf>,
+    fetch: &FetchContext,
+) -> Result<()> {
+    let ids = load_agev1keys(identity_files)?;
+    let conf = Config::from_general_path(configpath, true, None, fetch)?;
+    let options = SyncOptions {
+        fetch: fetch.clone(),
+        ..Default::default()
+    };
+    // There is no output, so an empty directory is used as the reference.
+    let reference = tempfile::tempdir()?;
+    let (memfolder, _) = MemFolder::load_active_with_ref(
+        &conf,
+        &without_verify_only(active_files(&conf, tags, &options)?),
+        tags,
+        &reference.path().to_path_buf(),
+        &ids,
+        &options,
+    )?;
+    neutral(memfolder.fingerprint()?);
+    Ok(())
+}
+
+fn verify_folder(
+    configpath: &str,
+    output: &PathBuf,
+    tags: &Vec<String>,
+    skip_first: bool,
+    identity_files: &Vec<PathBuf>,
+    fetch: &FetchContext,
+) -> Result<()> {
+    if !output.is_dir() {
+        return Err(format_err!("{} is not a directory.", output.display()));
+    }
+    let ids = load_agev1keys(identity_files)?;
+    let conf = Config::from_general_path(configpath, true, None, fetch)?;
+    // Like sync, files that already match their hash are taken from the output instead of being fetched.
+    let (memfolder, _) = MemFolder::load_first_valid_with_ref(
+        &conf,
+        tags,
+        output,
+        &ids,
+        &SyncOptions {
+            skip_first,
+            fetch: fetch.clone(),
+            ..Default::default()
+        },
+    )?;
+    let diff = memfolder.compare_with_disk(output, skip_first, None)?;
+    for path in &diff.missing {
+        red(format!("missing  {}", path.display()));
+    }
+    for path in &diff.modified {
+        red(format!("modified {}", path.display()));
+    }
+    for path in &diff.extra {
+        yellow(format!("extra    {}", path.display()));
+    }
+    let count = diff.missing.len() + diff.modified.len() + diff.extra.len();
+    if count > 0 {
+        return Err(format_err!(
+            "{} path(s) in {} do not match the config.",
+            count,
+            output.display()
+        ));
+    }
+    green(format!(
+        "All {} files in {} match the config.",
+        memfolder.paths().len(),
+        output.display()
+    ));
+    Ok(())
+}
+
+fn write_config_lock(
+    configpath: &str,
+    output: &PathBuf,
+    tags: &Vec<String>,
+    fetch: &FetchContext,
+) -> Result<()> {
+    // Resolving the files expands all inclusions, which records every loaded config.
+    let config = Config::from_general_path(configpath, true, None, fetch)?;
+    config.get_active(tags, fetch)?;
+    let lock = take_loaded_configs(configpath)?;
+    fs::write(output, toml::to_string(&lock)?)
+        .context(format!("Could not write lockfile {}", output.display()))?;
+    green(format!(
+        "Saved the hashes of {} configs as {}",
+        lock.configs.len(),
+        output.display()
+    ));
+    Ok(())
+}
+
+// Shows what the edits do to the fetched content. Nothing is written.
+fn print_edit_diffs(
+    configpath: &str,
+    tags: &Vec<String>,
+    identity_files: &Vec<PathBuf>,
+    fetch: &FetchContext,
+) -> Result<()> {
+    let ids = load_agev1keys(identity_files)?;
+    let config = Config::from_general_path(configpath, true, None, fetch)?;
+    break_line();
+    for file in config.get_active(tags, fetch)? {
+        if !file.edits.iter().any(|e| e.is_active(tags)) {
+            continue;
+        }
+        let path = file.get_path();
+        let raw = file.fetch_unedited(&ids, false, fetch)?;
+        let Ok(raw_text) = String::from_utf8(raw.clone()) else {
+            yellow(format!("{}: skipped, not utf8", path.display()));
+            continue;
+        };
+        let edited = String::from_utf8(file.from_reference_unchecked(&raw, tags, fetch)?)?;
+        if raw_text == edited {
+            neutral(format!("{}: the edits change nothing", path.display()));
+            continue;
+        }
+        let diff = similar::TextDiff::from_lines(&raw_text, &edited);
+        let unified = diff
+            .unified_diff()
+            .header(
+                &format!("a/{}", path.display()),
+                &format!("b/{}", path.display()),
+            )
+            .to_string();
+        for line in unified.lines() {
+            if line.starts_with("+++") || line.starts_with("---") {
+                neutral(line)
+            } else if line.starts_with('+') {
+                green(line)
+            } else if line.starts_with('-') {
+                println!("{}", line.red())
+            } else {
+                neutral(line)
+            }
+        }
+    }
+    break_line();
+    Ok(())
+}
+
 fn clean_command(
     configpath: &str,
     output: &PathBuf,
     tags: &Vec<String>,
     skip_first: bool,
     no_confirm: bool,
+    dry_run: bool,
+    fetch: &FetchContext,
 ) -> Result<()> {
+    let config = Config::from_general_path(configpath, true, None, fetch)?;
+    // The same directory as for sync, so the output path gets the variables filled in.
+    let output = &config.output_with_variables(output, tags, fetch)?;
     if !skip_first {
+        if dry_run {
+            break_line();
+            if output.exists() {
+                neutral(format!("- {}", output.display()));
+            }
+            break_line();
+            return Ok(());
+        }
         if !no_confirm {
             let prompt = format!(
                 "This will delete the directory {}",
@@ -251,9 +1201,17 @@ fn clean_command(
             };
         }
         fs::remove_dir_all(output)?;
-        return Ok(());
+        Ok(())
     } else {
-        let all_paths = get_active_paths(configpath, tags)?;
+        // Verify-only files are not ours to delete, not even inside of a tracked directory.
+        let kept = config
+            .get_active(tags, fetch)?
+            .iter()
+            .filter(|f| f.verify_only)
+            .map(|f| format_subpath(&f.path))
+            .collect::<Vec<_>>();
+        let mut all_paths = get_active_paths(&config, tags, &[], &ListContext::default(), fetch)?;
+        all_paths.retain(|p| !kept.contains(p));
         if !all_paths.iter().all(|p| p.is_relative()) {
             return Err(format_err!(
                 "List of paths to delete contains absolute path"
@@ -269,6 +1227,16 @@ fn clean_command(
                 )
             })
             .collect::<Vec<_>>()]);
+        if dry_run {
+            let mut existing = to_delete.iter().filter(|f| f.exists()).collect::<Vec<_>>();
+            existing.sort();
+            break_line();
+            for f in existing {
+                neutral(format!("- {}", f.display()));
+            }
+            break_line();
+            return Ok(());
+        }
         if !no_confirm {
             let list = to_delete
                 .iter()
@@ -287,18 +1255,62 @@ fn clean_command(
                 yellow(format!("Skipping missing path {}", f.display()));
                 continue;
             }
-            if f.is_file() {
-                _ = fs::remove_file(f)?;
-            } else {
-                fs::remove_dir_all(f)?;
-            }
+            remove_except(&f, output, &kept)?;
         }
         Ok(())
     }
 }
 
-fn clean_cache_dir() -> Result<()> {
+// Removes exactly what the last sync with --managed-list wrote. The config is not needed.
+fn clean_managed_files(output: &PathBuf, no_confirm: bool, dry_run: bool) -> Result<()> {
+    let output = &fill_in_output_path(output, &HashMap::new())?;
+    if !output.join(MANAGED_LIST).is_file() {
+        return Err(format_err!(
+            "There is no list of managed files in {}. It is written by sync --managed-list.",
+            output.display()
+        ));
+    }
+    let mut managed = read_managed_list(output)?
+        .into_iter()
+        .filter(|p| output.join(p).is_file())
+        .collect::<Vec<_>>();
+    managed.sort();
+    if dry_run {
+        break_line();
+        for p in &managed {
+            neutral(format!("- {}", output.join(p).display()));
+        }
+        break_line();
+        return Ok(());
+    }
+    if !no_confirm {
+        let list = managed
+            .iter()
+            .map(|p| format!("- {}", output.join(p).display()))
+            .collect::<Vec<String>>()
+            .join("\n");
+        let prompt = format!("The files:\n{}\nWill be deleted!\nIs that OK?", list);
+        match Confirm::new().with_prompt(prompt).report(false).interact() {
+            Ok(true) => {}
+            _ => return Err(format_err!("Not confirmed")),
+        };
+    }
+    for p in &managed {
+        remove_managed_file(output, p)?;
+    }
+    fs::remove_file(output.join(MANAGED_LIST)).context("Could not remove the list of managed files")
+}
+
+fn clean_cache_dir(keep: bool) -> Result<()> {
     match CACHEDIR.get() {
+        // On stderr, so it does not end up in the output of show.
+        Some(cd) if keep => {
+            eprintln!(
+                "{}",
+                format!("The cache directory is kept at {}", cd.path().display()).yellow()
+            );
+            Ok(())
+        }
         Some(cd) => {
             fs::remove_dir_all(cd.path())?;
             Ok(())
@@ -306,25 +1318,24 @@ fn clean_cache_dir() -> Result<()> {
         None => Ok(()),
     }
 }
+// Messages can contain sources and paths that were built from secret variables.
 pub fn yellow(warning: impl AsRef<str>) {
-    println!("{}", warning.as_ref().yellow());
+    println!("{}", hide_secrets(warning.as_ref()).yellow());
 }
 pub fn red(error: impl AsRef<str>) {
-    eprintln!("{}", error.as_ref().red());
+    eprintln!("{}", hide_secrets(error.as_ref()).red());
 }
 pub fn green(message: impl AsRef<str>) {
-    println!("{}", message.as_ref().green());
+    println!("{}", hide_secrets(message.as_ref()).green());
 }
 pub fn neutral(message: impl AsRef<str>) {
-    println!("{}", message.as_ref());
+    println!("{}", hide_secrets(message.as_ref()));
 }
 pub fn break_line() {
     let columns = terminal_size().unwrap_or((5, 5)).0;
     println!(
         "{}",
-        std::iter::repeat('-')
-            .take(columns as usize)
-            .collect::<String>()
+        std::iter::repeat_n('-', columns as usize).collect::<String>()
     );
 }
 
@@ -335,4 +1346,18 @@ mod test {
     fn test_example_config() {
         let _conf: Config = toml::from_str(include_str!("lorevault_example.toml")).unwrap();
     }
+    #[test]
+    fn test_example_force() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("myvault.toml");
+        write_example_config(&path, false).unwrap();
+        fs::write(&path, "changed").unwrap();
+        assert!(write_example_config(&path, false).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "changed");
+        write_example_config(&path, true).unwrap();
+        assert_eq!(
+            fs::read_to_string(&path).unwrap(),
+            include_str!("lorevault_example.toml")
+        );
+    }
 }
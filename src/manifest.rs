@@ -0,0 +1,336 @@
+#![allow(clippy::needless_return)]
+use crate::*;
+
+// A table (csv or tsv) with the columns path, source and optionally hash. Every row is a file.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    // The folder the paths of the rows are relative to.
+    #[serde(default)]
+    path: PathBuf,
+    #[serde(rename = "sources", alias = "source")]
+    sources: Vec<FileSource>,
+    hash: Option<String>, // Of the table itself.
+    // Tab for sources ending in .tsv, comma otherwise.
+    delimiter: Option<char>,
+    tags: Option<Vec<String>>,
+    #[serde(default)]
+    os: Vec<String>,
+    #[serde(default)]
+    arch: Vec<String>,
+    #[serde(skip)]
+    pub variables: HashMap<String, String>, // The sources in the table can use the variables of the config.
+}
+
+impl Manifest {
+    pub fn read_text_files(&mut self, vars: &HashMap<String, String>) -> Result<()> {
+        self.sources = self
+            .sources
+            .iter()
+            .map(|s| s.with_text_file(vars))
+            .collect::<Result<_>>()?;
+        Ok(())
+    }
+    pub fn get_tags(&self) -> Vec<String> {
+        self.tags.clone().unwrap_or(vec![])
+    }
+    pub fn get_path(&self) -> PathBuf {
+        format_subpath(&self.path)
+    }
+    pub fn platform_matches(&self) -> bool {
+        platform_matches(&self.os, &self.arch)
+    }
+    pub fn is_active(&self, tags: &Vec<String>) -> bool {
+        self.get_tags().is_empty() || self.get_tags().iter().any(|t| tags.contains(t))
+    }
+    pub fn remote_repos(&self) -> Vec<String> {
+        self.sources
+            .iter()
+            .filter_map(|s| s.remote_repo())
+            .collect()
+    }
+
+    pub fn get_all_files(&self, fetch: &FetchContext) -> Result<Vec<File>> {
+        let mut failed = vec![];
+        let (index, data) = fetch_first_valid(
+            &self.sources,
+            &self.hash,
+            Compression::None,
+            &mut failed,
+            fetch,
+        )
+        .map_err(|e| match e.is::<FloatingRef>() {
+            true => e,
+            false => format_err!("No valid source for manifest {}", self.sources_text()),
+        })?;
+        let source = &self.sources[index];
+        let name = source.to_string();
+        let delimiter = match self.delimiter {
+            Some(d) => d,
+            None if name.ends_with(".tsv") => '\t',
+            None => ',',
+        };
+        let text = String::from_utf8(data).context(format!("The manifest {} is not utf8", name))?;
+        let rows = parse_manifest(&text, delimiter)
+            .map_err(|e| format_err!("Invalid manifest {}: {}", name, e))?;
+        fetch.trace(|| format!("manifest {}: {} row(s)", name, rows.len()));
+        let tags = self.tags.clone();
+        let mut files = vec![];
+        for row in rows {
+            let source = FileSource::Auto(row.source)
+                .set_variables(&self.variables)
+                .map_err(|e| format_err!("Invalid manifest {}: line {}: {}", name, row.line, e))?;
+            files.push(File {
+                path: self.path.join(row.path),
+                tags: tags.clone(),
+                hash: row.hash,
+                hash_source: None,
+                sources: vec![source],
+                edits: vec![],
+                decrypt: DecryptionMethod::None,
+                decompress: Compression::None,
+                signature: None,
+                public_key: None,
+                optional: false,
+                verify_only: false,
+                os: vec![],
+                arch: vec![],
+            })
+        }
+        if files.is_empty() {
+            return Err(format_err!("The manifest {} has no rows", name));
+        }
+        Ok(files)
+    }
+
+    fn sources_text(&self) -> String {
+        self.sources
+            .iter()
+            .map(|s| s.to_string())
+            .collect::<Vec<_>>()
+            .join(" | ")
+    }
+}
+
+#[derive(Debug, PartialEq)]
+struct ManifestRow {
+    line: usize,
+    path: PathBuf,
+    source: String,
+    hash: Option<String>,
+}
+
+// The first record is the header. Columns other than path, source and hash are ignored.
+fn parse_manifest(text: &str, delimiter: char) -> Result<Vec<ManifestRow>> {
+    let mut records = parse_records(text, delimiter)?.into_iter();
+    let (_, header) = records.next().context("The header row is missing")?;
+    let column = |name: &str| {
+        header
+            .iter()
+            .position(|h| h.trim().eq_ignore_ascii_case(name))
+    };
+    let path_column = column("path").context("The header has no path column")?;
+    let source_column = column("source").context("The header has no source column")?;
+    let hash_column = column("hash");
+    let mut rows = vec![];
+    for (line, record) in records {
+        if record.len() != header.len() {
+            return Err(format_err!(
+                "line {}: expected {} fields like the header, found {}",
+                line,
+                header.len(),
+                record.len()
+            ));
+        }
+        let path = PathBuf::from(record[path_column].trim());
+        let source = record[source_column].trim().to_string();
+        if path.as_os_str().is_empty() || source.is_empty() {
+            return Err(format_err!(
+                "line {}: the path and source can not be empty",
+                line
+            ));
+        }
+        if path.is_absolute()
+            || path
+                .components()
+                .any(|c| c == std::path::Component::ParentDir)
+        {
+            return Err(format_err!(
+                "line {}: the path {} must stay inside of the folder",
+                line,
+                path.display()
+            ));
+        }
+        let hash = match hash_column.map(|c| record[c].trim()) {
+            None | Some("") => None,
+            Some(h) => {
+                Some(HashAlgo::normalize(h).map_err(|e| format_err!("line {}: {}", line, e))?)
+            }
+        };
+        rows.push(ManifestRow {
+            line,
+            path,
+            source,
+            hash,
+        });
+    }
+    Ok(rows)
+}
+
+// Splits the text into records of fields and the line each record starts on. Empty lines are skipped.
+// Fields can be quoted with ", then they can contain the delimiter, line breaks and "" for a quote.
+fn parse_records(text: &str, delimiter: char) -> Result<Vec<(usize, Vec<String>)>> {
+    let mut records = vec![];
+    let mut fields = vec![];
+    let mut field = String::new();
+    let mut line = 1;
+    let mut start = 1;
+    let mut chars = text.chars().peekable();
+    let mut quoted = false;
+    let mut was_quoted = false;
+    while let Some(c) = chars.next() {
+        if quoted {
+            match c {
+                '"' if chars.peek() == Some(&'"') => {
+                    chars.next();
+                    field.push('"');
+                }
+                '"' => quoted = false,
+                '\n' => {
+                    line += 1;
+                    field.push(c);
+                }
+                _ => field.push(c),
+            }
+            continue;
+        }
+        match c {
+            '"' if field.trim().is_empty() && !was_quoted => {
+                field.clear();
+                quoted = true;
+                was_quoted = true;
+            }
+            c if c == delimiter => {
+                fields.push(std::mem::take(&mut field));
+                was_quoted = false;
+            }
+            '\r' if chars.peek() == Some(&'\n') => {}
+            '\n' => {
+                fields.push(std::mem::take(&mut field));
+                if fields.len() > 1 || !fields[0].trim().is_empty() || was_quoted {
+                    records.push((start, std::mem::take(&mut fields)));
+                }
+                fields.clear();
+                was_quoted = false;
+                line += 1;
+                start = line;
+            }
+            _ if was_quoted && !c.is_whitespace() => {
+                return Err(format_err!(
+                    "line {}: unexpected {} after a quoted field",
+                    line,
+                    c
+                ));
+            }
+            _ => field.push(c),
+        }
+    }
+    if quoted {
+        return Err(format_err!("line {}: the quote is never closed", start));
+    }
+    fields.push(field);
+    if fields.len() > 1 || !fields[0].trim().is_empty() || was_quoted {
+        records.push((start, fields));
+    }
+    Ok(records)
+}
+
+impl VariableCompletion for Manifest {
+    fn required_variables(&self) -> Result<Vec<String>> {
+        Ok(vecset(vec![
+            self.sources.required_variables()?,
+            self.path.required_variables()?,
+        ]))
+    }
+    fn set_single_variable(&mut self, key: &str, value: &str) -> Result<Self> {
+        *self = Manifest {
+            path: self.path.set_single_variable(key, value)?,
+            sources: self.sources.set_single_variable(key, value)?,
+            ..self.clone()
+        };
+        return Ok(self.to_owned());
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_parse_records() {
+        let records = parse_records(
+            "path,source\r\n\"a, b.txt\",\"say \"\"hi\"\"\"\n\n\"multi\nline\",x\n",
+            ',',
+        )
+        .unwrap();
+        assert_eq!(
+            records,
+            vec![
+                (1, vec!["path".to_string(), "source".to_string()]),
+                (2, vec!["a, b.txt".to_string(), "say \"hi\"".to_string()]),
+                (4, vec!["multi\nline".to_string(), "x".to_string()]),
+            ]
+        );
+        assert_eq!(
+            parse_records("a\tb", '\t').unwrap(),
+            vec![(1, vec!["a".to_string(), "b".to_string()])]
+        );
+        assert!(parse_records("a,\"b", ',').is_err());
+        assert!(parse_records("a,\"b\"c", ',').is_err());
+    }
+
+    #[test]
+    fn test_parse_manifest() {
+        let hash = "d75ed77277f0999e2b27777eaedadb063740863da2be34adaced8468761682a7";
+        let rows = parse_manifest(
+            &format!(
+                "Source,Notes,Path,Hash\nfile1.txt,,a.txt,{}\nx,y,sub/b.txt,\n",
+                hash
+            ),
+            ',',
+        )
+        .unwrap();
+        assert_eq!(
+            rows,
+            vec![
+                ManifestRow {
+                    line: 2,
+                    path: PathBuf::from("a.txt"),
+                    source: "file1.txt".to_string(),
+                    hash: Some(hash.to_uppercase()),
+                },
+                ManifestRow {
+                    line: 3,
+                    path: PathBuf::from("sub/b.txt"),
+                    source: "x".to_string(),
+                    hash: None,
+                },
+            ]
+        );
+        let error = |text: &str| parse_manifest(text, ',').unwrap_err().to_string();
+        assert_eq!(
+            error("path,source\na.txt,x,y"),
+            "line 2: expected 2 fields like the header, found 3"
+        );
+        assert_eq!(
+            error("path,hash\na.txt,x"),
+            "The header has no source column"
+        );
+        assert_eq!(
+            error("path,source,hash\na.txt,x,1234"),
+            "line 2: 1234 is not a SHA3-256 hash in hex"
+        );
+        assert!(parse_manifest("path,source\n../a.txt,x", ',').is_err());
+        assert!(parse_manifest("path,source\n,x", ',').is_err());
+    }
+}
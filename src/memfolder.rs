@@ -1,52 +1,121 @@
+#![allow(
+    clippy::len_zero,
+    clippy::match_like_matches_macro,
+    clippy::needless_borrows_for_generic_args,
+    clippy::needless_return,
+    clippy::redundant_closure
+)]
 use crate::*;
-pub struct MemFolder(pub HashMap<PathBuf, Vec<u8>>);
+
+// The sidecar file listing all paths lorevault wrote into the output.
+pub const MANAGED_LIST: &str = ".lorevault-managed";
+const MANAGED_LIST_HEADER: &str = "# lorevault managed files v1";
+
+// A bundle starts with the magic line and the hash line, followed by the entries sorted by the bytes of their path.
+// Each entry is the length of the path, the path (utf8, with /), the length of the content and the content.
+// The lengths are 8 byte big-endian integers. The hash is the SHA3-256 hash of everything after the hash line.
+// There are no timestamps or permissions, so the same files always give the same bundle.
+const BUNDLE_MAGIC: &str = "LOREVAULT-BUNDLE v1";
+
+// The last field holds the verify-only files. They were checked on disk and are never written or removed.
+pub struct MemFolder(pub HashMap<PathBuf, Vec<u8>>, Option<Spill>, Vec<PathBuf>);
+
+// The differences between the folder and a directory on disk, all relative to the directory.
+#[derive(Debug, Default, PartialEq)]
+pub struct DiskDiff {
+    pub missing: Vec<PathBuf>,
+    pub extra: Vec<PathBuf>,
+    pub modified: Vec<PathBuf>,
+}
+
+impl DiskDiff {
+    pub fn is_empty(&self) -> bool {
+        self.missing.is_empty() && self.extra.is_empty() && self.modified.is_empty()
+    }
+}
+
+// Files that were moved out of memory to stay below the memory budget.
+// They wait in a directory next to the output, so the output is still only touched once everything is built.
+struct Spill {
+    dir: TempDir,
+    paths: Vec<PathBuf>,
+}
 
 impl MemFolder {
     pub fn empty() -> Self {
-        return MemFolder(HashMap::new());
+        return MemFolder(HashMap::new(), None, vec![]);
     }
 
     pub fn load_first_valid_with_ref(
         conf: &Config,
         tags: &Vec<String>,
         reference: &PathBuf,
-        ids: &Vec<age::x25519::Identity>,
-    ) -> Result<Self> {
+        ids: &Vec<AgeIdentity>,
+        options: &SyncOptions,
+    ) -> Result<(Self, Vec<(PathBuf, Error)>)> {
+        let active = active_files(conf, tags, options)?;
+        Self::load_active_with_ref(conf, &active, tags, reference, ids, options)
+    }
+
+    // Like load_first_valid_with_ref, but the active files were already resolved.
+    pub fn load_active_with_ref(
+        conf: &Config,
+        active: &Vec<File>,
+        tags: &Vec<String>,
+        reference: &PathBuf,
+        ids: &Vec<AgeIdentity>,
+        options: &SyncOptions,
+    ) -> Result<(Self, Vec<(PathBuf, Error)>)> {
         let mut memfolder = MemFolder::empty();
-        for item in &conf.get_active(tags)? {
+        let mut failures = vec![];
+        let mem_budget = options.mem_budget.map(|mb| mb * 1024 * 1024);
+        check_source_overlap(
+            active,
+            &conf.local_directory_roots(),
+            reference,
+            options.skip_first,
+        )?;
+        for item in active {
             if contains_parent_dir(&item.get_path()) {
                 return Err(format_err!(
                     "Escaping the current folder (..) is not allowed."
                 ));
             }
-            let mut ref_path = reference.clone();
-            ref_path.push(item.get_path());
-            if let (Some(reqhash), Ok(content)) = (&item.hash, fs::read(ref_path)) {
-                let hash = compute_hash(&content);
-                if &hash == reqhash {
-                    memfolder.0.insert(
-                        item.get_path().clone(),
-                        item.from_reference_unchecked(&content, tags)?,
-                    );
-                } else {
-                    memfolder
-                        .0
-                        .insert(item.get_path().clone(), item.build(tags, ids)?);
+            if item.verify_only {
+                match verify_on_disk(item, &reference.join(item.get_path()), &options.fetch) {
+                    Ok(()) => memfolder.2.push(format_subpath(&item.get_path())),
+                    Err(e) if options.keep_going => failures.push((item.get_path(), e)),
+                    Err(e) => return Err(e),
                 }
-            } else {
-                memfolder
-                    .0
-                    .insert(item.get_path().clone(), item.build(tags, ids)?);
+                continue;
+            }
+            match load_with_ref(item, tags, reference, ids, options) {
+                Ok(content) => {
+                    memfolder.0.insert(item.get_path().clone(), content);
+                    if let Some(budget) = mem_budget {
+                        if memfolder.size_in_bytes() > budget {
+                            memfolder.spill(reference)?;
+                        }
+                    }
+                }
+                Err(e) if item.optional && e.is::<Unreachable>() => {
+                    yellow(format!(
+                        "Skipping optional file {}, no source is reachable.",
+                        item.get_path().display()
+                    ));
+                }
+                Err(e) if options.keep_going => failures.push((item.get_path(), e)),
+                Err(e) => return Err(e),
             }
         }
 
-        Ok(memfolder)
+        Ok((memfolder, failures))
     }
 
-    pub fn write_to_folder(&self, out_path: &PathBuf) -> Result<()> {
+    pub fn write_to_folder(&self, out_path: &PathBuf, options: &SyncOptions) -> Result<()> {
         if out_path.exists() {
             if out_path.is_dir() {
-                fs::remove_dir_all(&out_path).context(format!(
+                remove_except(out_path, out_path, &self.2).context(format!(
                     "Could not remove the directory {}.",
                     out_path.display()
                 ))?;
@@ -57,14 +126,20 @@ impl MemFolder {
                 ));
             }
         }
-        fs::create_dir(out_path)
-            .context("Could not create output folder. Maybe its parent does not exist?")?;
+        if !out_path.exists() {
+            fs::create_dir(out_path)
+                .context("Could not create output folder. Maybe its parent does not exist?")?;
+        }
 
-        self.write_into(out_path)?;
+        self.write_into(out_path, options)?;
         Ok(())
     }
 
-    pub fn write_to_folder_skip_first(&self, out_path: &PathBuf) -> Result<()> {
+    pub fn write_to_folder_skip_first(
+        &self,
+        out_path: &PathBuf,
+        options: &SyncOptions,
+    ) -> Result<()> {
         if out_path.exists() {
             if out_path.is_dir() {
                 for tracked in self.tracked_subpaths()? {
@@ -75,7 +150,7 @@ impl MemFolder {
                         continue;
                     }
                     if tracked_path.is_dir() {
-                        fs::remove_dir_all(&tracked_path).context(format!(
+                        remove_except(&tracked_path, out_path, &self.2).context(format!(
                             "Could not remove directory {}.",
                             tracked_path.display()
                         ))?;
@@ -102,26 +177,180 @@ impl MemFolder {
                 .context("Could not create output folder. Maybe its parent does not exist?")?;
         }
 
-        self.write_into(out_path)?;
+        self.write_into(out_path, options)?;
         Ok(())
     }
 
-    fn write_into(&self, out_path: &PathBuf) -> Result<()> {
-        for (subpath, content) in &self.0 {
-            let mut target_path = out_path.clone();
-            let subpath = format_subpath(subpath);
-            target_path.push(subpath);
-            let prefix = target_path.parent().context("Malformed path")?;
-            fs::create_dir_all(prefix).context("Path could not be created")?;
-            let mut _file = std::fs::File::create(&target_path)?;
-            fs::write(target_path, content).context("Could not write file")?;
+    // Like write_to_folder_skip_first, but the tracked paths are not cleared first.
+    // Only what is in the way of a new file is removed.
+    pub fn write_to_folder_file_by_file(
+        &self,
+        out_path: &PathBuf,
+        options: &SyncOptions,
+    ) -> Result<()> {
+        if !out_path.exists() {
+            fs::create_dir(out_path)
+                .context("Could not create output folder. Maybe its parent does not exist?")?;
+        } else if !out_path.is_dir() {
+            return Err(format_err!(
+                "Path {} exists, but it is not a directory.",
+                out_path.display()
+            ));
+        }
+        for subpath in self.paths() {
+            let target = out_path.join(format_subpath(&subpath));
+            // A file where a directory is needed.
+            for parent in target.ancestors().skip(1) {
+                if parent == out_path.as_path() {
+                    break;
+                }
+                if parent.is_file() {
+                    fs::remove_file(parent)
+                        .context(format!("Could not remove file {}.", parent.display()))?;
+                }
+            }
+            if target.is_dir() {
+                fs::remove_dir_all(&target)
+                    .context(format!("Could not remove directory {}.", target.display()))?;
+            }
+        }
+        self.write_into(out_path, options)
+    }
+
+    // Files on disk inside of the tracked paths that are not part of the folder (relative to the output).
+    pub fn untracked_files(&self, out_path: &PathBuf) -> Result<Vec<PathBuf>> {
+        let paths = self
+            .paths()
+            .iter()
+            .map(|p| format_subpath(p))
+            .collect::<Vec<_>>();
+        let mut untracked = vec![];
+        for tracked in self.tracked_subpaths()? {
+            let on_disk = out_path.join(&tracked);
+            if !on_disk.is_dir() {
+                continue;
+            }
+            for file in files_below(&on_disk)? {
+                let relative = file.strip_prefix(out_path)?.to_path_buf();
+                if !paths.contains(&relative) && !self.2.contains(&relative) {
+                    untracked.push(relative);
+                }
+            }
+        }
+        untracked.sort();
+        Ok(untracked)
+    }
+
+    // With --dedup-links, the first file with some content is written and the others are hard links to it.
+    fn write_into(&self, out_path: &PathBuf, options: &SyncOptions) -> Result<()> {
+        let dedup = options.dedup_links;
+        let mut written: HashMap<String, PathBuf> = HashMap::new();
+        let mut in_memory = self.0.iter().collect::<Vec<_>>();
+        in_memory.sort_by(|a, b| a.0.cmp(b.0));
+        for (subpath, content) in in_memory {
+            let target_path = out_path.join(format_subpath(subpath));
+            if dedup {
+                let hash = compute_hash(content);
+                if let Some(first) = written.get(&hash) {
+                    if link_file(first, &target_path).is_ok() {
+                        continue;
+                    }
+                } else {
+                    written.insert(hash, target_path);
+                }
+            }
+            write_file(
+                out_path,
+                subpath,
+                content,
+                options.default_mode,
+                options.durable,
+            )?;
+        }
+        if let Some(spill) = &self.1 {
+            for subpath in &spill.paths {
+                let staged = spill.dir.path().join(format_subpath(subpath));
+                let target_path = out_path.join(format_subpath(subpath));
+                if dedup {
+                    let hash = compute_hash_reader(fs::File::open(&staged)?)?;
+                    if let Some(first) = written.get(&hash) {
+                        if link_file(first, &target_path).is_ok() {
+                            continue;
+                        }
+                    } else {
+                        written.insert(hash, target_path.clone());
+                    }
+                }
+                let prefix = target_path.parent().context("Malformed path")?;
+                fs::create_dir_all(prefix).context("Path could not be created")?;
+                let mode = options.default_mode;
+                if options.durable {
+                    move_durably(&staged, &target_path, mode)?;
+                    continue;
+                }
+                if fs::rename(&staged, &target_path).is_err() {
+                    fs::copy(&staged, &target_path).context("Could not write file")?;
+                }
+                set_mode(&target_path, mode)?;
+            }
         }
         Ok(())
     }
 
+    // Writes all files that are currently in memory to the spill directory and frees them.
+    fn spill(&mut self, output: &PathBuf) -> Result<()> {
+        if self.1.is_none() {
+            let parent = absolute_path(output)
+                .and_then(|p| p.parent().map(|p| p.to_path_buf()))
+                .context("Could not find the parent of the output folder.")?;
+            let dir = tempfile::Builder::new()
+                .prefix(".lorevault-spill")
+                .tempdir_in(&parent)
+                .context(format!(
+                    "Could not create a spill directory in {}.",
+                    parent.display()
+                ))?;
+            self.1 = Some(Spill { dir, paths: vec![] });
+        }
+        let spill = self.1.as_mut().expect("spill was just created");
+        for (subpath, content) in self.0.drain() {
+            write_file(
+                &spill.dir.path().to_path_buf(),
+                &subpath,
+                &content,
+                None,
+                false,
+            )?;
+            spill.paths.push(subpath);
+        }
+        Ok(())
+    }
+
+    // All paths, whether they are in memory or spilled.
+    pub fn paths(&self) -> Vec<PathBuf> {
+        let mut paths = self.0.keys().cloned().collect::<Vec<_>>();
+        if let Some(spill) = &self.1 {
+            paths.extend(spill.paths.iter().cloned());
+        }
+        paths
+    }
+
+    // Drops all files that are not inside one of the given tracked paths.
+    pub fn retain_tracked(&mut self, keep: &Vec<PathBuf>) {
+        let is_kept = |p: &PathBuf| {
+            p.components()
+                .next()
+                .is_some_and(|first| keep.contains(&PathBuf::from(first.as_os_str())))
+        };
+        self.0.retain(|p, _| is_kept(p));
+        if let Some(spill) = &mut self.1 {
+            spill.paths.retain(is_kept);
+        }
+    }
+
     pub fn tracked_subpaths(&self) -> Result<Vec<PathBuf>> {
         let mut firsts = vec![];
-        for k in self.0.keys() {
+        for k in self.paths() {
             let mut path = PathBuf::new();
             path.push(k.components().next().context("Empty path")?);
             if firsts.contains(&path) {
@@ -132,14 +361,922 @@ impl MemFolder {
         Ok(firsts)
     }
 
-    #[allow(unused)]
+    // True if writing the folder would not change anything on disk.
+    pub fn matches_disk(
+        &self,
+        out_path: &PathBuf,
+        skip_first: bool,
+        mode: Option<u32>,
+    ) -> Result<bool> {
+        if !out_path.is_dir() {
+            return Ok(false);
+        }
+        Ok(self
+            .compare_with_disk(out_path, skip_first, mode)?
+            .is_empty())
+    }
+
+    // With skip_first, only the tracked paths are compared, everything else is left alone anyway.
+    // The mode of the files is only compared with a default mode.
+    pub fn compare_with_disk(
+        &self,
+        out_path: &PathBuf,
+        skip_first: bool,
+        mode: Option<u32>,
+    ) -> Result<DiskDiff> {
+        let mut diff = DiskDiff::default();
+        let mut on_disk = vec![];
+        if skip_first {
+            for tracked in self.tracked_subpaths()? {
+                let tracked_path = out_path.join(tracked);
+                match fs::symlink_metadata(&tracked_path) {
+                    Ok(meta) if meta.is_dir() => on_disk.append(&mut files_below(&tracked_path)?),
+                    Ok(_) => on_disk.push(tracked_path),
+                    Err(_) => {}
+                }
+            }
+        } else if out_path.is_dir() {
+            on_disk = files_below(out_path)?;
+            on_disk.retain(|p| p != &out_path.join(MANAGED_LIST));
+        }
+        let on_disk = on_disk
+            .iter()
+            .map(|p| p.strip_prefix(out_path).map(|p| p.to_path_buf()))
+            .collect::<Result<Vec<_>, _>>()?;
+        let mut paths = self.paths();
+        paths.sort();
+        let subpaths = paths.iter().map(format_subpath).collect::<Vec<_>>();
+        diff.extra = on_disk
+            .into_iter()
+            .filter(|p| !subpaths.contains(p) && !self.2.contains(p))
+            .collect();
+        diff.extra.sort();
+        for path in paths {
+            let subpath = format_subpath(&path);
+            let target_path = out_path.join(&subpath);
+            // A symlink would be replaced by a regular file.
+            let meta = match fs::symlink_metadata(&target_path) {
+                Ok(meta) => meta,
+                Err(_) => {
+                    diff.missing.push(subpath);
+                    continue;
+                }
+            };
+            if !meta.is_file() || !mode_matches(&meta, mode) {
+                diff.modified.push(subpath);
+                continue;
+            }
+            let expected = match self.0.get(&path) {
+                Some(content) => compute_hash(content),
+                None => {
+                    let spill = self
+                        .1
+                        .as_ref()
+                        .context("File is neither in memory nor spilled")?;
+                    compute_hash_reader(fs::File::open(spill.dir.path().join(&subpath))?)?
+                }
+            };
+            if compute_hash_reader(fs::File::open(&target_path)?)? != expected {
+                diff.modified.push(subpath);
+            }
+        }
+        Ok(diff)
+    }
+
+    // The list has a fixed header line followed by one relative path per line in sorted order.
+    pub fn write_managed_list(&self, out_path: &PathBuf) -> Result<()> {
+        let paths = self.paths();
+        if paths.contains(&PathBuf::from(MANAGED_LIST)) {
+            return Err(format_err!(
+                "The config itself defines {}, so it can not be used as the list of managed files.",
+                MANAGED_LIST
+            ));
+        }
+        let mut paths = paths
+            .iter()
+            .map(|p| format_subpath(p).display().to_string())
+            .collect::<Vec<_>>();
+        paths.sort();
+        let mut content = format!("{}\n", MANAGED_LIST_HEADER);
+        for p in paths {
+            content.push_str(&p);
+            content.push('\n');
+        }
+        fs::write(out_path.join(MANAGED_LIST), content)
+            .context("Could not write list of managed files")
+    }
+
+    // Removes files that were managed according to the previous list, but are no longer part of the folder.
+    pub fn remove_no_longer_managed(
+        &self,
+        out_path: &PathBuf,
+        previous: &Vec<PathBuf>,
+    ) -> Result<()> {
+        let paths = self.paths();
+        for p in previous {
+            if paths.contains(p) || self.2.contains(p) || !out_path.join(p).is_file() {
+                continue;
+            }
+            yellow(format!(
+                "Removing no longer managed file {}",
+                out_path.join(p).display()
+            ));
+            remove_managed_file(out_path, p)?;
+        }
+        Ok(())
+    }
+
+    pub fn to_bundle(&self) -> Result<Vec<u8>> {
+        let mut entries = vec![];
+        for path in self.paths() {
+            let name = format_subpath(&path)
+                .to_str()
+                .context("Paths in a bundle must be utf8")?
+                .replace('\\', "/");
+            entries.push((name, path));
+        }
+        entries.sort();
+        let mut body = vec![];
+        for (name, path) in entries {
+            let content = match self.0.get(&path) {
+                Some(c) => c.clone(),
+                None => {
+                    let spill = self
+                        .1
+                        .as_ref()
+                        .context("File is neither in memory nor spilled")?;
+                    fs::read(spill.dir.path().join(format_subpath(&path)))?
+                }
+            };
+            let path_bytes = name.into_bytes();
+            body.extend((path_bytes.len() as u64).to_be_bytes());
+            body.extend(path_bytes);
+            body.extend((content.len() as u64).to_be_bytes());
+            body.extend(content);
+        }
+        let mut bundle =
+            format!("{}\nsha3-256 {}\n", BUNDLE_MAGIC, compute_hash(&body)).into_bytes();
+        bundle.extend(body);
+        Ok(bundle)
+    }
+
+    // One hash for the whole folder. Every file contributes the line "<HASH> <path>\n",
+    // with the path relative and separated by /. The lines are sorted by path and the
+    // fingerprint is the hash of all of them together.
+    pub fn fingerprint(&self) -> Result<String> {
+        let mut entries = vec![];
+        for path in self.paths() {
+            let name = format_subpath(&path)
+                .to_str()
+                .context("Paths must be utf8 to be fingerprinted")?
+                .replace('\\', "/");
+            let hash = match self.0.get(&path) {
+                Some(content) => compute_hash(content),
+                None => {
+                    let spill = self
+                        .1
+                        .as_ref()
+                        .context("File is neither in memory nor spilled")?;
+                    compute_hash_reader(fs::File::open(
+                        spill.dir.path().join(format_subpath(&path)),
+                    )?)?
+                }
+            };
+            entries.push((name, hash));
+        }
+        entries.sort();
+        let listing = entries
+            .iter()
+            .map(|(name, hash)| format!("{} {}\n", hash, name))
+            .collect::<String>();
+        Ok(compute_hash(&listing.into_bytes()))
+    }
+
+    pub fn read_bundle(path: &PathBuf) -> Result<Self> {
+        let data = fs::read(path).context(format!("Could not read bundle {}", path.display()))?;
+        Self::from_bundle(&data)
+            .map_err(|e| format_err!("Invalid bundle {}: {}", path.display(), e))
+    }
+
+    pub fn from_bundle(data: &[u8]) -> Result<Self> {
+        let mut lines = data.splitn(3, |b| *b == b'\n');
+        if lines.next() != Some(BUNDLE_MAGIC.as_bytes()) {
+            return Err(format_err!("Not a bundle of a supported version"));
+        }
+        let hash_line = String::from_utf8(lines.next().context("Missing hash")?.to_vec())?;
+        let hash = hash_line
+            .strip_prefix("sha3-256 ")
+            .context("Missing hash")?;
+        let body = lines.next().unwrap_or(&[]);
+        if compute_hash(&body.to_vec()) != hash {
+            return Err(format_err!(
+                "The hash of the bundle does not match, it is damaged"
+            ));
+        }
+        let mut folder = MemFolder::empty();
+        let mut rest = body;
+        while rest.len() > 0 {
+            let (path, after) = take_bundle_field(rest)?;
+            let (content, after) = take_bundle_field(after)?;
+            let path = PathBuf::from(String::from_utf8(path.to_vec())?);
+            if path.is_absolute() || contains_parent_dir(&path) {
+                return Err(format_err!("Invalid path {} in bundle", path.display()));
+            }
+            if folder.0.insert(path.clone(), content.to_vec()).is_some() {
+                return Err(format_err!(
+                    "The path {} is in the bundle twice",
+                    path.display()
+                ));
+            }
+            rest = after;
+        }
+        Ok(folder)
+    }
+
+    // Only the files that are in memory.
     pub fn size_in_bytes(&self) -> usize {
         self.0.values().map(|v| v.len()).sum()
     }
 }
+fn take_bundle_field(data: &[u8]) -> Result<(&[u8], &[u8])> {
+    if data.len() < 8 {
+        return Err(format_err!("Truncated bundle"));
+    }
+    let (len, rest) = data.split_at(8);
+    let len = u64::from_be_bytes(len.try_into()?) as usize;
+    if rest.len() < len {
+        return Err(format_err!("Truncated bundle"));
+    }
+    Ok(rest.split_at(len))
+}
+
+// A file or symlink where the output directory should be is removed. Directories are left alone.
+pub fn remove_nondir(out_path: &PathBuf) -> Result<()> {
+    match fs::symlink_metadata(out_path) {
+        Ok(meta) if !meta.is_dir() => {
+            yellow(format!(
+                "Removing {}, because it is not a directory.",
+                out_path.display()
+            ));
+            fs::remove_file(out_path).context(format!("Could not remove {}.", out_path.display()))
+        }
+        _ => Ok(()),
+    }
+}
+
+// Removes the files and the directories that are empty afterwards.
+pub fn remove_untracked(out_path: &PathBuf, untracked: &Vec<PathBuf>) -> Result<()> {
+    for p in untracked {
+        let target = out_path.join(p);
+        if !target.is_file() && !target.is_symlink() {
+            continue;
+        }
+        yellow(format!("Removing untracked file {}", target.display()));
+        fs::remove_file(&target).context(format!("Could not remove file {}.", target.display()))?;
+        for parent in target.ancestors().skip(1) {
+            if parent == out_path || fs::remove_dir(parent).is_err() {
+                break;
+            }
+        }
+    }
+    Ok(())
+}
+
+// Everything that is not a directory counts as a file here, so symlinks are listed as well.
+pub fn files_below(dir: &PathBuf) -> Result<Vec<PathBuf>> {
+    let mut files = vec![];
+    for entry in fs::read_dir(dir).context(format!("Could not read {}", dir.display()))? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            files.append(&mut files_below(&entry.path())?);
+        } else {
+            files.push(entry.path());
+        }
+    }
+    Ok(files)
+}
+
+// Directories that only contained managed files are removed as well.
+pub fn remove_managed_file(out_path: &PathBuf, subpath: &PathBuf) -> Result<()> {
+    let target = out_path.join(subpath);
+    fs::remove_file(&target).context(format!("Could not remove file {}.", target.display()))?;
+    for parent in target.ancestors().skip(1) {
+        if parent == out_path || fs::remove_dir(parent).is_err() {
+            break;
+        }
+    }
+    Ok(())
+}
+
+// Removes the file or directory at path, but leaves the kept paths (relative to the output) in place.
+// With nothing kept below it, a directory is removed as a whole.
+pub fn remove_except(path: &PathBuf, out_path: &PathBuf, kept: &Vec<PathBuf>) -> Result<()> {
+    let relative = path.strip_prefix(out_path)?.to_path_buf();
+    if kept.contains(&relative) {
+        return Ok(());
+    }
+    let meta = fs::symlink_metadata(path)?;
+    if !meta.is_dir() {
+        return Ok(fs::remove_file(path)?);
+    }
+    if !kept.iter().any(|k| k.starts_with(&relative)) {
+        return Ok(fs::remove_dir_all(path)?);
+    }
+    for entry in fs::read_dir(path)? {
+        remove_except(&entry?.path(), out_path, kept)?;
+    }
+    Ok(())
+}
+
+// A missing list is treated like an empty one.
+pub fn read_managed_list(out_path: &PathBuf) -> Result<Vec<PathBuf>> {
+    let Ok(content) = fs::read_to_string(out_path.join(MANAGED_LIST)) else {
+        return Ok(vec![]);
+    };
+    let mut lines = content.lines();
+    if lines.next() != Some(MANAGED_LIST_HEADER) {
+        return Err(format_err!(
+            "{} in {} is not a list of managed files.",
+            MANAGED_LIST,
+            out_path.display()
+        ));
+    }
+    let mut paths = vec![];
+    for line in lines.filter(|l| !l.trim().is_empty()) {
+        let path = PathBuf::from(line);
+        if path.is_absolute() || contains_parent_dir(&path) {
+            return Err(format_err!("Invalid path {} in {}", line, MANAGED_LIST));
+        }
+        paths.push(path);
+    }
+    Ok(paths)
+}
+
+// The active files of the config without the excluded ones.
+pub fn active_files(conf: &Config, tags: &Vec<String>, options: &SyncOptions) -> Result<Vec<File>> {
+    let context = ListContext::new(options.lenient_count, options.max_include_depth);
+    Ok(exclude_files(
+        conf.get_active_with(tags, &context, &options.fetch)?,
+        &options.exclude,
+        &options.fetch,
+    ))
+}
+
+// If the reference folder already contains the file with the right hash, it does not have to be fetched.
+// The line endings are normalized after the edits, so the edits still see the original ones.
+fn load_with_ref(
+    item: &File,
+    tags: &Vec<String>,
+    reference: &PathBuf,
+    ids: &Vec<AgeIdentity>,
+    options: &SyncOptions,
+) -> Result<Vec<u8>> {
+    let content = build_with_ref(item, tags, reference, ids, options)?;
+    Ok(match options.normalize_newlines {
+        Some(style) => normalize_newlines(content, style),
+        None => content,
+    })
+}
+
+fn build_with_ref(
+    item: &File,
+    tags: &Vec<String>,
+    reference: &PathBuf,
+    ids: &Vec<AgeIdentity>,
+    options: &SyncOptions,
+) -> Result<Vec<u8>> {
+    let mut ref_path = reference.clone();
+    ref_path.push(item.get_path());
+    // The file is hashed from disk first, so a stale reference is never read into memory.
+    // Without hash checks, a reference can not be trusted, so the file is always built.
+    if let (Some(reqhash), true, Ok(reffile)) =
+        (&item.hash, hash_check_enabled(), fs::File::open(&ref_path))
+    {
+        if hash_matches_reader(reqhash, reffile).unwrap_or(false) {
+            if let Ok(content) = fs::read(&ref_path) {
+                if options.warn_local_drift {
+                    item.warn_local_drift(reqhash, 0);
+                }
+                return item.from_reference_unchecked(&content, tags, &options.fetch);
+            }
+        }
+    }
+    item.build(tags, ids, options.warn_local_drift, &options.fetch)
+}
+
+// A verify-only file is only checked in the output, it is never written.
+fn verify_on_disk(item: &File, ref_path: &PathBuf, fetch: &FetchContext) -> Result<()> {
+    let content = fs::read(ref_path).context(format!(
+        "The verify-only file {} does not exist.",
+        item.get_path().display()
+    ))?;
+    // Without hash checks, only the presence is verified.
+    if !hash_check_enabled() {
+        return Ok(());
+    }
+    let hash = item.expected_hash(fetch)?.context(format!(
+        "The verify-only file {} needs a hash.",
+        item.get_path().display()
+    ))?;
+    if !hash_matches(&hash, &content)? {
+        return Err(format_err!(
+            "The verify-only file {} does not match its hash.",
+            item.get_path().display()
+        ));
+    }
+    Ok(())
+}
+
+// Writing to a folder that contains a local source (or is inside of one) would delete
+// or re-read the data that the sync itself depends on.
+// With skip_first, only the tracked paths inside the output are overwritten.
+fn check_source_overlap(
+    files: &Vec<File>,
+    directory_roots: &Vec<PathBuf>,
+    output: &PathBuf,
+    skip_first: bool,
+) -> Result<()> {
+    let Some(output) = absolute_path(output) else {
+        return Ok(());
+    };
+    let guarded = if skip_first {
+        let mut firsts = vec![];
+        for f in files {
+            if let Some(first) = f.get_path().components().next() {
+                firsts.push(output.join(first));
+            }
+        }
+        firsts
+    } else {
+        vec![output.clone()]
+    };
+    let mut sources = directory_roots.clone();
+    for f in files {
+        sources.extend(f.sources.iter().filter_map(|s| s.local_path()));
+    }
+    for source in &sources {
+        let Some(source) = absolute_path(source) else {
+            continue;
+        };
+        for g in &guarded {
+            if source.starts_with(g) || g.starts_with(&source) {
+                return Err(format_err!(
+                    "The output {} overlaps with the local source {}.",
+                    g.display(),
+                    source.display()
+                ));
+            }
+        }
+    }
+    Ok(())
+}
+
+// The file is created with the mode, so it is never readable by others, not even briefly.
+fn write_file(
+    root: &PathBuf,
+    subpath: &PathBuf,
+    content: &Vec<u8>,
+    mode: Option<u32>,
+    durable: bool,
+) -> Result<()> {
+    let mut target_path = root.clone();
+    target_path.push(format_subpath(subpath));
+    let prefix = target_path.parent().context("Malformed path")?;
+    fs::create_dir_all(prefix).context("Path could not be created")?;
+    if durable {
+        return write_durably(&target_path, content, mode);
+    }
+    // An existing file might be a hard link, which must not be changed through this path.
+    if target_path.is_file() {
+        fs::remove_file(&target_path)
+            .context(format!("Could not remove {}", target_path.display()))?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create(true).truncate(true);
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    }
+    let mut file = options.open(&target_path)?;
+    file.write_all(content).context("Could not write file")?;
+    set_mode(&target_path, mode)
+}
+
+// The content goes to a temporary file next to the target, which is flushed and renamed into place.
+// The rename replaces the old file at once, so a hard link to it is not changed either.
+fn write_durably(target_path: &PathBuf, content: &Vec<u8>, mode: Option<u32>) -> Result<()> {
+    replace_durably(target_path, mode, |file| file.write_all(content))
+}
+
+// A spilled file is flushed and renamed into place. If the spill directory is on another file system,
+// it is copied to a temporary file next to the target instead, so the target is still replaced at once.
+fn move_durably(staged: &PathBuf, target_path: &PathBuf, mode: Option<u32>) -> Result<()> {
+    set_mode(staged, mode)?;
+    fs::File::open(staged)
+        .and_then(|file| file.sync_all())
+        .context(format!("Could not flush {}", staged.display()))?;
+    if fs::rename(staged, target_path).is_ok() {
+        return sync_dir(target_path.parent().context("Malformed path")?);
+    }
+    replace_durably(target_path, mode, |file| {
+        std::io::copy(&mut fs::File::open(staged)?, file).map(|_| ())
+    })
+}
+
+// The new content is written by `write` into a temporary file, which is flushed and renamed over the target.
+fn replace_durably(
+    target_path: &PathBuf,
+    mode: Option<u32>,
+    write: impl FnOnce(&mut fs::File) -> std::io::Result<()>,
+) -> Result<()> {
+    let prefix = target_path.parent().context("Malformed path")?;
+    let name = target_path.file_name().context("Malformed path")?;
+    let temp_path = prefix.join(format!(".lorevault-tmp-{}", name.to_string_lossy()));
+    // A leftover of an earlier crash.
+    if temp_path.is_file() {
+        fs::remove_file(&temp_path)?;
+    }
+    let mut options = fs::OpenOptions::new();
+    options.write(true).create_new(true);
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        std::os::unix::fs::OpenOptionsExt::mode(&mut options, mode);
+    }
+    let written = options
+        .open(&temp_path)
+        .and_then(|mut file| {
+            write(&mut file)?;
+            file.sync_all()
+        })
+        .context(format!("Could not write file {}", temp_path.display()))
+        .and_then(|_| set_mode(&temp_path, mode))
+        .and_then(|_| {
+            fs::rename(&temp_path, target_path)
+                .context(format!("Could not replace {}", target_path.display()))
+        });
+    if written.is_err() {
+        let _ = fs::remove_file(&temp_path);
+    }
+    written?;
+    sync_dir(prefix)
+}
+
+// The rename is only durable once the directory itself is flushed.
+fn sync_dir(dir: &std::path::Path) -> Result<()> {
+    #[cfg(unix)]
+    fs::File::open(dir)
+        .and_then(|d| d.sync_all())
+        .context(format!("Could not flush directory {}", dir.display()))?;
+    #[cfg(not(unix))]
+    let _ = dir;
+    Ok(())
+}
+
+// If this fails, the file is written normally.
+fn link_file(existing: &PathBuf, target_path: &PathBuf) -> Result<()> {
+    let prefix = target_path.parent().context("Malformed path")?;
+    fs::create_dir_all(prefix).context("Path could not be created")?;
+    if target_path.is_file() {
+        fs::remove_file(target_path)?;
+    }
+    fs::hard_link(existing, target_path)?;
+    Ok(())
+}
+
+// Without a default mode, the mode of existing files is not managed.
+fn mode_matches(meta: &fs::Metadata, mode: Option<u32>) -> bool {
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        return meta.permissions().mode() & 0o7777 == mode & 0o7777;
+    }
+    let _ = (meta, mode);
+    true
+}
+
+// The umask and an existing file can both change the mode that was given on creation.
+fn set_mode(path: &PathBuf, mode: Option<u32>) -> Result<()> {
+    #[cfg(unix)]
+    if let Some(mode) = mode {
+        use std::os::unix::fs::PermissionsExt;
+        fs::set_permissions(path, fs::Permissions::from_mode(mode))
+            .context(format!("Could not set the mode of {}", path.display()))?;
+    }
+    #[cfg(not(unix))]
+    let _ = (path, mode);
+    Ok(())
+}
+
+// Like canonicalize, but the last component does not have to exist yet.
+fn absolute_path(path: &PathBuf) -> Option<PathBuf> {
+    if let Ok(p) = path.canonicalize() {
+        return Some(p);
+    }
+    let name = path.file_name()?;
+    let parent = match path.parent() {
+        Some(p) if p.as_os_str().len() > 0 => p.to_path_buf(),
+        _ => PathBuf::from("."),
+    };
+    Some(parent.canonicalize().ok()?.join(name))
+}
+
 fn contains_parent_dir(path: &PathBuf) -> bool {
     path.components().any(|component| match component {
         std::path::Component::ParentDir => true,
         _ => false,
     })
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[cfg(unix)]
+    #[test]
+    fn test_default_mode() {
+        use std::os::unix::fs::PermissionsExt;
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().to_path_buf();
+        let subpath = PathBuf::from("sub/secret.txt");
+        write_file(&root, &subpath, &b"secret".to_vec(), Some(0o600), false).unwrap();
+        let mode = fs::metadata(root.join(&subpath))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o600);
+        // An existing file gets the mode too.
+        write_file(&root, &subpath, &b"other".to_vec(), Some(0o640), false).unwrap();
+        let mode = fs::metadata(root.join(&subpath))
+            .unwrap()
+            .permissions()
+            .mode();
+        assert_eq!(mode & 0o7777, 0o640);
+        assert_eq!(fs::read(root.join(&subpath)).unwrap(), b"other");
+    }
+    #[test]
+    fn test_bundle_roundtrip() {
+        let mut folder = MemFolder::empty();
+        folder
+            .0
+            .insert(PathBuf::from("a.txt"), b"first\nfile".to_vec());
+        folder
+            .0
+            .insert(PathBuf::from("sub/b.bin"), vec![0, 159, 146, 150]);
+        folder.0.insert(PathBuf::from("empty"), vec![]);
+        let bundle = folder.to_bundle().unwrap();
+        assert!(bundle.starts_with(b"LOREVAULT-BUNDLE v1\nsha3-256 "));
+        // The format does not depend on the order of the HashMap.
+        assert_eq!(bundle, folder.to_bundle().unwrap());
+        let mut reversed = MemFolder::empty();
+        let mut entries: Vec<_> = folder.0.clone().into_iter().collect();
+        entries.sort();
+        for (path, content) in entries.into_iter().rev() {
+            reversed.0.insert(path, content);
+        }
+        assert_eq!(bundle, reversed.to_bundle().unwrap());
+        let read = MemFolder::from_bundle(&bundle).unwrap();
+        assert_eq!(read.0, folder.0);
+
+        let mut damaged = bundle.clone();
+        let last = damaged.len() - 1;
+        damaged[last] ^= 1;
+        assert!(MemFolder::from_bundle(&damaged).is_err());
+        assert!(MemFolder::from_bundle(&bundle[..bundle.len() - 2]).is_err());
+        assert!(MemFolder::from_bundle(b"something else").is_err());
+    }
+
+    #[test]
+    fn test_fingerprint() {
+        let mut first = MemFolder::empty();
+        first.0.insert(PathBuf::from("b.txt"), b"b".to_vec());
+        first.0.insert(PathBuf::from("dir/a.txt"), b"a".to_vec());
+        let mut second = MemFolder::empty();
+        second.0.insert(PathBuf::from("dir/a.txt"), b"a".to_vec());
+        second.0.insert(PathBuf::from("b.txt"), b"b".to_vec());
+        assert_eq!(first.fingerprint().unwrap(), second.fingerprint().unwrap());
+        let listing = format!(
+            "{} b.txt\n{} dir/a.txt\n",
+            compute_hash(&b"b".to_vec()),
+            compute_hash(&b"a".to_vec())
+        );
+        assert_eq!(
+            first.fingerprint().unwrap(),
+            compute_hash(&listing.into_bytes())
+        );
+        // Moving content to another path changes the fingerprint.
+        second.0.remove(&PathBuf::from("b.txt"));
+        second.0.insert(PathBuf::from("c.txt"), b"b".to_vec());
+        assert_ne!(first.fingerprint().unwrap(), second.fingerprint().unwrap());
+    }
+
+    #[test]
+    fn test_write_durably() {
+        let dir = tempfile::tempdir().unwrap();
+        let target = dir.path().join("config.txt");
+        write_durably(&target, &b"old".to_vec(), None).unwrap();
+        let link = dir.path().join("link.txt");
+        fs::hard_link(&target, &link).unwrap();
+        write_durably(&target, &b"new".to_vec(), Some(0o600)).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"new");
+        // The file was replaced, not changed through the link.
+        assert_eq!(fs::read(&link).unwrap(), b"old");
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&target).unwrap().permissions().mode();
+            assert_eq!(mode & 0o7777, 0o600);
+        }
+        let mut names = fs::read_dir(dir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name())
+            .collect::<Vec<_>>();
+        names.sort();
+        assert_eq!(names, vec!["config.txt", "link.txt"]);
+    }
+
+    #[test]
+    fn test_move_durably() {
+        let spill = tempfile::tempdir().unwrap();
+        let out = tempfile::tempdir().unwrap();
+        let staged = spill.path().join("staged.txt");
+        let target = out.path().join("config.txt");
+        fs::write(&staged, "new").unwrap();
+        fs::write(&target, "old").unwrap();
+        move_durably(&staged, &target, Some(0o600)).unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"new");
+        assert!(!staged.exists());
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            let mode = fs::metadata(&target).unwrap().permissions().mode();
+            assert_eq!(mode & 0o7777, 0o600);
+        }
+        // The fallback for another file system leaves no temporary file behind.
+        fs::write(&staged, "copied").unwrap();
+        replace_durably(&target, None, |file| {
+            std::io::copy(&mut fs::File::open(&staged)?, file).map(|_| ())
+        })
+        .unwrap();
+        assert_eq!(fs::read(&target).unwrap(), b"copied");
+        assert_eq!(fs::read_dir(out.path()).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn test_retain_tracked() {
+        let mut folder = MemFolder::empty();
+        folder.0.insert(PathBuf::from("a/one.txt"), vec![]);
+        folder.0.insert(PathBuf::from("a/sub/two.txt"), vec![]);
+        folder.0.insert(PathBuf::from("b.txt"), vec![]);
+        folder.0.insert(PathBuf::from("c/three.txt"), vec![]);
+        folder.retain_tracked(&vec![PathBuf::from("a"), PathBuf::from("b.txt")]);
+        let mut tracked = folder.tracked_subpaths().unwrap();
+        tracked.sort();
+        assert_eq!(tracked, vec![PathBuf::from("a"), PathBuf::from("b.txt")]);
+        assert_eq!(folder.paths().len(), 3);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn test_dedup_links() {
+        use std::os::unix::fs::MetadataExt;
+        let mut folder = MemFolder::empty();
+        folder.0.insert(PathBuf::from("a.txt"), b"same".to_vec());
+        folder
+            .0
+            .insert(PathBuf::from("sub/b.txt"), b"same".to_vec());
+        folder.0.insert(PathBuf::from("c.txt"), b"other".to_vec());
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().to_path_buf();
+        folder
+            .write_into(
+                &out,
+                &SyncOptions {
+                    dedup_links: true,
+                    ..Default::default()
+                },
+            )
+            .unwrap();
+        let inode = |p: &str| fs::metadata(out.join(p)).unwrap().ino();
+        assert_eq!(inode("a.txt"), inode("sub/b.txt"));
+        assert_ne!(inode("a.txt"), inode("c.txt"));
+        assert_eq!(fs::read(out.join("sub/b.txt")).unwrap(), b"same");
+        // Writing again without links must not change the other file through the link.
+        folder.0.insert(PathBuf::from("a.txt"), b"changed".to_vec());
+        folder.write_into(&out, &SyncOptions::default()).unwrap();
+        assert_ne!(inode("a.txt"), inode("sub/b.txt"));
+        assert_eq!(fs::read(out.join("sub/b.txt")).unwrap(), b"same");
+    }
+
+    #[test]
+    fn test_matches_disk() {
+        let mut folder = MemFolder::empty();
+        folder.0.insert(PathBuf::from("a.txt"), b"first".to_vec());
+        folder
+            .0
+            .insert(PathBuf::from("sub/b.txt"), b"second".to_vec());
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        assert!(!folder.matches_disk(&out, false, None).unwrap());
+        folder
+            .write_to_folder(&out, &SyncOptions::default())
+            .unwrap();
+        assert!(folder.matches_disk(&out, false, None).unwrap());
+        // The list of managed files is not part of the folder.
+        fs::write(out.join(MANAGED_LIST), "").unwrap();
+        assert!(folder.matches_disk(&out, false, None).unwrap());
+        // Untracked files only matter if the whole output is managed.
+        fs::write(out.join("stray.txt"), "").unwrap();
+        assert!(!folder.matches_disk(&out, false, None).unwrap());
+        assert!(folder.matches_disk(&out, true, None).unwrap());
+        fs::write(out.join("sub/stray.txt"), "").unwrap();
+        assert!(!folder.matches_disk(&out, true, None).unwrap());
+        fs::remove_file(out.join("sub/stray.txt")).unwrap();
+        fs::write(out.join("sub/b.txt"), "changed").unwrap();
+        assert!(!folder.matches_disk(&out, true, None).unwrap());
+    }
+
+    #[test]
+    fn test_compare_with_disk() {
+        let mut folder = MemFolder::empty();
+        folder.0.insert(PathBuf::from("a.txt"), b"first".to_vec());
+        folder
+            .0
+            .insert(PathBuf::from("sub/b.txt"), b"second".to_vec());
+        let dir = tempfile::tempdir().unwrap();
+        let out = dir.path().join("out");
+        let diff = folder.compare_with_disk(&out, false, None).unwrap();
+        assert_eq!(
+            diff.missing,
+            vec![PathBuf::from("a.txt"), PathBuf::from("sub/b.txt")]
+        );
+        folder
+            .write_to_folder(&out, &SyncOptions::default())
+            .unwrap();
+        assert!(folder
+            .compare_with_disk(&out, false, None)
+            .unwrap()
+            .is_empty());
+        fs::write(out.join("stray.txt"), "").unwrap();
+        fs::write(out.join("sub/b.txt"), "changed").unwrap();
+        fs::remove_file(out.join("a.txt")).unwrap();
+        assert_eq!(
+            folder.compare_with_disk(&out, false, None).unwrap(),
+            DiskDiff {
+                missing: vec![PathBuf::from("a.txt")],
+                extra: vec![PathBuf::from("stray.txt")],
+                modified: vec![PathBuf::from("sub/b.txt")],
+            }
+        );
+        // Outside of the tracked paths, nothing is compared.
+        let diff = folder.compare_with_disk(&out, true, None).unwrap();
+        assert!(diff.extra.is_empty());
+    }
+
+    #[test]
+    fn test_verify_only() {
+        let dir = tempfile::tempdir().unwrap();
+        let reference = dir.path().to_path_buf();
+        fs::write(reference.join("managed.txt"), "managed elsewhere").unwrap();
+        let file = |path: &str, hash: &str| -> File {
+            toml::from_str(&format!(
+                "path = \"{}\"\nhash = \"{}\"\nverify_only = true",
+                path, hash
+            ))
+            .unwrap()
+        };
+        let hash = compute_hash(&b"managed elsewhere".to_vec());
+        let path = reference.join("managed.txt");
+        assert!(
+            verify_on_disk(&file("managed.txt", &hash), &path, &FetchContext::default()).is_ok()
+        );
+        let other = compute_hash(&b"something else".to_vec());
+        let error = verify_on_disk(
+            &file("managed.txt", &other),
+            &path,
+            &FetchContext::default(),
+        );
+        assert!(error
+            .unwrap_err()
+            .to_string()
+            .contains("does not match its hash"));
+        let error = verify_on_disk(
+            &file("missing.txt", &hash),
+            &reference.join("missing.txt"),
+            &FetchContext::default(),
+        );
+        assert!(error.unwrap_err().to_string().contains("does not exist"));
+        // Writing the folder leaves the file alone.
+        use std::os::unix::fs::MetadataExt;
+        let inode = fs::metadata(&path).unwrap().ino();
+        let mut folder = MemFolder::empty();
+        folder
+            .0
+            .insert(PathBuf::from("other.txt"), b"other".to_vec());
+        folder.2.push(PathBuf::from("managed.txt"));
+        folder
+            .write_to_folder(&reference, &SyncOptions::default())
+            .unwrap();
+        assert_eq!(fs::metadata(&path).unwrap().ino(), inode);
+        assert!(reference.join("other.txt").is_file());
+    }
+}
@@ -0,0 +1,39 @@
+#![allow(clippy::len_zero)]
+use crate::*;
+use minisign_verify::{PublicKey, Signature};
+
+// Verifies a detached minisign signature over the data.
+// The key can be given as the base64 line alone or as the whole contents of a .pub file.
+pub fn verify_minisign(data: &[u8], signature: &[u8], public_key: &str) -> Result<()> {
+    let key_line = public_key
+        .lines()
+        .map(|l| l.trim())
+        .rfind(|l| l.len() > 0 && !l.starts_with("untrusted comment:"))
+        .context("The public key is empty")?;
+    let key = PublicKey::from_base64(key_line)
+        .map_err(|e| format_err!("Invalid minisign public key: {}", e))?;
+    let signature = Signature::decode(
+        std::str::from_utf8(signature).context("The signature file is not valid utf8")?,
+    )
+    .map_err(|e| format_err!("Invalid minisign signature file: {}", e))?;
+    key.verify(data, &signature, false)
+        .map_err(|e| format_err!("{}", e))
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    const KEY: &str = include_str!("../testing/minisign.pub");
+    const SIGNATURE: &[u8] = include_bytes!("../testing/file1.txt.minisig");
+    const DATA: &[u8] = include_bytes!("../testing/testfolder/file1.txt");
+
+    #[test]
+    fn test_minisign() {
+        verify_minisign(DATA, SIGNATURE, KEY).unwrap();
+        let key_line = KEY.lines().last().unwrap();
+        verify_minisign(DATA, SIGNATURE, key_line).unwrap();
+        let mut tampered = DATA.to_vec();
+        tampered.push(b'\n');
+        assert!(verify_minisign(&tampered, SIGNATURE, KEY).is_err());
+    }
+}
@@ -1,3 +1,8 @@
+#![allow(
+    clippy::needless_borrow,
+    clippy::needless_late_init,
+    clippy::needless_return
+)]
 use crate::*;
 
 #[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
@@ -7,7 +12,14 @@ pub enum FileSource {
     #[serde(rename = "local")]
     Local { path: PathBuf },
     #[serde(rename = "http")]
-    Download { url: String },
+    Download {
+        url: String,
+        #[serde(default)]
+        redirects: Redirects,
+        // Replaces the user agent of all requests for this source.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        user_agent: Option<String>,
+    },
     #[serde(rename = "sftp")]
     Sftp {
         user: String,
@@ -23,19 +35,47 @@ pub enum FileSource {
     },
     #[serde(rename = "text")]
     Text {
+        #[serde(default)]
         content: String,
+        // A template next to the config. It replaces the content when the variables are set.
+        #[serde(default, skip_serializing_if = "Option::is_none")]
+        from_file: Option<PathBuf>,
         #[serde(default)]
         ignore_variables: bool,
     },
+    // An asset of a GitHub or GitLab release, found by a glob on its name.
+    #[serde(rename = "release")]
+    Release {
+        repo: String,
+        asset: String,
+        #[serde(default = "default_release_tag")]
+        tag: String,
+        #[serde(default)]
+        forge: Forge,
+        api: Option<String>, // For self-hosted instances.
+        token: Option<String>,
+    },
     #[serde(untagged)]
     Auto(String),
 }
 
+fn default_release_tag() -> String {
+    "latest".to_string()
+}
+
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq)]
+#[serde(rename_all = "lowercase")]
+pub enum Forge {
+    #[default]
+    Github,
+    Gitlab,
+}
+
 impl fmt::Display for FileSource {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             FileSource::Local { path } => write!(f, "{}", path.display()),
-            FileSource::Download { url } => write!(f, "{}", url),
+            FileSource::Download { url, .. } => write!(f, "{}", url),
             FileSource::Sftp {
                 user,
                 service,
@@ -44,6 +84,9 @@ impl fmt::Display for FileSource {
             } => write!(f, "{}@{}:{}", user, service, path.display()),
             FileSource::Git { repo, id, path } => write!(f, "{}#{}:{}", repo, id, path.display()),
             FileSource::Text { .. } => write!(f, "Custom text"),
+            FileSource::Release {
+                repo, tag, asset, ..
+            } => write!(f, "{} release {}: {}", repo, tag, asset),
 
             FileSource::Auto(a) => write!(f, "{}", a),
         }
@@ -51,9 +94,14 @@ impl fmt::Display for FileSource {
 }
 
 impl FileSource {
-    pub fn fetch(&self) -> Result<Vec<u8>> {
+    pub fn fetch(&self, fetch: &FetchContext) -> Result<Vec<u8>> {
+        source_backend().fetch_bytes(self, fetch)
+    }
+
+    // Reads the source without going through the backend.
+    pub fn fetch_directly(&self, fetch: &FetchContext) -> Result<Vec<u8>> {
         match self {
-            FileSource::Auto(auto) => parse_auto_source(auto)?.fetch(),
+            FileSource::Auto(auto) => parse_auto_source(auto)?.fetch(fetch),
             FileSource::Local { path } => {
                 if path.is_relative() {
                     return Err(format_err!(
@@ -66,25 +114,35 @@ impl FileSource {
                     path.to_string_lossy()
                 ))
             }
-            FileSource::Download { url } => {
+            FileSource::Download {
+                url,
+                redirects,
+                user_agent,
+            } => {
                 let spinner = ProgressBar::new_spinner();
                 spinner.set_style(
                     ProgressStyle::default_spinner()
                         .template("{spinner:.green}{spinner:.green} {msg}")
                         .context("Failed because progress bar")?,
                 );
-                spinner.set_message(format!("Loading: {}", url));
+                spinner.set_message(hide_secrets(&format!("Loading: {}", url)));
                 spinner.enable_steady_tick(Duration::from_millis(50));
-                let response = reqwest::blocking::get(url)?;
-                let bytes = response.error_for_status()?.bytes()?.to_vec();
-                spinner.finish_with_message(format!("Loaded: {}", url));
+                let bytes = download(url, redirects, user_agent.as_deref(), fetch)?;
+                spinner.finish_with_message(hide_secrets(&format!("Loaded: {}", url)));
                 Ok(bytes)
             }
             FileSource::Git {
                 repo,
                 id: commit,
                 path,
-            } => get_git_file(commit, path, repo),
+            } => get_git_file(commit, path, repo, fetch),
+            FileSource::Text {
+                from_file: Some(path),
+                ..
+            } => Err(format_err!(
+                "The text file {} was not read before the content was needed",
+                path.display()
+            )),
             FileSource::Text { content, .. } => Ok(content.clone().into_bytes()),
             FileSource::Sftp {
                 user,
@@ -92,26 +150,627 @@ impl FileSource {
                 path,
                 port,
             } => get_file_over_sftp(user, service, path, *port),
+            FileSource::Release {
+                repo,
+                asset,
+                tag,
+                forge,
+                api,
+                token,
+            } => fetch_release_asset(
+                repo,
+                asset,
+                tag,
+                *forge,
+                api.as_deref(),
+                token.as_deref(),
+                fetch,
+            ),
+        }
+    }
+
+    // The remote repo that has to be cloned for this source.
+    pub fn remote_repo(&self) -> Option<String> {
+        match self {
+            FileSource::Git { repo, .. } if is_url_or_ssh(repo) => Some(repo.clone()),
+            FileSource::Auto(auto) => parse_auto_source(auto).ok()?.remote_repo(),
+            _ => None,
+        }
+    }
+
+    // The repo and the id of a git source.
+    pub fn git_ref(&self) -> Option<(String, String)> {
+        match self {
+            FileSource::Git { repo, id, .. } => Some((repo.clone(), id.trim().to_string())),
+            FileSource::Auto(auto) => parse_auto_source(auto).ok()?.git_ref(),
+            _ => None,
+        }
+    }
+
+    // The path on this machine that the source reads from, if it is a local file.
+    pub fn local_path(&self) -> Option<PathBuf> {
+        match self {
+            FileSource::Local { path } => Some(path.clone()),
+            FileSource::Auto(auto) => parse_auto_source(auto).ok()?.local_path(),
+            _ => None,
+        }
+    }
+}
+
+// How an http source treats redirects. Following them is the default of reqwest.
+#[derive(Debug, Clone, Copy, Serialize, Deserialize, Default, PartialEq, Eq, Hash)]
+pub enum Redirects {
+    #[default]
+    #[serde(rename = "follow")]
+    Follow,
+    #[serde(rename = "none")]
+    Refuse,
+    #[serde(rename = "same_host")]
+    SameHost,
+}
+
+impl Redirects {
+    fn policy(&self) -> reqwest::redirect::Policy {
+        match self {
+            Self::Follow => reqwest::redirect::Policy::default(),
+            Self::Refuse => reqwest::redirect::Policy::none(),
+            Self::SameHost => reqwest::redirect::Policy::custom(|attempt| {
+                let same_host = attempt
+                    .previous()
+                    .first()
+                    .is_some_and(|first| first.host_str() == attempt.url().host_str());
+                if attempt.previous().len() > 10 {
+                    attempt.error("too many redirects")
+                } else if same_host {
+                    attempt.follow()
+                } else {
+                    let refused = format!("Redirect to {} was refused", attempt.url());
+                    attempt.error(refused)
+                }
+            }),
+        }
+    }
+}
+
+pub const DEFAULT_USER_AGENT: &str = concat!("lorevault/", env!("CARGO_PKG_VERSION"));
+
+// The certificates are added to the roots of the system. A file without any is a mistake.
+pub fn load_ca_bundle(path: &PathBuf) -> Result<Vec<reqwest::Certificate>> {
+    let pem = fs::read(path).context(format!("Could not read CA bundle {}", path.display()))?;
+    let certificates = reqwest::Certificate::from_pem_bundle(&pem)
+        .context(format!("The CA bundle {} is not valid PEM", path.display()))?;
+    if certificates.is_empty() {
+        return Err(format_err!(
+            "The CA bundle {} contains no certificates",
+            path.display()
+        ));
+    }
+    Ok(certificates)
+}
+
+// What the command line sets for loading configs and fetching sources.
+// It is passed down to everything that reads a source, the configs themselves included.
+#[derive(Debug, Clone, Default)]
+pub struct FetchContext {
+    // Set by --user-agent and --ca-bundle, for all http requests.
+    pub user_agent: Option<String>,
+    pub ca_certificates: Vec<reqwest::Certificate>,
+    // Set by --netrc. Credentials for https sources and clones are looked up in .netrc.
+    pub netrc: bool,
+    // Set by --require-pinned-refs. Every git id must be a full commit hash.
+    pub require_pinned_refs: bool,
+    // Set by --allow-exec. Secret variables may be read by running programs like vault.
+    pub allow_exec: bool,
+    // Set by --trace. Every resolution step is printed to stderr.
+    pub trace: bool,
+    // Set by --config-var and --profile. Only the config given on the command line sees them.
+    pub config_vars: HashMap<String, String>,
+    pub profile: Option<String>,
+    // The clients are shared by all downloads, one for each redirect policy.
+    pub http_clients: OnceCell<HashMap<Redirects, reqwest::blocking::Client>>,
+}
+
+impl FetchContext {
+    fn http_client(&self, redirects: &Redirects) -> Result<&reqwest::blocking::Client> {
+        let clients = self.http_clients.get_or_try_init(|| -> Result<_> {
+            let mut clients = HashMap::new();
+            for r in [Redirects::Follow, Redirects::Refuse, Redirects::SameHost] {
+                let mut builder = reqwest::blocking::Client::builder()
+                    .redirect(r.policy())
+                    .user_agent(self.user_agent.as_deref().unwrap_or(DEFAULT_USER_AGENT));
+                for certificate in &self.ca_certificates {
+                    builder = builder.add_root_certificate(certificate.clone());
+                }
+                clients.insert(r, builder.build()?);
+            }
+            Ok(clients)
+        })?;
+        clients
+            .get(redirects)
+            .context("No http client for redirect policy")
+    }
+}
+
+// How often a download that breaks off is continued before it fails.
+const DOWNLOAD_ATTEMPTS: usize = 3;
+
+// The body is written to a partial file. If the connection breaks, the download is continued
+// with a range request. A server that does not support ranges sends the whole file again.
+// The hash is checked on the complete file by the caller.
+fn download(
+    url: &str,
+    redirects: &Redirects,
+    user_agent: Option<&str>,
+    fetch: &FetchContext,
+) -> Result<Vec<u8>> {
+    let (partial, file) = open_partial(&downloads_dir()?, url)?;
+    let result = download_resumable(url, redirects, user_agent, file, fetch);
+    // What was received of a failed download is kept, so the next run continues it.
+    if let Some(partial) = partial {
+        let received = fs::metadata(&partial).map(|m| m.len()).unwrap_or(0);
+        if result.is_ok() || received == 0 {
+            let _ = fs::remove_file(&partial);
+        }
+    }
+    result
+}
+
+// Partial downloads are kept across runs, unlike the rest of the cache.
+fn downloads_dir() -> Result<PathBuf> {
+    match dirs::cache_dir() {
+        Some(dir) => Ok(dir.join("lorevault").join("downloads")),
+        None => cache_dir(),
+    }
+}
+
+// The partial file of the url, locked for this process. If another process (or job) downloads
+// the same url right now, an anonymous file is used instead, which is not continued later.
+fn open_partial(dir: &PathBuf, url: &str) -> Result<(Option<PathBuf>, fs::File)> {
+    fs::create_dir_all(dir).context(format!("Could not create {}", dir.display()))?;
+    let partial = dir.join(format!("{}.partial", cache_name(url).display()));
+    let file = fs::File::options()
+        .create(true)
+        .read(true)
+        .append(true)
+        .open(&partial)
+        .context(format!("Could not create {}", partial.display()))?;
+    match file.try_lock() {
+        Ok(()) => Ok((Some(partial), file)),
+        Err(fs::TryLockError::WouldBlock) => Ok((None, tempfile::tempfile_in(cache_dir()?)?)),
+        Err(fs::TryLockError::Error(e)) => {
+            Err(format_err!("Could not lock {}: {}", partial.display(), e))
+        }
+    }
+}
+
+fn download_resumable(
+    url: &str,
+    redirects: &Redirects,
+    user_agent: Option<&str>,
+    mut file: fs::File,
+    fetch: &FetchContext,
+) -> Result<Vec<u8>> {
+    // The partial file can hold the start of the body from an earlier run.
+    let mut received = file.metadata()?.len();
+    for attempt in 1..=DOWNLOAD_ATTEMPTS {
+        let mut request = fetch.http_client(redirects)?.get(url);
+        if let Some(user_agent) = user_agent {
+            request = request.header(reqwest::header::USER_AGENT, user_agent);
+        }
+        if let Some((login, password)) = netrc_credentials(url, fetch.netrc) {
+            request = request.basic_auth(login, Some(password));
+        }
+        if received > 0 {
+            request = request.header(reqwest::header::RANGE, format!("bytes={}-", received));
+        }
+        let mut response = match checked_response(request, url) {
+            Ok(response) => response,
+            // The file may have changed since the earlier run, so it is downloaded from the start.
+            Err(e) if attempt == 1 && received > 0 => {
+                yellow(format!(
+                    "Continuing the earlier download of {} failed, starting over. ({})",
+                    url, e
+                ));
+                file.set_len(0)?;
+                received = 0;
+                continue;
+            }
+            // After a download broke off, a failed request to continue it is tried again.
+            Err(e) if attempt > 1 && attempt < DOWNLOAD_ATTEMPTS => {
+                yellow(format!(
+                    "Continuing the download of {} failed, trying again. ({})",
+                    url, e
+                ));
+                continue;
+            }
+            Err(e) => return Err(e),
+        };
+        if received > 0 && !continues_at(&response, received) {
+            file.set_len(0)?;
+            file.seek(std::io::SeekFrom::Start(0))?;
+            received = 0;
+        }
+        match append_body(&mut response, &mut file, &mut received) {
+            Ok(()) => {
+                let mut bytes = vec![];
+                file.seek(std::io::SeekFrom::Start(0))?;
+                file.read_to_end(&mut bytes)?;
+                return Ok(bytes);
+            }
+            Err(e) if attempt < DOWNLOAD_ATTEMPTS => yellow(format!(
+                "The download of {} broke off after {} bytes, continuing. ({})",
+                url, received, e
+            )),
+            Err(e) => return Err(format_err!("The download of {} failed: {}", url, e)),
+        }
+    }
+    unreachable!("the last attempt always returns")
+}
+
+// Refused redirects and error statuses are errors as well.
+fn checked_response(
+    request: reqwest::blocking::RequestBuilder,
+    url: &str,
+) -> Result<reqwest::blocking::Response> {
+    // The reason for a refused redirect is only part of the error source.
+    let response = request
+        .send()
+        .map_err(|e| match std::error::Error::source(&e) {
+            Some(reason) => format_err!("{}: {}", e, reason),
+            None => e.into(),
+        })?;
+    if response.status().is_redirection() {
+        return Err(format_err!(
+            "Redirect from {} to {} was refused",
+            url,
+            response
+                .headers()
+                .get(reqwest::header::LOCATION)
+                .and_then(|l| l.to_str().ok())
+                .unwrap_or("unknown location")
+        ));
+    }
+    Ok(response.error_for_status()?)
+}
+
+// A partial response that starts exactly at the first missing byte.
+fn continues_at(response: &reqwest::blocking::Response, received: u64) -> bool {
+    response.status() == reqwest::StatusCode::PARTIAL_CONTENT
+        && response
+            .headers()
+            .get(reqwest::header::CONTENT_RANGE)
+            .and_then(|r| r.to_str().ok())
+            .is_some_and(|r| r.starts_with(&format!("bytes {}-", received)))
+}
+
+fn append_body(body: &mut impl Read, file: &mut fs::File, received: &mut u64) -> Result<()> {
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let n = match body.read(&mut buffer) {
+            Ok(0) => return Ok(()),
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        file.write_all(&buffer[..n])?;
+        *received += n as u64;
+    }
+}
+
+#[derive(Deserialize)]
+struct GithubRelease {
+    tag_name: String,
+    assets: Vec<GithubAsset>,
+}
+
+#[derive(Deserialize)]
+struct GithubAsset {
+    name: String,
+    url: String,
+    browser_download_url: String,
+}
+
+#[derive(Deserialize)]
+struct GitlabRelease {
+    tag_name: String,
+    assets: GitlabAssets,
+}
+
+#[derive(Deserialize)]
+struct GitlabAssets {
+    links: Vec<GitlabLink>,
+}
+
+#[derive(Deserialize)]
+struct GitlabLink {
+    name: String,
+    url: String,
+    direct_asset_url: Option<String>,
+}
+
+fn forge_request(
+    request: reqwest::blocking::RequestBuilder,
+    forge: Forge,
+    token: Option<&str>,
+) -> reqwest::blocking::RequestBuilder {
+    // GitHub refuses api requests without a user agent, the client always sends one.
+    match (forge, token) {
+        (_, None) => request,
+        (Forge::Github, Some(token)) => request.bearer_auth(token),
+        (Forge::Gitlab, Some(token)) => request.header("PRIVATE-TOKEN", token),
+    }
+}
+
+// Looks up the release and downloads the one asset whose name matches the glob.
+fn fetch_release_asset(
+    repo: &str,
+    asset: &str,
+    tag: &str,
+    forge: Forge,
+    api: Option<&str>,
+    token: Option<&str>,
+    fetch: &FetchContext,
+) -> Result<Vec<u8>> {
+    let pattern = glob::Pattern::new(asset).context(format!("Invalid asset pattern {}", asset))?;
+    // The api is asked with the token, so it must not redirect to another host.
+    let client = fetch.http_client(&Redirects::SameHost)?;
+    let api = api
+        .unwrap_or(match forge {
+            Forge::Github => "https://api.github.com",
+            Forge::Gitlab => "https://gitlab.com",
+        })
+        .trim_end_matches('/');
+    let api_origin = reqwest::Url::parse(api).context(format!("Invalid api url {}", api))?;
+    let (release_tag, assets) = match forge {
+        Forge::Github => {
+            let url = match tag {
+                "latest" => format!("{}/repos/{}/releases/latest", api, repo),
+                tag => format!("{}/repos/{}/releases/tags/{}", api, repo, tag),
+            };
+            let release: GithubRelease = forge_request(client.get(&url), forge, token)
+                .send()?
+                .error_for_status()
+                .context(format!("Could not find release {} of {}", tag, repo))?
+                .json()?;
+            // The api url of an asset also works for private repos, but only with a token.
+            let assets = release
+                .assets
+                .into_iter()
+                .map(|a| match token {
+                    Some(_) => (a.name, a.url),
+                    None => (a.name, a.browser_download_url),
+                })
+                .collect::<Vec<_>>();
+            (release.tag_name, assets)
+        }
+        Forge::Gitlab => {
+            let project = repo.replace('/', "%2F");
+            let url = match tag {
+                "latest" => format!(
+                    "{}/api/v4/projects/{}/releases/permalink/latest",
+                    api, project
+                ),
+                tag => format!("{}/api/v4/projects/{}/releases/{}", api, project, tag),
+            };
+            let release: GitlabRelease = forge_request(client.get(&url), forge, token)
+                .send()?
+                .error_for_status()
+                .context(format!("Could not find release {} of {}", tag, repo))?
+                .json()?;
+            let assets = release
+                .assets
+                .links
+                .into_iter()
+                .map(|l| (l.name, l.direct_asset_url.unwrap_or(l.url)))
+                .collect::<Vec<_>>();
+            (release.tag_name, assets)
+        }
+    };
+    let matching = assets
+        .iter()
+        .filter(|(name, _)| pattern.matches(name))
+        .collect::<Vec<_>>();
+    let download_url = match matching.as_slice() {
+        [(_, url)] => url,
+        [] => {
+            return Err(format_err!(
+                "No asset of release {} of {} matches {}",
+                release_tag,
+                repo,
+                asset
+            ))
+        }
+        _ => {
+            return Err(format_err!(
+                "Several assets of release {} of {} match {}: {}",
+                release_tag,
+                repo,
+                asset,
+                matching
+                    .iter()
+                    .map(|(name, _)| name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", ")
+            ))
+        }
+    };
+    download_asset(download_url, &api_origin, forge, token, fetch)
+}
+
+// The token is only sent to the host of the api, a release can link to assets anywhere.
+// Redirects are followed here, so the token is also dropped when one leaves the api host.
+fn download_asset(
+    url: &str,
+    api_origin: &reqwest::Url,
+    forge: Forge,
+    token: Option<&str>,
+    fetch: &FetchContext,
+) -> Result<Vec<u8>> {
+    let client = fetch.http_client(&Redirects::Refuse)?;
+    let mut url = reqwest::Url::parse(url).context(format!("Invalid asset url {}", url))?;
+    for _ in 0..10 {
+        let mut request = client
+            .get(url.clone())
+            .header(reqwest::header::ACCEPT, "application/octet-stream");
+        if same_origin(&url, api_origin) {
+            request = forge_request(request, forge, token);
+        }
+        let response = request.send()?;
+        if !response.status().is_redirection() {
+            return Ok(response.error_for_status()?.bytes()?.to_vec());
+        }
+        let location = response
+            .headers()
+            .get(reqwest::header::LOCATION)
+            .and_then(|l| l.to_str().ok())
+            .context(format!("Redirect from {} without a location", url))?;
+        url = url.join(location)?;
+    }
+    Err(format_err!("Too many redirects for the asset {}", url))
+}
+
+fn same_origin(a: &reqwest::Url, b: &reqwest::Url) -> bool {
+    a.scheme() == b.scheme()
+        && a.host_str() == b.host_str()
+        && a.port_or_known_default() == b.port_or_known_default()
+}
+
+// Enough to tell files apart when reading a listing. Pins always use the full hash.
+pub const SHORT_HASH_LEN: usize = 12;
+
+pub fn short_hash(hash: &str) -> &str {
+    &hash[..hash.len().min(SHORT_HASH_LEN)]
+}
+
+// A pinned hash can name its algorithm with a prefix like "sha256:". Without one, it is SHA3-256.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, clap::ValueEnum)]
+pub enum HashAlgo {
+    #[default]
+    #[value(name = "sha3-256")]
+    Sha3_256,
+    Sha256,
+    Blake3,
+}
+
+impl HashAlgo {
+    pub fn prefix(&self) -> &'static str {
+        match self {
+            HashAlgo::Sha3_256 => "sha3-256",
+            HashAlgo::Sha256 => "sha256",
+            HashAlgo::Blake3 => "blake3",
+        }
+    }
+    // Only the algorithm is checked here. A malformed value simply never matches.
+    pub fn split(hash: &str) -> Result<(HashAlgo, &str)> {
+        let Some((prefix, value)) = hash.split_once(':') else {
+            return Ok((HashAlgo::Sha3_256, hash));
+        };
+        [HashAlgo::Sha3_256, HashAlgo::Sha256, HashAlgo::Blake3]
+            .into_iter()
+            .find(|a| a.prefix().eq_ignore_ascii_case(prefix.trim()))
+            .map(|a| (a, value.trim()))
+            .context(format!(
+                "Unknown hash algorithm {}, use sha3-256, sha256 or blake3",
+                prefix
+            ))
+    }
+    fn name(&self) -> &'static str {
+        match self {
+            HashAlgo::Sha3_256 => "SHA3-256",
+            HashAlgo::Sha256 => "SHA-256",
+            HashAlgo::Blake3 => "BLAKE3",
+        }
+    }
+    // For hashes that are read from files, where a typo should be an error and not a mismatch.
+    pub fn normalize(hash: &str) -> Result<String> {
+        let (algo, value) = HashAlgo::split(hash)?;
+        if value.len() != 64 || !value.chars().all(|c| c.is_ascii_hexdigit()) {
+            return Err(format_err!(
+                "{} is not a {} hash in hex",
+                value,
+                algo.name()
+            ));
+        }
+        Ok(algo.format(&value.to_uppercase()))
+    }
+    // SHA3-256 is written without a prefix, like all the hashes before the others existed.
+    pub fn format(&self, hash: &str) -> String {
+        match self {
+            HashAlgo::Sha3_256 => hash.to_string(),
+            _ => format!("{}:{}", self.prefix(), hash),
         }
     }
 }
 
 pub fn compute_hash(content: &Vec<u8>) -> String {
-    let mut hasher = Sha3_256::new();
-    hasher.update(content);
+    compute_hash_reader(&content[..]).expect("reading from memory can not fail")
+}
 
-    let result = hasher.finalize();
+pub fn compute_hash_reader(reader: impl Read) -> Result<String> {
+    compute_hash_with(HashAlgo::Sha3_256, reader)
+}
+
+// Computes the hash with the algorithm of the expected one and compares the hex digits in any case.
+pub fn hash_matches(expected: &str, content: &[u8]) -> Result<bool> {
+    hash_matches_reader(expected, content)
+}
+
+pub fn hash_matches_reader(expected: &str, reader: impl Read) -> Result<bool> {
+    let (algo, value) = HashAlgo::split(expected)?;
+    Ok(compute_hash_with(algo, reader)?.eq_ignore_ascii_case(value))
+}
+
+enum Hasher {
+    Sha3_256(Box<Sha3_256>),
+    Sha256(Box<sha2::Sha256>),
+    Blake3(Box<blake3::Hasher>),
+}
+
+// Hashes the data in chunks, so it never has to be in memory as a whole.
+pub fn compute_hash_with(algo: HashAlgo, mut reader: impl Read) -> Result<String> {
+    let mut hasher = match algo {
+        HashAlgo::Sha3_256 => Hasher::Sha3_256(Box::new(Sha3_256::new())),
+        HashAlgo::Sha256 => Hasher::Sha256(Box::new(sha2::Sha256::new())),
+        HashAlgo::Blake3 => Hasher::Blake3(Box::new(blake3::Hasher::new())),
+    };
+    let mut buffer = [0u8; 64 * 1024];
+    loop {
+        let n = match reader.read(&mut buffer) {
+            Ok(0) => break,
+            Ok(n) => n,
+            Err(e) if e.kind() == std::io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e.into()),
+        };
+        match &mut hasher {
+            Hasher::Sha3_256(h) => h.update(&buffer[..n]),
+            Hasher::Sha256(h) => h.update(&buffer[..n]),
+            Hasher::Blake3(h) => {
+                h.update(&buffer[..n]);
+            }
+        }
+    }
+
+    let result = match hasher {
+        Hasher::Sha3_256(h) => h.finalize().to_vec(),
+        Hasher::Sha256(h) => h.finalize().to_vec(),
+        Hasher::Blake3(h) => h.finalize().as_bytes().to_vec(),
+    };
     let hex_string: String = result
         .iter()
         .map(|byte| format!("{:02X}", byte))
         .collect::<Vec<_>>()
         .join("");
-    return hex_string;
+    return Ok(hex_string);
 }
 
-fn get_git_file(id: &str, file_path: &PathBuf, repo_path: &str) -> Result<Vec<u8>> {
-    let repo = get_git_repo(repo_path)?;
-    let commit_hash = get_commit_from_string(&repo, id)?;
+fn get_git_file(
+    id: &str,
+    file_path: &PathBuf,
+    repo_path: &str,
+    fetch: &FetchContext,
+) -> Result<Vec<u8>> {
+    let repo = get_git_repo(repo_path, fetch)?;
+    let commit_hash = get_commit_from_string(&repo, id, fetch)?;
 
     let commit = repo.find_commit(Oid::from_str(&commit_hash)?)?;
     let tree = commit.tree()?;
@@ -131,11 +790,48 @@ fn get_git_file(id: &str, file_path: &PathBuf, repo_path: &str) -> Result<Vec<u8
     }
 }
 
-pub fn get_commit_from_string(repo: &Repository, input: &str) -> Result<String> {
-    let obj = repo.revparse_single(input.trim()).context(format!(
-        "Could not find commit for id: {} revparse failed",
-        input
-    ))?;
+// A git id that is not a full commit hash, while --require-pinned-refs is set.
+#[derive(Debug)]
+pub struct FloatingRef(pub String);
+
+impl fmt::Display for FloatingRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The git id {} is not a full commit hash (--require-pinned-refs).",
+            self.0
+        )
+    }
+}
+
+impl std::error::Error for FloatingRef {}
+
+fn is_pinned_ref(id: &str) -> bool {
+    id.len() == 40 && id.chars().all(|c| c.is_ascii_hexdigit())
+}
+
+pub fn get_commit_from_string(
+    repo: &Repository,
+    input: &str,
+    fetch: &FetchContext,
+) -> Result<String> {
+    if fetch.require_pinned_refs && !is_pinned_ref(input.trim()) {
+        return Err(FloatingRef(input.trim().to_string()).into());
+    }
+    let obj = repo
+        .revparse_single(input.trim())
+        .or_else(|e| {
+            // A cached clone can be older than the ref, so it is updated once before giving up.
+            if !is_cached_repo(repo) {
+                return Err(Error::from(e));
+            }
+            update_cached_repo(repo, fetch.netrc)?;
+            Ok(repo.revparse_single(input.trim())?)
+        })
+        .context(format!(
+            "Could not find commit for id: {} revparse failed",
+            input
+        ))?;
     if let Some(commit) = obj.as_commit() {
         let commit_string = commit.id().to_string();
 
@@ -145,12 +841,12 @@ pub fn get_commit_from_string(repo: &Repository, input: &str) -> Result<String>
     Err(format_err!("Could not find commit for id: {}", input))
 }
 
-pub fn get_git_repo(repo_path: &str) -> Result<Repository> {
+pub fn get_git_repo(repo_path: &str, fetch: &FetchContext) -> Result<Repository> {
     let repo: Repository;
     if is_url_or_ssh(repo_path) {
         repo = match fetch_repo_from_cache(repo_path) {
             Ok(r) => r,
-            Err(_) => clone_repository(repo_path)?,
+            Err(_) => clone_repository(repo_path, fetch.netrc)?,
         };
     } else {
         if PathBuf::from(repo_path).is_relative() {
@@ -172,57 +868,252 @@ fn cache_name(url: impl AsRef<str>) -> PathBuf {
     PathBuf::from(compute_hash(&url.as_ref().bytes().collect()))
 }
 
-fn bare_clone(from: &str, to: &PathBuf) -> Result<Repository> {
+fn remote_callbacks<'a>(
+    auth: &'a GitAuthenticator,
+    git_config: &'a git2::Config,
+    netrc: bool,
+) -> git2::RemoteCallbacks<'a> {
+    let mut remote_callbacks = git2::RemoteCallbacks::new();
+    let mut git_credentials = auth.credentials(git_config);
+    let mut tried_netrc = false;
+    remote_callbacks.credentials(move |url, username, allowed| {
+        // The .netrc login is only tried once, so wrong credentials do not end in a loop.
+        if !tried_netrc && allowed.contains(git2::CredentialType::USER_PASS_PLAINTEXT) {
+            if let Some((login, password)) = netrc_credentials(url, netrc) {
+                tried_netrc = true;
+                return git2::Cred::userpass_plaintext(&login, &password);
+            }
+        }
+        git_credentials(url, username, allowed)
+    });
+    remote_callbacks
+}
+
+fn bare_clone(from: &str, to: &PathBuf, netrc: bool) -> Result<Repository> {
     let auth = GitAuthenticator::default();
     let git_config = git2::Config::open_default()?;
     let mut repo_builder = git2::build::RepoBuilder::new();
     let mut fetch_options = git2::FetchOptions::new();
-    let mut remote_callbacks = git2::RemoteCallbacks::new();
-
-    remote_callbacks.credentials(auth.credentials(&git_config));
-    fetch_options.remote_callbacks(remote_callbacks);
+    fetch_options.remote_callbacks(remote_callbacks(&auth, &git_config, netrc));
     repo_builder.fetch_options(fetch_options);
 
     let repo = repo_builder
         .bare(true)
         .remote_create(|repo, name, url| repo.remote_with_fetch(name, url, "+refs/*:refs/*"))
-        .clone(from, to)?;
+        .clone(from, to)
+        .map_err(|e| clone_error(from, e))?;
     Ok(repo)
 }
-fn clone_repository(repo_url: &str) -> Result<Repository> {
+
+// One machine (or the default) of a .netrc file.
+#[derive(Debug, Clone, PartialEq)]
+struct NetrcEntry {
+    machine: Option<String>,
+    login: String,
+    password: String,
+}
+
+fn parse_netrc(text: &str) -> Vec<NetrcEntry> {
+    let mut entries = vec![];
+    let mut current: Option<NetrcEntry> = None;
+    let mut lines = text.lines().filter(|l| !l.trim_start().starts_with('#'));
+    while let Some(line) = lines.next() {
+        let mut words = line.split_whitespace();
+        while let Some(word) = words.next() {
+            match word {
+                "machine" | "default" => {
+                    entries.extend(current.take());
+                    current = Some(NetrcEntry {
+                        machine: match word {
+                            "machine" => Some(words.next().unwrap_or_default().to_string()),
+                            _ => None,
+                        },
+                        login: String::new(),
+                        password: String::new(),
+                    });
+                }
+                "login" | "password" => {
+                    if let (Some(entry), Some(value)) = (current.as_mut(), words.next()) {
+                        match word {
+                            "login" => entry.login = value.to_string(),
+                            _ => entry.password = value.to_string(),
+                        }
+                    }
+                }
+                "account" => {
+                    words.next();
+                }
+                // A macro definition runs until the next empty line.
+                "macdef" => {
+                    for macro_line in lines.by_ref() {
+                        if macro_line.trim().is_empty() {
+                            break;
+                        }
+                    }
+                    break;
+                }
+                _ => {}
+            }
+        }
+    }
+    entries.extend(current);
+    entries
+}
+
+// A matching machine wins over the default entry, like in curl.
+fn find_netrc_entry<'a>(entries: &'a [NetrcEntry], host: &str) -> Option<&'a NetrcEntry> {
+    entries
+        .iter()
+        .find(|e| e.machine.as_deref() == Some(host))
+        .or_else(|| entries.iter().find(|e| e.machine.is_none()))
+}
+
+// Only used with --netrc and only for https urls without credentials of their own.
+// Over plain http, the password would be sent in cleartext.
+fn netrc_credentials(url: &str, netrc: bool) -> Option<(String, String)> {
+    if !netrc {
+        return None;
+    }
+    let url = reqwest::Url::parse(url).ok()?;
+    if url.scheme() != "https" || !url.username().is_empty() {
+        return None;
+    }
+    let path = match std::env::var_os("NETRC") {
+        Some(path) => PathBuf::from(path),
+        None => dirs::home_dir()?.join(".netrc"),
+    };
+    let text = fs::read_to_string(path).ok()?;
+    let entries = parse_netrc(&text);
+    let entry = find_netrc_entry(&entries, url.host_str()?)?;
+    Some((entry.login.clone(), entry.password.clone()))
+}
+
+fn is_cached_repo(repo: &Repository) -> bool {
+    match (CACHEDIR.get(), repo.path().canonicalize()) {
+        (Some(cd), Ok(path)) => cd
+            .path()
+            .canonicalize()
+            .is_ok_and(|cache| path.starts_with(cache)),
+        _ => false,
+    }
+}
+
+// Fetches all refs of the origin again, like the initial mirror clone.
+fn update_cached_repo(repo: &Repository, netrc: bool) -> Result<()> {
+    let mut remote = repo.find_remote("origin")?;
+    let url = remote.url().unwrap_or_default().to_string();
+    neutral(format!(
+        "Fetching {} again, the id is not in the cached clone",
+        url
+    ));
+    let auth = GitAuthenticator::default();
+    let git_config = git2::Config::open_default()?;
+    let mut fetch_options = git2::FetchOptions::new();
+    fetch_options.remote_callbacks(remote_callbacks(&auth, &git_config, netrc));
+    remote
+        .fetch(&[] as &[&str], Some(&mut fetch_options), None)
+        .map_err(|e| clone_error(&url, e))?;
+    Ok(())
+}
+
+// The errors git2 produces for missing credentials are not very helpful on their own.
+fn clone_error(repo: &str, e: git2::Error) -> Error {
+    if e.code() == git2::ErrorCode::Auth || e.message().to_lowercase().contains("authentication") {
+        format_err!(
+            "Authentication failed for {}. Set up an ssh-agent with a matching key, or a git credential helper or access token for https remotes. ({})",
+            repo,
+            e.message()
+        )
+    } else {
+        e.into()
+    }
+}
+fn clone_repository(repo_url: &str, netrc: bool) -> Result<Repository> {
     let spinner = ProgressBar::new_spinner();
     spinner.set_style(
         ProgressStyle::default_spinner()
             .template("{spinner:.green}{spinner:.green} {msg}")
             .context("Failed because progress bar")?,
     );
-    spinner.set_message(format!("Cloning: {}", repo_url));
+    spinner.set_message(hide_secrets(&format!("Cloning: {}", repo_url)));
     spinner.enable_steady_tick(Duration::from_millis(50));
-    let cachedir = match CACHEDIR.get() {
-        Some(cd) => cd,
+
+    let repo = bare_clone(repo_url, &cache_dir()?.join(cache_name(repo_url)), netrc)?;
+    spinner.finish_with_message(hide_secrets(&format!("Cloned: {}", repo_url)));
+
+    Ok(repo)
+}
+
+// Clones the repos into the cache using up to `jobs` threads.
+// Failures are only reported here. The repo is cloned again once it is actually needed.
+pub fn prefetch_repos(repos: &Vec<String>, jobs: usize, fetch: &FetchContext) -> Result<()> {
+    let cachedir = cache_dir()?;
+    let pending = std::sync::Mutex::new(
+        repos
+            .iter()
+            .filter(|r| fetch_repo_from_cache(r).is_err())
+            .cloned()
+            .collect::<Vec<String>>(),
+    );
+    let bars = MultiProgress::new();
+    let style = ProgressStyle::default_spinner()
+        .template("{spinner:.green}{spinner:.green} {msg}")
+        .context("Failed because progress bar")?;
+    std::thread::scope(|scope| {
+        for _ in 0..jobs.max(1) {
+            scope.spawn(|| loop {
+                let Some(url) = pending.lock().expect("Clone queue was poisoned").pop() else {
+                    break;
+                };
+                let spinner = bars.add(ProgressBar::new_spinner());
+                spinner.set_style(style.clone());
+                spinner.set_message(hide_secrets(&format!("Cloning: {}", url)));
+                spinner.enable_steady_tick(Duration::from_millis(50));
+                let target = cachedir.join(cache_name(&url));
+                match bare_clone(&url, &target, fetch.netrc) {
+                    Ok(_) => spinner.finish_with_message(hide_secrets(&format!("Cloned: {}", url))),
+                    Err(e) => {
+                        _ = fs::remove_dir_all(&target);
+                        spinner.finish_with_message(
+                            hide_secrets(&format!("Could not clone {}: {}", url, e))
+                                .yellow()
+                                .to_string(),
+                        )
+                    }
+                }
+            });
+        }
+    });
+    Ok(())
+}
+
+fn cache_dir() -> Result<PathBuf> {
+    match CACHEDIR.get() {
+        Some(cd) => Ok(cd.path().to_path_buf()),
         None => {
             init_cache_dir()?;
-            CACHEDIR
+            Ok(CACHEDIR
                 .get()
                 .context("Could not establish cache directory for cloned repos.")?
+                .path()
+                .to_path_buf())
         }
-    };
-
-    let repo = bare_clone(
-        repo_url,
-        &cachedir
-            .path()
-            .join(cache_name(repo_url))
-            .as_path()
-            .to_path_buf(),
-    )?;
-    spinner.finish_with_message(format!("Cloned: {}", repo_url));
+    }
+}
 
-    Ok(repo)
+// LOREVAULT_CACHE_ROOT moves the cache out of the system temp directory (which also follows TMPDIR).
+fn new_cache_tempdir(root: Option<std::ffi::OsString>) -> Result<TempDir> {
+    match root {
+        Some(root) => TempDir::new_in(&root).context(format!(
+            "Could not create the cache directory in {} (LOREVAULT_CACHE_ROOT)",
+            PathBuf::from(root).display()
+        )),
+        None => Ok(TempDir::new()?),
+    }
 }
 
 pub fn init_cache_dir() -> Result<PathBuf> {
-    let tmpdir = TempDir::new()?;
+    let tmpdir = new_cache_tempdir(std::env::var_os("LOREVAULT_CACHE_ROOT"))?;
     let path = tmpdir.path().to_path_buf();
     let result = CACHEDIR.set(tmpdir);
 
@@ -268,21 +1159,162 @@ fn get_file_over_sftp(
             .template("{spinner:.green}{spinner:.green} {msg}")
             .context("Failed because progress bar")?,
     );
-    spinner.set_message(format!("loading: {}@{}:{}", user, service, path.display()));
+    spinner.set_message(hide_secrets(&format!(
+        "loading: {}@{}:{}",
+        user,
+        service,
+        path.display()
+    )));
     spinner.enable_steady_tick(Duration::from_millis(50));
 
+    let contents = with_sftp_session(user, service, port, |sftp| {
+        let mut remote_file = sftp.open(path)?;
+        let mut contents = Vec::new();
+        remote_file.read_to_end(&mut contents)?;
+        Ok(contents)
+    })?;
+    spinner.finish_with_message(hide_secrets(&format!(
+        "loaded: {}@{}:{}",
+        user,
+        service,
+        path.display()
+    )));
+    Ok(contents)
+}
+
+// One session per user@host:port is kept for the whole run, so many files from the same host
+// do not each pay for the connection, handshake and authentication.
+static SFTP_SESSIONS: SessionCache<ssh2::Sftp> = SessionCache::new();
+
+fn with_sftp_session<T>(
+    user: &str,
+    service: &str,
+    port: Option<usize>,
+    f: impl Fn(&ssh2::Sftp) -> Result<T>,
+) -> Result<T> {
+    let key = format!("{}@{}:{}", user, service, port.unwrap_or(22));
+    SFTP_SESSIONS.with_session(&key, || sftp_session(user, service, port), f)
+}
+
+// The lock is only held to look up or store a session, never while a file is transferred,
+// so the parallel jobs do not wait for each other's downloads.
+struct SessionCache<S>(Mutex<BTreeMap<String, Arc<S>>>);
+
+impl<S> SessionCache<S> {
+    const fn new() -> Self {
+        SessionCache(Mutex::new(BTreeMap::new()))
+    }
+
+    fn sessions(&self) -> Result<std::sync::MutexGuard<'_, BTreeMap<String, Arc<S>>>> {
+        self.0
+            .lock()
+            .map_err(|_| format_err!("The sftp sessions were poisoned"))
+    }
+
+    // A cached session may have been closed by the server in the meantime, so a failure with it
+    // is tried once more with a new session. An error from the sftp server itself (like a missing file) is not.
+    fn with_session<T>(
+        &self,
+        key: &str,
+        connect: impl FnOnce() -> Result<S>,
+        f: impl Fn(&S) -> Result<T>,
+    ) -> Result<T> {
+        let cached = self.sessions()?.get(key).cloned();
+        if let Some(session) = cached {
+            match f(&session) {
+                Ok(result) => return Ok(result),
+                Err(e) if is_sftp_status(&e) => return Err(e),
+                Err(_) => {
+                    let mut sessions = self.sessions()?;
+                    // Another job may already have replaced it.
+                    if sessions.get(key).is_some_and(|s| Arc::ptr_eq(s, &session)) {
+                        sessions.remove(key);
+                    }
+                }
+            }
+        }
+        let session = Arc::new(connect()?);
+        self.sessions()?.insert(key.to_string(), session.clone());
+        f(&session)
+    }
+}
+
+fn is_sftp_status(e: &Error) -> bool {
+    e.downcast_ref::<ssh2::Error>()
+        .is_some_and(|e| matches!(e.code(), ssh2::ErrorCode::SFTP(_)))
+}
+
+fn sftp_session(user: &str, service: &str, port: Option<usize>) -> Result<ssh2::Sftp> {
     let port = port.unwrap_or(22);
     let tcp = TcpStream::connect(format!("{}:{}", service, port))?;
     let mut sess = Session::new()?;
     sess.set_tcp_stream(tcp);
     sess.handshake()?;
     sess.userauth_agent(user)?;
-    let sftp = sess.sftp()?;
-    let mut remote_file = sftp.open(path)?;
-    let mut contents = Vec::new();
-    remote_file.read_to_end(&mut contents)?;
-    spinner.finish_with_message(format!("loaded: {}@{}:{}", user, service, path.display()));
-    Ok(contents)
+    Ok(sess.sftp()?)
+}
+
+// Lists all files below the remote directory (relative to it).
+// Symlinks to files are listed, symlinks to directories are skipped, so there are no cycles.
+pub fn list_files_over_sftp(
+    user: &str,
+    service: &str,
+    path: &PathBuf,
+    port: Option<usize>,
+) -> Result<Vec<PathBuf>> {
+    with_sftp_session(user, service, port, |sftp| {
+        let mut files = vec![];
+        list_sftp_folder(sftp, path, path, &mut files)?;
+        Ok(files)
+    })
+}
+
+fn list_sftp_folder(
+    sftp: &ssh2::Sftp,
+    root: &PathBuf,
+    folder: &PathBuf,
+    files: &mut Vec<PathBuf>,
+) -> Result<()> {
+    let entries = sftp.readdir(folder).context(format!(
+        "Could not list remote directory {}",
+        folder.display()
+    ))?;
+    if entries.is_empty() {
+        return Err(format_err!(
+            "Empty folders not supported ({}).",
+            folder.display()
+        ));
+    }
+    for (entry, stat) in entries {
+        let stat = if stat.file_type().is_symlink() {
+            match sftp.stat(&entry) {
+                Ok(target) if target.is_file() => target,
+                Ok(_) => {
+                    yellow(format!(
+                        "Skipping symlink to a directory {}",
+                        entry.display()
+                    ));
+                    continue;
+                }
+                Err(e) => {
+                    return Err(format_err!("Broken symlink {}: {}", entry.display(), e));
+                }
+            }
+        } else {
+            stat
+        };
+        if stat.is_dir() {
+            list_sftp_folder(sftp, root, &entry, files)?;
+        } else if stat.is_file() {
+            files.push(entry.strip_prefix(root)?.to_path_buf());
+        } else {
+            return Err(format_err!(
+                "Only regular files are supported ({}).",
+                entry.display()
+            ));
+        }
+    }
+    Ok(())
 }
 
 pub fn format_subpath(subpath: &PathBuf) -> PathBuf {
@@ -292,7 +1324,7 @@ pub fn format_subpath(subpath: &PathBuf) -> PathBuf {
     }
 }
 
-fn parse_sftp(sftp_url: &str) -> Result<(String, String, String)> {
+pub fn parse_sftp(sftp_url: &str) -> Result<(String, String, String)> {
     let parts: Vec<&str> = sftp_url.split('@').collect();
     if parts.len() != 2 {
         return Err(format_err!("invalid ssh string"));
@@ -322,6 +1354,8 @@ fn parse_auto_source(auto: &str) -> Result<FileSource> {
     if is_url(auto) && !is_repo(auto) {
         return Ok(FileSource::Download {
             url: auto.to_string(),
+            redirects: Redirects::default(),
+            user_agent: None,
         });
     }
     source_from_string_simple(auto)
@@ -330,6 +1364,440 @@ fn parse_auto_source(auto: &str) -> Result<FileSource> {
 #[cfg(test)]
 mod test {
     use super::*;
+    use std::sync::Arc;
+    fn commit_file(repo: &Repository, content: &str) -> Oid {
+        let blob = repo.blob(content.as_bytes()).unwrap();
+        let mut builder = repo.treebuilder(None).unwrap();
+        builder.insert("file.txt", blob, 0o100644).unwrap();
+        let tree = repo.find_tree(builder.write().unwrap()).unwrap();
+        let signature = git2::Signature::now("test", "test@example.com").unwrap();
+        let parent = repo.head().ok().and_then(|h| h.peel_to_commit().ok());
+        let parents = parent.iter().collect::<Vec<_>>();
+        repo.commit(
+            Some("HEAD"),
+            &signature,
+            &signature,
+            "test",
+            &tree,
+            &parents,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_session_cache() {
+        let cache = SessionCache::new();
+        let connects = AtomicUsize::new(0);
+        let connect = || Ok(connects.fetch_add(1, Ordering::Relaxed));
+        let use_session = |session: &usize| Ok(*session);
+        assert_eq!(cache.with_session("host", connect, use_session).unwrap(), 0);
+        assert_eq!(cache.with_session("host", connect, use_session).unwrap(), 0);
+        assert_eq!(connects.load(Ordering::Relaxed), 1);
+        // The lock is not held while the session is used.
+        let nested = cache
+            .with_session("host", connect, |_| {
+                cache.with_session("host", connect, use_session)
+            })
+            .unwrap();
+        assert_eq!(nested, 0);
+        assert_eq!(
+            cache.with_session("other", connect, use_session).unwrap(),
+            1
+        );
+        // A session that broke is replaced by a new one.
+        let reconnected = cache
+            .with_session("host", connect, |session: &usize| match session {
+                0 => Err(format_err!("connection closed")),
+                _ => Ok(*session),
+            })
+            .unwrap();
+        assert_eq!(reconnected, 2);
+        assert_eq!(cache.with_session("host", connect, use_session).unwrap(), 2);
+    }
+
+    #[test]
+    fn test_fetch_missing_ref() {
+        let origin_dir = TempDir::new().unwrap();
+        let origin = Repository::init(origin_dir.path()).unwrap();
+        let first = commit_file(&origin, "first");
+        let cached_path = cache_dir().unwrap().join("test_fetch_missing_ref");
+        let cached = bare_clone(origin_dir.path().to_str().unwrap(), &cached_path, false).unwrap();
+        assert_eq!(
+            get_commit_from_string(&cached, &first.to_string(), &FetchContext::default()).unwrap(),
+            first.to_string()
+        );
+        // The second commit is only in the cache after the fetch.
+        let second = commit_file(&origin, "second");
+        assert_eq!(
+            get_commit_from_string(&cached, &second.to_string(), &FetchContext::default()).unwrap(),
+            second.to_string()
+        );
+        assert!(
+            get_commit_from_string(&cached, "does-not-exist", &FetchContext::default()).is_err()
+        );
+        fs::remove_dir_all(cached_path).unwrap();
+    }
+
+    // What the test servers see of a request. The header names are lowercase.
+    struct Request {
+        index: usize,
+        path: String,
+        headers: HashMap<String, String>,
+    }
+
+    // The status can be followed by more header lines. With cut_after, the connection is closed
+    // after that many bytes of the body, although the content length announces all of it.
+    struct Response {
+        status: String,
+        body: Vec<u8>,
+        cut_after: Option<usize>,
+    }
+
+    impl Response {
+        fn ok(body: impl Into<Vec<u8>>) -> Self {
+            Response {
+                status: "200 OK".to_string(),
+                body: body.into(),
+                cut_after: None,
+            }
+        }
+        fn status(status: &str) -> Self {
+            Response {
+                status: status.to_string(),
+                body: vec![],
+                cut_after: None,
+            }
+        }
+    }
+
+    // Answers every request with the response of the handler. The handler is made with the address of the server.
+    fn serve_with<H>(handler: impl FnOnce(&str) -> H) -> String
+    where
+        H: Fn(&Request) -> Response + Send + 'static,
+    {
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = format!("http://{}", listener.local_addr().unwrap());
+        let handler = handler(&address);
+        std::thread::spawn(move || {
+            for (index, stream) in listener.incoming().enumerate() {
+                let mut stream = stream.unwrap();
+                let mut reader = std::io::BufReader::new(stream.try_clone().unwrap());
+                let mut request_line = String::new();
+                reader.read_line(&mut request_line).unwrap();
+                let mut header = String::new();
+                let mut headers = HashMap::new();
+                while reader.read_line(&mut header).unwrap() > 2 {
+                    if let Some((name, value)) = header.split_once(':') {
+                        headers.insert(name.trim().to_lowercase(), value.trim().to_string());
+                    }
+                    header.clear();
+                }
+                let path = request_line.split_whitespace().nth(1).unwrap_or_default();
+                let response = handler(&Request {
+                    index,
+                    path: path.to_string(),
+                    headers,
+                });
+                let _ = write!(
+                    stream,
+                    "HTTP/1.1 {}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n",
+                    response.status,
+                    response.body.len()
+                );
+                let end = response.cut_after.unwrap_or(response.body.len());
+                let _ = stream.write_all(&response.body[..end]);
+            }
+        });
+        address
+    }
+
+    // Answers each request with the body for its path, or 404. The routes get the address of the server.
+    fn serve(routes: impl FnOnce(&str) -> Vec<(&'static str, String)>) -> String {
+        serve_with(|address| {
+            let routes = routes(address);
+            move |request: &Request| match routes.iter().find(|(p, _)| *p == request.path) {
+                Some((_, body)) => Response::ok(body.clone()),
+                // /user-agent always answers with the user agent of the request.
+                None if request.path == "/user-agent" => Response::ok(
+                    request
+                        .headers
+                        .get("user-agent")
+                        .cloned()
+                        .unwrap_or_default(),
+                ),
+                None => Response::status("404 Not Found"),
+            }
+        })
+    }
+
+    // The first response breaks off after 40 bytes. The range headers of all requests are recorded.
+    // The responses to the requests in failing get the status 503 instead.
+    fn serve_interrupted(
+        body: &'static str,
+        ranges: bool,
+        failing: Vec<usize>,
+    ) -> (String, Arc<Mutex<Vec<String>>>) {
+        let seen = Arc::new(Mutex::new(vec![]));
+        let recorded = seen.clone();
+        let address = serve_with(move |_| {
+            move |request: &Request| {
+                let range = request.headers.get("range").cloned().unwrap_or_default();
+                recorded.lock().unwrap().push(range.clone());
+                if failing.contains(&request.index) {
+                    return Response::status("503 Service Unavailable");
+                }
+                let start = match range.strip_prefix("bytes=") {
+                    Some(r) if ranges => r.trim_end_matches('-').parse::<usize>().unwrap(),
+                    _ => 0,
+                };
+                let status = match start {
+                    0 => "200 OK".to_string(),
+                    _ => format!(
+                        "206 Partial Content\r\nContent-Range: bytes {}-{}/{}",
+                        start,
+                        body.len() - 1,
+                        body.len()
+                    ),
+                };
+                Response {
+                    status,
+                    body: body.as_bytes()[start..].to_vec(),
+                    cut_after: (request.index == 0).then_some(40),
+                }
+            }
+        });
+        (format!("{}/large.bin", address), seen)
+    }
+
+    #[test]
+    fn test_resumed_download() {
+        let body = "0123456789".repeat(10).leak();
+        let download = |url: &str| FileSource::Download {
+            url: url.to_string(),
+            redirects: Redirects::Follow,
+            user_agent: None,
+        };
+        let (url, seen) = serve_interrupted(body, true, vec![]);
+        assert_eq!(
+            download(&url).fetch(&FetchContext::default()).unwrap(),
+            body.as_bytes()
+        );
+        assert_eq!(*seen.lock().unwrap(), vec!["", "bytes=40-"]);
+        // Without support for ranges, the whole file is sent again.
+        let (url, seen) = serve_interrupted(body, false, vec![]);
+        assert_eq!(
+            download(&url).fetch(&FetchContext::default()).unwrap(),
+            body.as_bytes()
+        );
+        assert_eq!(*seen.lock().unwrap(), vec!["", "bytes=40-"]);
+        // A failed request while continuing is tried again.
+        let (url, seen) = serve_interrupted(body, true, vec![1]);
+        assert_eq!(
+            download(&url).fetch(&FetchContext::default()).unwrap(),
+            body.as_bytes()
+        );
+        assert_eq!(*seen.lock().unwrap(), vec!["", "bytes=40-", "bytes=40-"]);
+        // The first request is not repeated, the next source might work.
+        let (url, seen) = serve_interrupted(body, true, vec![0]);
+        assert!(download(&url).fetch(&FetchContext::default()).is_err());
+        assert_eq!(*seen.lock().unwrap(), vec![""]);
+    }
+
+    #[test]
+    fn test_partial_across_runs() {
+        let body = "0123456789".repeat(10).leak();
+        let dir = TempDir::new().unwrap().path().to_path_buf();
+        let fetch = FetchContext::default();
+        // The download breaks off and continuing it fails, so the run gives up.
+        let (url, seen) = serve_interrupted(body, true, vec![1, 2]);
+        let (partial, file) = open_partial(&dir, &url).unwrap();
+        let partial = partial.unwrap();
+        assert!(download_resumable(&url, &Redirects::Follow, None, file, &fetch).is_err());
+        assert_eq!(fs::metadata(&partial).unwrap().len(), 40);
+        // The next run continues where the last one stopped.
+        let (again, file) = open_partial(&dir, &url).unwrap();
+        assert_eq!(again, Some(partial.clone()));
+        assert_eq!(
+            download_resumable(&url, &Redirects::Follow, None, file, &fetch).unwrap(),
+            body.as_bytes()
+        );
+        assert_eq!(seen.lock().unwrap().last().unwrap(), "bytes=40-");
+        // While one process downloads the url, another one gets its own file.
+        let (first, _held) = open_partial(&dir, &url).unwrap();
+        let (second, _) = open_partial(&dir, &url).unwrap();
+        assert_eq!(first, Some(partial));
+        assert_eq!(second, None);
+    }
+
+    #[test]
+    fn test_release_asset() {
+        let address = serve(|address| {
+            let release = format!(
+                r#"{{"tag_name":"v2","assets":[
+                    {{"name":"tool-linux-amd64","url":"{0}/api/1","browser_download_url":"{0}/dl/linux"}},
+                    {{"name":"tool-linux-arm64","url":"{0}/api/2","browser_download_url":"{0}/dl/arm"}}]}}"#,
+                address
+            );
+            vec![
+                ("/repos/owner/tool/releases/latest", release.clone()),
+                ("/repos/owner/tool/releases/tags/v2", release),
+                ("/dl/linux", "linux binary".to_string()),
+            ]
+        });
+        let release = |asset: &str, tag: &str| FileSource::Release {
+            repo: "owner/tool".to_string(),
+            asset: asset.to_string(),
+            tag: tag.to_string(),
+            forge: Forge::Github,
+            api: Some(address.clone()),
+            token: None,
+        };
+        assert_eq!(
+            release("*-linux-amd64", "latest")
+                .fetch(&FetchContext::default())
+                .unwrap(),
+            b"linux binary"
+        );
+        assert_eq!(
+            release("*-amd64", "v2")
+                .fetch(&FetchContext::default())
+                .unwrap(),
+            b"linux binary"
+        );
+        let err = release("tool-linux-*", "latest")
+            .fetch(&FetchContext::default())
+            .unwrap_err();
+        assert!(err.to_string().contains("Several assets"));
+        let err = release("*-windows-*", "latest")
+            .fetch(&FetchContext::default())
+            .unwrap_err();
+        assert!(err.to_string().contains("No asset of release v2"));
+        assert!(release("*", "v1").fetch(&FetchContext::default()).is_err());
+    }
+
+    #[test]
+    fn test_release_token() {
+        // Answers with the token it got, so the test can see where it was sent.
+        let token_of = |request: &Request| {
+            Response::ok(
+                request
+                    .headers
+                    .get("private-token")
+                    .cloned()
+                    .unwrap_or("none".to_string()),
+            )
+        };
+        let elsewhere = serve_with(|_| token_of);
+        let address = serve_with(|address| {
+            let release = format!(
+                r#"{{"tag_name":"v2","assets":{{"links":[
+                    {{"name":"here","url":"{0}/here"}},
+                    {{"name":"elsewhere","url":"{1}/file"}},
+                    {{"name":"redirected","url":"{0}/redirect"}}]}}}}"#,
+                address, elsewhere
+            );
+            let redirect = format!("302 Found\r\nLocation: {}/file", elsewhere);
+            move |request: &Request| match request.path.as_str() {
+                "/api/v4/projects/owner%2Ftool/releases/v2" => Response::ok(release.clone()),
+                "/redirect" => Response::status(&redirect),
+                _ => token_of(request),
+            }
+        });
+        let release = |asset: &str| FileSource::Release {
+            repo: "owner/tool".to_string(),
+            asset: asset.to_string(),
+            tag: "v2".to_string(),
+            forge: Forge::Gitlab,
+            api: Some(address.clone()),
+            token: Some("secret".to_string()),
+        };
+        assert_eq!(
+            release("here").fetch(&FetchContext::default()).unwrap(),
+            b"secret"
+        );
+        assert_eq!(
+            release("elsewhere")
+                .fetch(&FetchContext::default())
+                .unwrap(),
+            b"none"
+        );
+        assert_eq!(
+            release("redirected")
+                .fetch(&FetchContext::default())
+                .unwrap(),
+            b"none"
+        );
+    }
+
+    #[test]
+    fn test_user_agent() {
+        let address = serve(|_| vec![]);
+        let download = |user_agent: Option<&str>| FileSource::Download {
+            url: format!("{}/user-agent", address),
+            redirects: Redirects::default(),
+            user_agent: user_agent.map(|u| u.to_string()),
+        };
+        assert_eq!(
+            download(None).fetch(&FetchContext::default()).unwrap(),
+            DEFAULT_USER_AGENT.as_bytes()
+        );
+        assert_eq!(
+            download(Some("custom/1.0"))
+                .fetch(&FetchContext::default())
+                .unwrap(),
+            b"custom/1.0"
+        );
+    }
+
+    #[test]
+    fn test_load_ca_bundle() {
+        let dir = TempDir::new().unwrap();
+        let missing = dir.path().join("missing.pem");
+        assert!(load_ca_bundle(&missing)
+            .unwrap_err()
+            .to_string()
+            .contains("Could not read CA bundle"));
+        let empty = dir.path().join("empty.pem");
+        fs::write(&empty, "not a certificate").unwrap();
+        assert!(load_ca_bundle(&empty)
+            .unwrap_err()
+            .to_string()
+            .contains("contains no certificates"));
+    }
+
+    #[test]
+    fn test_cache_root() {
+        let root = TempDir::new().unwrap();
+        let cache = new_cache_tempdir(Some(root.path().into())).unwrap();
+        assert!(cache.path().starts_with(root.path()));
+        let missing = root.path().join("missing");
+        assert!(new_cache_tempdir(Some(missing.into())).is_err());
+    }
+
+    #[test]
+    fn test_parse_netrc() {
+        let text = "# comment\nmachine example.com login alice password secret\nmacdef init\ncd /\n\nmachine other.org\n  login bob\n  account x\n  password hunter2\ndefault login anon password guest\n";
+        let entries = parse_netrc(text);
+        assert_eq!(entries.len(), 3);
+        let entry = find_netrc_entry(&entries, "other.org").unwrap();
+        assert_eq!(
+            (entry.login.as_str(), entry.password.as_str()),
+            ("bob", "hunter2")
+        );
+        let entry = find_netrc_entry(&entries, "example.com").unwrap();
+        assert_eq!(entry.password, "secret");
+        let entry = find_netrc_entry(&entries, "unknown.net").unwrap();
+        assert_eq!(entry.login, "anon");
+        assert!(find_netrc_entry(&entries[..2], "unknown.net").is_none());
+    }
+    #[test]
+    fn test_pinned_refs() {
+        assert!(is_pinned_ref("9ae0fdedd423803f0401f6e7a23cd2bb88c175b2"));
+        assert!(!is_pinned_ref("9ae0fde"));
+        assert!(!is_pinned_ref("main"));
+        assert!(!is_pinned_ref("develop^"));
+        assert!(!is_pinned_ref("9ae0fdedd423803f0401f6e7a23cd2bb88c175bx"));
+    }
     #[test]
     fn test_parse_auto_sources() {
         assert_eq!(
@@ -350,4 +1818,60 @@ mod test {
             }
         );
     }
+    #[test]
+    fn test_streaming_hash() {
+        // Larger than the buffer, so several chunks are hashed.
+        let data = (0..200_000).map(|i| (i % 251) as u8).collect::<Vec<u8>>();
+        let mut hasher = Sha3_256::new();
+        hasher.update(&data);
+        let buffered = hasher
+            .finalize()
+            .iter()
+            .map(|byte| format!("{:02X}", byte))
+            .collect::<String>();
+        assert_eq!(compute_hash_reader(&data[..]).unwrap(), buffered);
+        assert_eq!(compute_hash(&data), buffered);
+        assert_eq!(
+            compute_hash(&vec![]),
+            "A7FFC6F8BF1ED76651C14756A061D662F580FF4DE43B49FA82D80A4B80F8434A"
+        );
+    }
+    #[test]
+    fn test_hash_algos() {
+        let data = b"abc".to_vec();
+        let sha256 = "BA7816BF8F01CFEA414140DE5DAE2223B00361A396177A9CB410FF61F20015AD";
+        let blake3 = "6437B3AC38465133FFB63B75273A8DB548C558465D79DB03FD359C6CD5BD9D85";
+        assert_eq!(
+            compute_hash_with(HashAlgo::Sha256, &data[..]).unwrap(),
+            sha256
+        );
+        assert_eq!(
+            compute_hash_with(HashAlgo::Blake3, &data[..]).unwrap(),
+            blake3
+        );
+        assert!(hash_matches(&format!("sha256:{}", sha256.to_lowercase()), &data).unwrap());
+        assert!(hash_matches(&format!("BLAKE3:{}", blake3), &data).unwrap());
+        assert!(hash_matches(&compute_hash(&data), &data).unwrap());
+        assert!(hash_matches(&format!("sha3-256:{}", compute_hash(&data)), &data).unwrap());
+        assert!(!hash_matches(&format!("blake3:{}", sha256), &data).unwrap());
+        assert!(!hash_matches("wronghash", &data).unwrap());
+        assert!(hash_matches("md5:900150983cd24fb0d6963f7d28e17f72", &data).is_err());
+        assert_eq!(HashAlgo::Sha256.format("AB"), "sha256:AB");
+        assert_eq!(HashAlgo::Sha3_256.format("AB"), "AB");
+        assert_eq!(
+            HashAlgo::normalize(&format!("Sha256:{}", sha256.to_lowercase())).unwrap(),
+            format!("sha256:{}", sha256)
+        );
+        assert_eq!(
+            HashAlgo::normalize("blake3:1234").unwrap_err().to_string(),
+            "1234 is not a BLAKE3 hash in hex"
+        );
+    }
+    #[test]
+    fn test_short_hash() {
+        let hash = compute_hash(&vec![]);
+        assert_eq!(short_hash(&hash), "A7FFC6F8BF1E");
+        assert_eq!(short_hash(&hash).len(), SHORT_HASH_LEN);
+        assert_eq!(short_hash("ABC"), "ABC");
+    }
 }
@@ -1,3 +1,4 @@
+#![allow(clippy::len_zero, clippy::needless_return)]
 use crate::*;
 
 pub trait VariableCompletion: Sized + Clone {
@@ -7,15 +8,25 @@ pub trait VariableCompletion: Sized + Clone {
         let requested = self.required_variables()?;
         let mut new = self.clone();
         for key in &requested {
-            let value = map
-                .get(key)
-                .context(format!("Required key: {} is not in variables", key))?;
+            let value = map.get(key).ok_or(MissingVariable(key.clone()))?;
             new.set_single_variable(key, value)?;
         }
         Ok(new)
     }
 }
 
+// A variable is used but has no value. Inclusions turn this into a hint about with_vars.
+#[derive(Debug)]
+pub struct MissingVariable(pub String);
+
+impl fmt::Display for MissingVariable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Required key: {} is not in variables", self.0)
+    }
+}
+
+impl std::error::Error for MissingVariable {}
+
 impl VariableCompletion for String {
     fn required_variables(&self) -> Result<Vec<String>> {
         let re = Regex::new(r"\{\{([^{}]+)\}\}")
@@ -59,7 +70,7 @@ impl VariableCompletion for FileSource {
         match self {
             FileSource::Auto(s) => s.required_variables(),
 
-            FileSource::Download { url } => url.clone().required_variables(),
+            FileSource::Download { url, .. } => url.clone().required_variables(),
             FileSource::Git {
                 repo,
                 id: commit,
@@ -82,9 +93,28 @@ impl VariableCompletion for FileSource {
                 Ok(vecset(vec![rb_service, rb_user, rb_path]))
             }
             FileSource::Local { path } => path.to_owned().required_variables(),
+            FileSource::Release {
+                repo,
+                asset,
+                tag,
+                api,
+                token,
+                ..
+            } => {
+                let mut required = vec![
+                    repo.required_variables()?,
+                    asset.required_variables()?,
+                    tag.required_variables()?,
+                ];
+                for optional in [api, token].into_iter().flatten() {
+                    required.push(optional.required_variables()?);
+                }
+                Ok(vecset(required))
+            }
             FileSource::Text {
                 content,
                 ignore_variables,
+                ..
             } => {
                 if *ignore_variables {
                     Ok(vec![])
@@ -97,8 +127,14 @@ impl VariableCompletion for FileSource {
     fn set_single_variable(&mut self, key: &str, value: &str) -> Result<FileSource> {
         *self = match self {
             FileSource::Auto(s) => Self::Auto(s.set_single_variable(key, value)?),
-            FileSource::Download { url } => FileSource::Download {
+            FileSource::Download {
+                url,
+                redirects,
+                user_agent,
+            } => FileSource::Download {
                 url: url.set_single_variable(key, value)?,
+                redirects: *redirects,
+                user_agent: user_agent.clone(),
             },
             FileSource::Git {
                 repo,
@@ -123,18 +159,42 @@ impl VariableCompletion for FileSource {
             FileSource::Local { path } => FileSource::Local {
                 path: path.set_single_variable(key, value)?,
             },
+            FileSource::Release {
+                repo,
+                asset,
+                tag,
+                forge,
+                api,
+                token,
+            } => FileSource::Release {
+                repo: repo.set_single_variable(key, value)?,
+                asset: asset.set_single_variable(key, value)?,
+                tag: tag.set_single_variable(key, value)?,
+                forge: *forge,
+                api: match api {
+                    Some(a) => Some(a.set_single_variable(key, value)?),
+                    None => None,
+                },
+                token: match token {
+                    Some(t) => Some(t.set_single_variable(key, value)?),
+                    None => None,
+                },
+            },
             FileSource::Text {
                 content,
+                from_file,
                 ignore_variables,
             } => {
                 if *ignore_variables {
                     FileSource::Text {
                         content: content.clone(),
+                        from_file: from_file.clone(),
                         ignore_variables: *ignore_variables,
                     }
                 } else {
                     FileSource::Text {
                         content: content.set_single_variable(key, value)?,
+                        from_file: from_file.clone(),
                         ignore_variables: *ignore_variables,
                     }
                 }
@@ -144,6 +204,41 @@ impl VariableCompletion for FileSource {
     }
 }
 
+impl FileSource {
+    // A text source with from_file gets the content of the file, so the variables can be set in it like in inline text.
+    pub fn with_text_file(&self, vars: &HashMap<String, String>) -> Result<Self> {
+        let FileSource::Text {
+            content,
+            from_file: Some(file),
+            ignore_variables,
+        } = self
+        else {
+            return Ok(self.clone());
+        };
+        if !content.is_empty() {
+            return Err(format_err!(
+                "A text source can have content or from_file, but not both."
+            ));
+        }
+        let path = relative_to_parent(file, vars, "a text source")?;
+        let content = fs::read_to_string(&path)
+            .context(format!("Could not read the text file {}", path.display()))?;
+        Ok(FileSource::Text {
+            content,
+            from_file: None,
+            ignore_variables: *ignore_variables,
+        })
+    }
+}
+
+// For the optional sources, like hash_source, signature and to_source.
+pub fn with_optional_text_file(
+    source: &Option<FileSource>,
+    vars: &HashMap<String, String>,
+) -> Result<Option<FileSource>> {
+    source.as_ref().map(|s| s.with_text_file(vars)).transpose()
+}
+
 impl<T> VariableCompletion for Vec<T>
 where
     T: VariableCompletion,
@@ -170,16 +265,52 @@ impl VariableCompletion for File {
         let rb_path = self.path.required_variables()?;
         let rb_sources = self.sources.required_variables()?;
         let rb_edits = self.edits.required_variables()?;
-        Ok(vecset(vec![rb_path, rb_sources, rb_edits]))
+        let rb_signature = match &self.signature {
+            Some(s) => s.required_variables()?,
+            None => vec![],
+        };
+        let rb_key = match &self.public_key {
+            Some(k) => k.required_variables()?,
+            None => vec![],
+        };
+        let rb_hash_source = match &self.hash_source {
+            Some(s) => s.required_variables()?,
+            None => vec![],
+        };
+        Ok(vecset(vec![
+            rb_path,
+            rb_sources,
+            rb_edits,
+            rb_signature,
+            rb_key,
+            rb_hash_source,
+        ]))
     }
     fn set_single_variable(&mut self, key: &str, value: &str) -> Result<Self> {
         Ok(File {
             path: self.path.set_single_variable(key, value)?,
             tags: self.tags.clone(),
             hash: self.hash.clone(),
+            hash_source: match &mut self.hash_source {
+                Some(s) => Some(s.set_single_variable(key, value)?),
+                None => None,
+            },
             sources: self.sources.set_single_variable(key, value)?,
             edits: self.edits.set_single_variable(key, value)?,
             decrypt: self.decrypt,
+            decompress: self.decompress,
+            signature: match &mut self.signature {
+                Some(s) => Some(s.set_single_variable(key, value)?),
+                None => None,
+            },
+            public_key: match &mut self.public_key {
+                Some(k) => Some(k.set_single_variable(key, value)?),
+                None => None,
+            },
+            optional: self.optional,
+            verify_only: self.verify_only,
+            os: self.os.clone(),
+            arch: self.arch.clone(),
         })
     }
 }
@@ -198,7 +329,15 @@ impl VariableCompletion for Inclusion {
     fn required_variables(&self) -> Result<Vec<String>> {
         let rb_subfolder = self.subfolder.required_variables()?;
         let rb_config = self.config.required_variables()?;
-        Ok(vecset(vec![rb_subfolder, rb_config]))
+        let rb_root = match &self.source_root {
+            Some(r) => r.required_variables()?,
+            None => vec![],
+        };
+        let mut rb_with_vars = vec![];
+        for value in self.with_vars.values() {
+            rb_with_vars.append(&mut value.required_variables()?);
+        }
+        Ok(vecset(vec![rb_subfolder, rb_config, rb_root, rb_with_vars]))
     }
     fn set_single_variable(&mut self, key: &str, value: &str) -> Result<Self> {
         Ok(Self {
@@ -206,14 +345,186 @@ impl VariableCompletion for Inclusion {
             subfolder: self.subfolder.set_single_variable(key, value)?,
             tags: self.tags.clone(),
             with_tags: self.with_tags.clone(),
+            include_default_tags: self.include_default_tags,
             hash: self.hash.clone(),
+            source_root: match &self.source_root {
+                Some(r) => Some(r.clone().set_single_variable(key, value)?),
+                None => None,
+            },
+            with_vars: self
+                .with_vars
+                .iter()
+                .map(|(k, v)| Ok((k.clone(), v.clone().set_single_variable(key, value)?)))
+                .collect::<Result<_>>()?,
+            parent_variables: self.parent_variables.clone(),
+            os: self.os.clone(),
+            arch: self.arch.clone(),
         })
     }
 }
 
+// A variable is either given directly or read from somewhere when the config is loaded.
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(untagged)]
+pub enum VariableValue {
+    Plain(String),
+    File(FileVariable),
+    Secret(SecretVariable),
+    // [var.tagname] holds values that replace the plain ones while the tag is active.
+    Tagged(HashMap<String, String>),
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct FileVariable {
+    pub file: PathBuf,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct SecretVariable {
+    pub secret: String,
+}
+
+// Combines the variables from the config with the built-in ones (SELF_...) and resolves all references.
+// The overrides come from the tag sections and replace plain variables of the same name.
+// The paths of file variables and secret references can use the other plain variables, but not each other.
+pub fn evaluate_variables(
+    defined: &HashMap<String, VariableValue>,
+    builtin: HashMap<String, String>,
+    overrides: &HashMap<String, String>,
+    allow_exec: bool,
+) -> Result<HashMap<String, String>> {
+    let mut plain = builtin;
+    for (k, v) in defined {
+        if let VariableValue::Plain(s) = v {
+            plain.insert(k.clone(), s.clone());
+        }
+    }
+    for (k, v) in overrides {
+        plain.insert(k.clone(), v.clone());
+    }
+    let partial = resolve_what_possible(&plain)?;
+    for (k, v) in defined {
+        if overrides.contains_key(k) {
+            continue;
+        }
+        match v {
+            VariableValue::File(fv) => {
+                plain.insert(k.clone(), fv.read(k, &partial)?);
+            }
+            VariableValue::Secret(sv) => {
+                let secret = sv
+                    .secret
+                    .set_variables(&partial)
+                    .context(format!("Could not fill in the secret of variable {}", k))?;
+                plain.insert(k.clone(), resolve_secret(k, &secret, allow_exec)?);
+            }
+            _ => {}
+        }
+    }
+    resolve_variable_inter_refs(&plain)
+}
+
+// Fills in the output directory of sync. ENV_<NAME> stands for the environment variable NAME.
+pub fn fill_in_output_path(output: &PathBuf, vars: &HashMap<String, String>) -> Result<PathBuf> {
+    let mut vars = vars.clone();
+    for key in output.required_variables()? {
+        if let (Some(name), false) = (key.strip_prefix("ENV_"), vars.contains_key(&key)) {
+            let value = std::env::var(name).map_err(|_| {
+                format_err!(
+                    "The environment variable {} in the output path is not set",
+                    name
+                )
+            })?;
+            vars.insert(key, value);
+        }
+    }
+    output.set_variables(&vars).map_err(|e| {
+        format_err!(
+            "Could not fill in the output path {}: {}",
+            output.display(),
+            e
+        )
+    })
+}
+
+// Collects the values of the tag sections for the active tags.
+// Two active tags must not set the same variable.
+pub fn tag_overrides(
+    defined: &HashMap<String, VariableValue>,
+    active_tags: &Vec<String>,
+) -> Result<HashMap<String, String>> {
+    let mut overrides: HashMap<String, String> = HashMap::new();
+    let mut origins: HashMap<String, String> = HashMap::new();
+    let mut tags = active_tags.clone();
+    tags.sort();
+    for tag in &tags {
+        if let Some(VariableValue::Tagged(values)) = defined.get(tag) {
+            for (k, v) in values {
+                if let Some(other) = origins.get(k) {
+                    return Err(format_err!(
+                        "The variable {} is set by both active tags {} and {}.",
+                        k,
+                        other,
+                        tag
+                    ));
+                }
+                overrides.insert(k.clone(), v.clone());
+                origins.insert(k.clone(), tag.clone());
+            }
+        }
+    }
+    Ok(overrides)
+}
+
+impl FileVariable {
+    fn read(&self, name: &str, vars: &HashMap<String, String>) -> Result<String> {
+        let path = relative_to_parent(&self.file, vars, &format!("variable {}", name))?;
+        let content = fs::read_to_string(&path).context(format!(
+            "Could not read variable {} from {}",
+            name,
+            path.display()
+        ))?;
+        Ok(content.trim().to_string())
+    }
+}
+
+// Fills in the path and resolves a relative one against SELF_PARENT. The description names the path in errors.
+fn relative_to_parent(
+    path: &PathBuf,
+    vars: &HashMap<String, String>,
+    description: &str,
+) -> Result<PathBuf> {
+    let path = path
+        .set_variables(vars)
+        .context(format!("Could not fill in the path of {}", description))?;
+    if path.is_relative() {
+        let parent = vars.get("SELF_PARENT").context(format!(
+            "The path {} of {} is relative, but the config has no parent directory.",
+            path.display(),
+            description
+        ))?;
+        return Ok(PathBuf::from(parent).join(path));
+    }
+    Ok(path)
+}
+
 pub fn resolve_variable_inter_refs(
     vars_in: &HashMap<String, String>,
 ) -> Result<HashMap<String, String>> {
+    let resolved = resolve_what_possible(vars_in)?;
+    if resolved.len() == vars_in.len() {
+        Ok(resolved)
+    } else {
+        Err(format_err!(
+            "There seems to be some problem with variable inter-reference."
+        ))
+    }
+}
+
+// Resolves the variables that can be resolved and leaves out the others.
+fn resolve_what_possible(vars_in: &HashMap<String, String>) -> Result<HashMap<String, String>> {
     let mut resolved: HashMap<String, String> = HashMap::new();
     let mut current_resolved_count = 0;
     for _ in 0..1000 {
@@ -230,11 +541,7 @@ pub fn resolve_variable_inter_refs(
                 }
             }
         }
-        if resolved.len() == current_resolved_count {
-            return Err(format_err!(
-                "There seems to be some problem with variable inter-reference."
-            ));
-        } else if resolved.len() == vars_in.len() {
+        if resolved.len() == current_resolved_count || resolved.len() == vars_in.len() {
             return Ok(resolved);
         } else {
             current_resolved_count = resolved.len();
@@ -249,6 +556,20 @@ pub fn resolve_variable_inter_refs(
 mod test {
     use super::*;
     #[test]
+    fn test_output_path_variables() {
+        let vars = HashMap::from([("user".to_string(), "alice".to_string())]);
+        std::env::set_var("LOREVAULT_OUTPUT_TEST", "/srv");
+        let output = PathBuf::from("{{ENV_LOREVAULT_OUTPUT_TEST}}/{{user}}/.config");
+        assert_eq!(
+            fill_in_output_path(&output, &vars).unwrap(),
+            PathBuf::from("/srv/alice/.config")
+        );
+        let err =
+            fill_in_output_path(&PathBuf::from("{{ENV_LOREVAULT_UNSET_TEST}}"), &vars).unwrap_err();
+        assert!(err.to_string().contains("LOREVAULT_UNSET_TEST"));
+        assert!(fill_in_output_path(&PathBuf::from("{{other}}"), &vars).is_err());
+    }
+    #[test]
     fn test_var() {
         let mut str = "the var is {{varname}}".to_string();
         assert_eq!(
@@ -282,4 +603,68 @@ mod test {
             "{{value and plainandvalue}}"
         );
     }
+    #[test]
+    fn test_file_variable() {
+        let dir = TempDir::new().unwrap();
+        fs::write(dir.path().join("token"), "secret-token\n").unwrap();
+        let mut defined = HashMap::new();
+        defined.insert(
+            "token".to_string(),
+            VariableValue::File(FileVariable {
+                file: PathBuf::from("{{tokenfile}}"),
+            }),
+        );
+        defined.insert(
+            "tokenfile".to_string(),
+            VariableValue::Plain("token".to_string()),
+        );
+        defined.insert(
+            "header".to_string(),
+            VariableValue::Plain("Bearer {{token}}".to_string()),
+        );
+        let mut builtin = HashMap::new();
+        builtin.insert(
+            "SELF_PARENT".to_string(),
+            dir.path().to_str().unwrap().to_string(),
+        );
+        let vars = evaluate_variables(&defined, builtin.clone(), &HashMap::new(), false).unwrap();
+        assert_eq!(vars.get("token").unwrap(), "secret-token");
+        assert_eq!(vars.get("header").unwrap(), "Bearer secret-token");
+
+        defined.insert(
+            "tokenfile".to_string(),
+            VariableValue::Plain("missing".to_string()),
+        );
+        let err = evaluate_variables(&defined, builtin, &HashMap::new(), false).unwrap_err();
+        assert!(err.to_string().contains("variable token"));
+    }
+    #[test]
+    fn test_tag_variables() {
+        let defined: HashMap<String, VariableValue> = toml::from_str(
+            r#"
+            server = "localhost"
+            url = "https://{{server}}/api"
+            [prod]
+            server = "prod.example.com"
+            [staging]
+            server = "staging.example.com"
+            "#,
+        )
+        .unwrap();
+        let resolve = |tags: Vec<&str>| {
+            let tags = tags.iter().map(|t| t.to_string()).collect();
+            let overrides = tag_overrides(&defined, &tags)?;
+            evaluate_variables(&defined, HashMap::new(), &overrides, false)
+        };
+        let base = resolve(vec![]).unwrap();
+        assert_eq!(base.get("url").unwrap(), "https://localhost/api");
+        let prod = resolve(vec!["prod"]).unwrap();
+        assert_eq!(prod.get("url").unwrap(), "https://prod.example.com/api");
+        let staging = resolve(vec!["staging", "unrelated"]).unwrap();
+        assert_eq!(staging.get("server").unwrap(), "staging.example.com");
+        let err = resolve(vec!["prod", "staging"]).unwrap_err();
+        assert!(err
+            .to_string()
+            .contains("both active tags prod and staging"));
+    }
 }